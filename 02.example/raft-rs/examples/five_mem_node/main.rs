@@ -397,23 +397,22 @@ impl Proposal {
 }
 
 fn propose(raft_group: &mut RawNode<MemStorage>, proposal: &mut Proposal) {
-    let last_index1 = raft_group.raft.raft_log.last_index() + 1;
-    if let Some((ref key, ref value)) = proposal.normal {
+    let handle = if let Some((ref key, ref value)) = proposal.normal {
         let data = format!("put {} {}", key, value).into_bytes();
-        let _ = raft_group.propose(vec![], data);
+        raft_group.propose(vec![], data)
     } else if let Some(ref cc) = proposal.conf_change {
-        let _ = raft_group.propose_conf_change(vec![], cc.clone());
+        raft_group.propose_conf_change(vec![], cc.clone())
     } else if let Some(_transferee) = proposal.transfer_leader {
         // TODO: implement transfer leader.
         unimplemented!();
-    }
+    } else {
+        Ok(None)
+    };
 
-    let last_index2 = raft_group.raft.raft_log.last_index() + 1;
-    if last_index2 == last_index1 {
+    match handle {
+        Ok(Some(handle)) => proposal.proposed = handle.index,
         // Propose failed, don't forget to respond to the client.
-        proposal.propose_success.send(false).unwrap();
-    } else {
-        proposal.proposed = last_index1;
+        _ => proposal.propose_success.send(false).unwrap(),
     }
 }
 
@@ -0,0 +1,42 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+use raft::Clock;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A clock that only moves when told to, for testing leader leases.
+///
+/// Clones share the same time, so one clock can be set on every peer of a
+/// `Network` and advanced for all of them at once.
+#[derive(Clone)]
+pub struct MockClock {
+    base: Instant,
+    offset: Arc<Mutex<Duration>>,
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock {
+        MockClock::new()
+    }
+}
+
+impl MockClock {
+    /// Creates a clock starting at the current time.
+    pub fn new() -> MockClock {
+        MockClock {
+            base: Instant::now(),
+            offset: Arc::default(),
+        }
+    }
+
+    /// Moves the clock forward by `d`.
+    pub fn advance(&self, d: Duration) {
+        *self.offset.lock().unwrap() += d;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.base + *self.offset.lock().unwrap()
+    }
+}
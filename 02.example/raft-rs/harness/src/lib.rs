@@ -22,7 +22,8 @@ This module contains various testing harness utilities for Raft.
 
 */
 
+mod clock;
 mod interface;
 mod network;
 
-pub use self::{interface::Interface, network::Network};
+pub use self::{clock::MockClock, interface::Interface, network::Network};
@@ -1,5 +1,6 @@
 // Copyright 2019 TiKV Project Authors. Licensed under Apache-2.0.
 
+mod test_multi_raft;
 mod test_raft;
 mod test_raft_flow_control;
 mod test_raft_paper;
@@ -0,0 +1,229 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+use raft::eraftpb::*;
+use raft::storage::MemStorage;
+use raft::*;
+use slog::Logger;
+
+use crate::test_util::*;
+
+const STORES: [u64; 3] = [1, 2, 3];
+
+// The peer of group `group_id` hosted on store `store_id`.
+fn peer_id(group_id: u64, store_id: u64) -> u64 {
+    group_id * 10 + store_id
+}
+
+fn new_host(store_id: u64, groups: &[u64], logger: &Logger) -> MultiRaft<MemStorage> {
+    new_host_with_hibernate(store_id, groups, 0, logger)
+}
+
+fn new_host_with_hibernate(
+    store_id: u64,
+    groups: &[u64],
+    hibernate_tick: usize,
+    logger: &Logger,
+) -> MultiRaft<MemStorage> {
+    let mut host = MultiRaft::new();
+    for &g in groups {
+        let peers: Vec<u64> = STORES.iter().map(|s| peer_id(g, *s)).collect();
+        for (s, p) in STORES.iter().zip(&peers) {
+            host.set_peer_store(*p, *s);
+        }
+        let storage = new_storage();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(1, 1, peers))
+            .unwrap();
+        let mut config = new_test_config(peer_id(g, store_id), 10, 1);
+        config.hibernate_tick = hibernate_tick;
+        let node = RawNode::new(&config, storage, logger).unwrap();
+        host.add_group(g, node).unwrap();
+    }
+    host
+}
+
+type StoreMessagesVec = Vec<(u64, Vec<GroupMessage>)>;
+
+// Persists and advances all pending readies of the host, returns the number of
+// applied normal entries carrying data and the messages merged per store.
+fn handle_ready(host: &mut MultiRaft<MemStorage>) -> (usize, StoreMessagesVec) {
+    let mut msgs = vec![];
+    let mut applied = 0;
+    if !host.has_ready() {
+        return (applied, msgs);
+    }
+    let mut mrd = host.ready();
+    msgs.extend(mrd.take_messages());
+    for (g, rd) in mrd.mut_readies() {
+        let store = host.group(*g).unwrap().store().clone();
+        if !rd.snapshot().is_empty() {
+            store.wl().apply_snapshot(rd.snapshot().clone()).unwrap();
+        }
+        store.wl().append(rd.entries()).unwrap();
+        if let Some(hs) = rd.hs() {
+            store.wl().set_hardstate(hs.clone());
+        }
+        applied += count_data(&rd.take_committed_entries());
+    }
+    msgs.extend(mrd.take_persisted_messages());
+    let mut mlrd = host.advance(mrd);
+    msgs.extend(mlrd.take_messages());
+    let mut groups = vec![];
+    for (g, light_rd) in mlrd.mut_light_readies() {
+        if let Some(commit) = light_rd.commit_index() {
+            let store = host.group(*g).unwrap().store().clone();
+            store.wl().mut_hard_state().set_commit(commit);
+        }
+        applied += count_data(&light_rd.take_committed_entries());
+        groups.push(*g);
+    }
+    for g in groups {
+        host.advance_apply(g);
+    }
+    (applied, msgs)
+}
+
+fn count_data(ents: &[Entry]) -> usize {
+    ents.iter().filter(|e| !e.data.is_empty()).count()
+}
+
+// Delivers messages between hosts until there is nothing left to do, returns
+// the number of applied entries carrying data per store.
+fn pump(hosts: &mut [MultiRaft<MemStorage>]) -> Vec<usize> {
+    let mut applied = vec![0; hosts.len()];
+    loop {
+        let mut msgs = vec![];
+        for (i, host) in hosts.iter_mut().enumerate() {
+            let (n, m) = handle_ready(host);
+            applied[i] += n;
+            msgs.extend(m);
+        }
+        if msgs.is_empty() {
+            return applied;
+        }
+        for (store_id, batch) in msgs {
+            let host = &mut hosts[store_id as usize - 1];
+            for m in batch {
+                host.step(m).unwrap();
+            }
+        }
+    }
+}
+
+#[test]
+fn test_multi_raft_elect_and_replicate() {
+    let l = default_logger();
+    let groups = [1, 2, 3];
+    let mut hosts: Vec<_> = STORES.iter().map(|s| new_host(*s, &groups, &l)).collect();
+
+    // Every store leads one group.
+    for (&g, host) in groups.iter().zip(hosts.iter_mut()) {
+        host.group_mut(g).unwrap().campaign().unwrap();
+    }
+    pump(&mut hosts);
+    for (&g, &s) in groups.iter().zip(STORES.iter()) {
+        let node = hosts[s as usize - 1].group(g).unwrap();
+        assert_eq!(node.raft.state, StateRole::Leader);
+    }
+
+    for (&g, host) in groups.iter().zip(hosts.iter_mut()) {
+        host.propose(g, vec![], b"somedata".to_vec()).unwrap();
+    }
+    let applied = pump(&mut hosts);
+    // Every store applies the proposal of every group.
+    assert_eq!(applied, vec![3, 3, 3]);
+}
+
+#[test]
+fn test_multi_raft_merge_messages_per_store() {
+    let l = default_logger();
+    let groups = [1, 2];
+    let mut host = new_host(1, &groups, &l);
+    for &g in &groups {
+        host.group_mut(g).unwrap().campaign().unwrap();
+    }
+    let mut mrd = host.ready();
+    assert_eq!(mrd.readies().len(), 2);
+    assert!(mrd.must_sync());
+    let mut msgs = mrd.take_messages();
+    msgs.extend(mrd.take_persisted_messages());
+    assert_eq!(msgs.len(), 2);
+    for s in &[2, 3] {
+        let batch = &msgs[s];
+        let mut got: Vec<_> = batch.iter().map(|m| (m.group_id, m.msg.to)).collect();
+        got.sort_unstable();
+        assert_eq!(got, vec![(1, peer_id(1, *s)), (2, peer_id(2, *s))]);
+    }
+}
+
+#[test]
+fn test_multi_raft_tick_active_only() {
+    let l = default_logger();
+    let groups = [1, 2];
+    let mut host = new_host(1, &groups, &l);
+    host.deactivate(2);
+    assert_eq!(host.active_count(), 1);
+    for _ in 0..30 {
+        host.tick();
+    }
+    // Only the active group times out and campaigns.
+    assert_eq!(host.group(1).unwrap().raft.state, StateRole::Candidate);
+    assert_eq!(host.group(2).unwrap().raft.state, StateRole::Follower);
+    assert_eq!(host.group(2).unwrap().raft.election_elapsed, 0);
+
+    // A message wakes the group up.
+    let mut m = new_message(peer_id(2, 2), peer_id(2, 1), MessageType::MsgHeartbeat, 0);
+    m.term = 1;
+    host.step(GroupMessage::new(2, m)).unwrap();
+    assert!(host.is_active(2));
+
+    let m = new_message(1, 1, MessageType::MsgHeartbeat, 0);
+    assert!(matches!(
+        host.step(GroupMessage::new(3, m)),
+        Err(Error::NotExists { id: 3, .. })
+    ));
+}
+
+#[test]
+fn test_multi_raft_hibernate() {
+    let l = default_logger();
+    let groups = [1, 2, 3];
+    let mut hosts: Vec<_> = STORES
+        .iter()
+        .map(|s| new_host_with_hibernate(*s, &groups, 10, &l))
+        .collect();
+    for (&g, host) in groups.iter().zip(hosts.iter_mut()) {
+        host.group_mut(g).unwrap().campaign().unwrap();
+    }
+    pump(&mut hosts);
+
+    for _ in 0..30 {
+        for host in hosts.iter_mut() {
+            host.tick();
+        }
+        pump(&mut hosts);
+    }
+    // Idle groups hibernate and are no longer ticked.
+    for host in &hosts {
+        assert_eq!(host.active_count(), 0);
+        for g in groups {
+            assert!(host.group(g).unwrap().is_hibernated());
+        }
+    }
+
+    // A proposal wakes the group on every store.
+    hosts[0].propose(1, vec![], b"somedata".to_vec()).unwrap();
+    let applied = pump(&mut hosts);
+    assert_eq!(applied, vec![1, 1, 1]);
+    for host in &hosts {
+        assert!(host.is_active(1));
+        assert!(!host.is_active(2));
+    }
+
+    // Store 2 is down, the groups it leads are woken up on other stores.
+    hosts[0].wake_store(2);
+    assert!(hosts[0].is_active(2));
+    assert!(!hosts[0].group(2).unwrap().is_hibernated());
+    assert!(hosts[0].group(3).unwrap().is_hibernated());
+}
@@ -17,6 +17,8 @@
 use std::cmp;
 use std::collections::HashMap;
 use std::panic::{self, AssertUnwindSafe};
+use std::sync::Arc;
+use std::time::Duration;
 
 use harness::*;
 use protobuf::Message as PbMessage;
@@ -5851,3 +5853,670 @@ fn test_switching_check_quorum() {
     }
     assert_eq!(sm.state, StateRole::Leader);
 }
+
+fn new_hibernate_network(read_only_option: ReadOnlyOption, l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut config = new_test_config(id, 10, 1);
+        config.check_quorum = true;
+        config.pre_vote = true;
+        config.hibernate_tick = 10;
+        config.read_only_option = read_only_option;
+        let storage = new_storage();
+        storage.initialize_with_conf_state((vec![1, 2, 3], vec![]));
+        peers.push(Some(new_test_raft_with_config(&config, storage, l)));
+    }
+    let mut nt = Network::new(peers, l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt
+}
+
+// Ticks every peer once and delivers the generated messages.
+fn tick_network(nt: &mut Network) {
+    for id in 1..=3 {
+        nt.peers.get_mut(&id).unwrap().tick();
+    }
+    let msgs = nt.read_messages();
+    nt.filter_and_send(msgs);
+}
+
+#[test]
+fn test_hibernate_idle_group() {
+    let l = default_logger();
+    let mut nt = new_hibernate_network(ReadOnlyOption::Safe, &l);
+
+    for _ in 0..20 {
+        tick_network(&mut nt);
+    }
+    for id in 1..=3 {
+        assert!(nt.peers[&id].hibernated, "{} should hibernate", id);
+    }
+
+    // A hibernating group exchanges no messages and never campaigns.
+    for _ in 0..100 {
+        for id in 1..=3 {
+            assert!(!nt.peers.get_mut(&id).unwrap().tick());
+        }
+        assert!(nt.read_messages().is_empty());
+    }
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // A proposal wakes the whole group up.
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    for id in 1..=3 {
+        let p = &nt.peers[&id];
+        assert!(!p.hibernated, "{} should be woken up", id);
+        assert_eq!(p.raft_log.committed, 2);
+    }
+
+    // And it hibernates again once idle.
+    for _ in 0..20 {
+        tick_network(&mut nt);
+    }
+    for id in 1..=3 {
+        assert!(nt.peers[&id].hibernated, "{} should hibernate", id);
+    }
+}
+
+#[test]
+fn test_hibernate_rejected_by_lagging_follower() {
+    let l = default_logger();
+    let mut nt = new_hibernate_network(ReadOnlyOption::Safe, &l);
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+
+    for _ in 0..30 {
+        tick_network(&mut nt);
+    }
+    // The leader keeps ticking as long as a follower is behind.
+    assert!(!nt.peers[&1].hibernated);
+
+    nt.recover();
+    for _ in 0..30 {
+        tick_network(&mut nt);
+    }
+    for id in 1..=3 {
+        assert!(nt.peers[&id].hibernated, "{} should hibernate", id);
+        assert_eq!(nt.peers[&id].raft_log.committed, 2);
+    }
+}
+
+#[test]
+fn test_hibernate_wake_on_leader_failure() {
+    let l = default_logger();
+    let mut nt = new_hibernate_network(ReadOnlyOption::Safe, &l);
+    for _ in 0..20 {
+        tick_network(&mut nt);
+    }
+    assert!(nt.peers[&2].hibernated);
+
+    // The liveness check of the leader fails.
+    nt.isolate(1);
+    nt.peers.get_mut(&2).unwrap().wake();
+    let msgs = nt.read_messages();
+    nt.filter_and_send(msgs);
+    for _ in 0..30 {
+        tick_network(&mut nt);
+    }
+
+    // Peer 3 wakes up on the pre-vote and doesn't trust the stale lease.
+    assert_eq!(nt.peers[&2].state, StateRole::Leader);
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].leader_id, 2);
+    assert!(nt.peers[&1].hibernated);
+
+    // The old leader steps down once it hears from the group.
+    nt.recover();
+    for _ in 0..2 {
+        tick_network(&mut nt);
+    }
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+    assert_eq!(nt.peers[&1].leader_id, 2);
+}
+
+#[test]
+fn test_hibernate_suspends_lease_read() {
+    let l = default_logger();
+    let mut nt = new_hibernate_network(ReadOnlyOption::LeaseBased, &l);
+    for _ in 0..20 {
+        tick_network(&mut nt);
+    }
+    assert!(nt.peers[&1].hibernated);
+
+    // A woken leader can't serve lease reads before it confirms the quorum.
+    nt.isolate(1);
+    let mut m = new_message(1, 1, MessageType::MsgReadIndex, 0);
+    m.set_entries(vec![new_entry(0, 0, Some("ctx1"))].into());
+    nt.send(vec![m.clone()]);
+    assert!(!nt.peers[&1].hibernated);
+    assert!(!nt.peers[&1].in_lease());
+    assert!(nt.peers[&1].read_states.is_empty());
+
+    nt.recover();
+    tick_network(&mut nt);
+    assert!(nt.peers[&1].in_lease());
+    nt.send(vec![m]);
+    assert_eq!(nt.peers[&1].read_states.len(), 1);
+}
+
+#[test]
+fn test_hibernate_stale_read() {
+    let l = default_logger();
+    let mut nt = new_hibernate_network(ReadOnlyOption::Safe, &l);
+    for _ in 0..20 {
+        tick_network(&mut nt);
+    }
+    assert!(nt.peers[&3].hibernated);
+    let committed = nt.peers[&1].raft_log.committed;
+    nt.peers.get_mut(&3).unwrap().commit_apply(committed);
+    let elapsed = nt.peers[&3].leader_contact_elapsed().unwrap();
+    assert!(nt.peers[&3].can_read_stale(elapsed));
+
+    // Partitioned while hibernating, the follower can't tell how old its data is.
+    nt.isolate(3);
+    for _ in 0..10 {
+        assert!(!nt.peers.get_mut(&3).unwrap().tick());
+    }
+    assert!(nt.peers[&3].hibernated);
+    assert_eq!(nt.peers[&3].leader_contact_elapsed(), Some(elapsed + 10));
+    assert!(!nt.peers[&3].can_read_stale(elapsed + 9));
+}
+
+// Creates a network where 1 and 2 are full voters and 3 is a witness.
+fn new_witness_network(l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut cs = conf_state(vec![1, 2, 3], vec![]);
+        cs.set_witnesses(vec![3]);
+        let s = MemStorage::new_with_conf_state(cs);
+        let c = new_test_config(id, 10, 1);
+        peers.push(Some(new_test_raft_with_config(&c, s, l)));
+    }
+    Network::new(peers, l)
+}
+
+#[test]
+fn test_witness_receives_entries_without_data() {
+    let l = default_logger();
+    let mut nt = new_witness_network(&l);
+    assert!(nt.peers[&3].is_witness());
+    assert!(!nt.peers[&3].promotable());
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+
+    let last = nt.peers[&1].raft_log.last_index();
+    for id in 1..=3 {
+        let p = &nt.peers[&id];
+        assert_eq!(p.raft_log.committed, last, "#{}", id);
+        let ents = p.raft_log.all_entries();
+        let e = ents.last().unwrap();
+        assert_eq!(e.index, last, "#{}", id);
+        assert_eq!(e.term, nt.peers[&1].term, "#{}", id);
+        // Only the witness keeps no data.
+        assert_eq!(e.data.is_empty(), id == 3, "#{}", id);
+    }
+}
+
+#[test]
+fn test_witness_counts_toward_quorum() {
+    let l = default_logger();
+    let mut nt = new_witness_network(&l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.isolate(2);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let last = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last);
+    assert_eq!(nt.peers[&3].raft_log.committed, last);
+
+    // The witness votes, so 2 can be elected with it once 1 is gone.
+    nt.recover();
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    nt.isolate(1);
+    nt.send(vec![new_message(2, 2, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&2].state, StateRole::Leader);
+}
+
+#[test]
+fn test_witness_never_becomes_leader() {
+    let l = default_logger();
+    let mut nt = new_witness_network(&l);
+
+    let term = nt.peers[&3].term;
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert_eq!(nt.peers[&3].term, term);
+
+    for _ in 0..100 {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&3].state, StateRole::Follower);
+    assert!(nt.peers.get_mut(&3).unwrap().read_messages().is_empty());
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.send(vec![new_message(3, 1, MessageType::MsgTransferLeader, 0)]);
+    check_leader_transfer_state(&nt.peers[&1], StateRole::Leader, 1);
+
+    // A witness can't be turned into a full voter.
+    let cc = conf_change_v2(vec![new_conf_change_single(3, ConfChangeType::AddNode)]);
+    assert!(nt
+        .peers
+        .get_mut(&1)
+        .unwrap()
+        .apply_conf_change(&cc)
+        .is_err());
+}
+
+// Voter 1 holds 3 of the 5 votes of voters (1, 2, 3).
+fn new_weighted_network(check_quorum: bool, l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut cs = conf_state(vec![1, 2, 3], vec![]);
+        let mut w = VoterWeight::default();
+        w.id = 1;
+        w.weight = 3;
+        cs.mut_weights().push(w);
+        let s = MemStorage::new_with_conf_state(cs);
+        let mut c = new_test_config(id, 10, 1);
+        c.check_quorum = check_quorum;
+        peers.push(Some(new_test_raft_with_config(&c, s, l)));
+    }
+    Network::new(peers, l)
+}
+
+#[test]
+fn test_weighted_voter_elects_and_commits_alone() {
+    let l = default_logger();
+    let mut nt = new_weighted_network(false, &l);
+    nt.isolate(1);
+    nt.send(vec![new_message(2, 2, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&2].state, StateRole::Candidate);
+
+    nt.recover();
+    nt.isolate(2);
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let last = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last);
+
+    let cs = nt.peers[&1].prs().conf().to_conf_state();
+    assert_eq!(cs.get_weights().len(), 1);
+    assert_eq!((cs.get_weights()[0].id, cs.get_weights()[0].weight), (1, 3));
+}
+
+#[test]
+fn test_weighted_check_quorum() {
+    let l = default_logger();
+    let mut nt = new_weighted_network(true, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // Voter 1 alone is an active quorum.
+    nt.isolate(1);
+    for _ in 0..=nt.peers[&1].election_timeout() {
+        nt.peers.get_mut(&1).unwrap().tick();
+    }
+    nt.send(vec![new_message(1, 1, MessageType::MsgCheckQuorum, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+}
+
+#[test]
+fn test_readd_voter_keeps_weight_and_zone() {
+    let l = default_logger();
+    let cc = conf_change_v2(vec![new_conf_change_single(1, ConfChangeType::AddNode)]);
+
+    let mut nt = new_weighted_network(false, &l);
+    let cs = nt
+        .peers
+        .get_mut(&1)
+        .unwrap()
+        .apply_conf_change(&cc)
+        .unwrap();
+    assert_eq!(cs.get_weights().len(), 1);
+    assert_eq!((cs.get_weights()[0].id, cs.get_weights()[0].weight), (1, 3));
+
+    let mut nt = new_zoned_network(&l);
+    let cs = nt
+        .peers
+        .get_mut(&1)
+        .unwrap()
+        .apply_conf_change(&cc)
+        .unwrap();
+    assert_eq!(cs.get_zones().len(), 5);
+    let zone = cs.get_zones().iter().find(|z| z.id == 1).unwrap();
+    assert_eq!(zone.zone, 1);
+}
+
+// Five voters that need four votes to elect a leader and two acks to commit.
+fn new_flexible_network(l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=5 {
+        let s = MemStorage::new_with_conf_state((vec![1, 2, 3, 4, 5], vec![]));
+        let mut c = new_test_config(id, 10, 1);
+        c.check_quorum = true;
+        c.election_quorum = 4;
+        c.commit_quorum = 2;
+        peers.push(Some(new_test_raft_with_config(&c, s, l)));
+    }
+    Network::new(peers, l)
+}
+
+#[test]
+fn test_flexible_quorum_commit() {
+    let l = default_logger();
+    let mut nt = new_flexible_network(&l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // Two acks are enough to commit.
+    for id in 3..=5 {
+        nt.isolate(id);
+    }
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let last = nt.peers[&1].raft_log.last_index();
+    assert_eq!(nt.peers[&1].raft_log.committed, last);
+
+    // And they keep the leader in charge.
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgCheckQuorum, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // One ack is not.
+    nt.isolate(2);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    assert_eq!(nt.peers[&1].raft_log.committed, last);
+    for _ in 0..=nt.peers[&1].election_timeout() {
+        nt.peers.get_mut(&1).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+}
+
+#[test]
+fn test_flexible_quorum_election() {
+    let l = default_logger();
+    let mut nt = new_flexible_network(&l);
+
+    // A majority is not enough to win an election.
+    nt.isolate(4);
+    nt.isolate(5);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Candidate);
+
+    nt.recover();
+    nt.isolate(5);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+}
+
+#[test]
+fn test_flexible_quorum_config() {
+    let l = default_logger();
+    let mut c = new_test_config(1, 10, 1);
+    c.election_quorum = 4;
+    c.validate().unwrap_err();
+    c.commit_quorum = 5;
+    c.validate().unwrap_err();
+    c.commit_quorum = 2;
+    c.validate().unwrap();
+
+    // Quorums that don't intersect can't be used for the initial config...
+    let s = MemStorage::new_with_conf_state((vec![1, 2, 3, 4, 5, 6], vec![]));
+    assert!(Raft::new(&c, s, &l).is_err());
+
+    // ...nor be reached by a conf change.
+    let s = MemStorage::new_with_conf_state((vec![1, 2, 3, 4, 5], vec![]));
+    let mut r = Raft::new(&c, s, &l).unwrap();
+    let cc = conf_change_v2(vec![new_conf_change_single(6, ConfChangeType::AddNode)]);
+    assert!(r.apply_conf_change(&cc).is_err());
+    let cc = conf_change_v2(vec![new_conf_change_single(5, ConfChangeType::RemoveNode)]);
+    assert!(r.apply_conf_change(&cc).is_ok());
+}
+
+// Voters 1 and 2 are in zone 1, 3 and 4 in zone 2, and 5 in zone 3.
+fn new_zoned_network(l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=5 {
+        let mut cs = conf_state(vec![1, 2, 3, 4, 5], vec![]);
+        for (id, zone) in [(1, 1), (2, 1), (3, 2), (4, 2), (5, 3)] {
+            let mut z = VoterZone::default();
+            z.id = id;
+            z.zone = zone;
+            cs.mut_zones().push(z);
+        }
+        let s = MemStorage::new_with_conf_state(cs);
+        let mut c = new_test_config(id, 10, 1);
+        c.check_quorum = true;
+        peers.push(Some(new_test_raft_with_config(&c, s, l)));
+    }
+    Network::new(peers, l)
+}
+
+#[test]
+fn test_hierarchical_quorum_election() {
+    let l = default_logger();
+    let mut nt = new_zoned_network(&l);
+
+    // A majority of voters is not a majority of zones.
+    nt.isolate(2);
+    nt.isolate(4);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Candidate);
+
+    nt.recover();
+    nt.isolate(3);
+    nt.isolate(4);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // Zones are kept in the conf state.
+    let cs = nt.peers[&1].prs().conf().to_conf_state();
+    assert_eq!(cs.get_zones().len(), 5);
+}
+
+#[test]
+fn test_hierarchical_quorum_commit_and_check_quorum() {
+    let l = default_logger();
+    let mut nt = new_zoned_network(&l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    let committed = nt.peers[&1].raft_log.committed;
+
+    nt.isolate(2);
+    nt.isolate(4);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    assert_eq!(nt.peers[&1].raft_log.committed, committed);
+
+    // Peers that were active before the isolation still count once.
+    nt.send(vec![new_message(1, 1, MessageType::MsgCheckQuorum, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgCheckQuorum, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Follower);
+}
+
+fn new_clock_lease_network(clock: &MockClock, l: &Logger) -> Network {
+    let mut peers = vec![];
+    for id in 1..=3 {
+        let mut c = new_test_config(id, 10, 1);
+        c.check_quorum = true;
+        c.read_only_option = ReadOnlyOption::LeaseBased;
+        c.lease_duration = Duration::from_millis(100);
+        c.max_clock_drift = Duration::from_millis(10);
+        let s = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+        let mut r = new_test_raft_with_config(&c, s, l);
+        r.set_clock(Arc::new(clock.clone()));
+        peers.push(Some(r));
+    }
+    Network::new(peers, l)
+}
+
+#[test]
+fn test_clock_lease_read() {
+    let l = default_logger();
+    let clock = MockClock::new();
+    let mut nt = new_clock_lease_network(&clock, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    let read = |nt: &mut Network, ctx: &str| {
+        nt.peers.get_mut(&1).unwrap().read_states.clear();
+        let entry = new_entry(0, 0, Some(ctx));
+        nt.send(vec![new_message_with_entries(
+            1,
+            1,
+            MessageType::MsgReadIndex,
+            vec![entry],
+        )]);
+        !nt.peers[&1].read_states.is_empty()
+    };
+
+    // No heartbeat has been acknowledged yet.
+    assert!(!read(&mut nt, "ctx1"));
+
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert!(read(&mut nt, "ctx2"));
+
+    // The lease ends max_clock_drift before lease_duration.
+    clock.advance(Duration::from_millis(89));
+    assert!(read(&mut nt, "ctx3"));
+    clock.advance(Duration::from_millis(1));
+    assert!(!read(&mut nt, "ctx4"));
+
+    // Acks from a minority don't renew the lease.
+    nt.isolate(2);
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert!(!read(&mut nt, "ctx5"));
+
+    nt.recover();
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert!(read(&mut nt, "ctx6"));
+}
+
+#[test]
+fn test_clock_lease_rejects_vote() {
+    let l = default_logger();
+    let clock = MockClock::new();
+    let mut nt = new_clock_lease_network(&clock, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+
+    // 2 has counted out the election timeout, but the clock lease still holds.
+    let timeout = nt.peers[&2].election_timeout();
+    nt.peers
+        .get_mut(&2)
+        .unwrap()
+        .set_randomized_election_timeout(timeout + 1);
+    for _ in 0..timeout {
+        nt.peers.get_mut(&2).unwrap().tick();
+    }
+    nt.isolate(1);
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Candidate);
+
+    clock.advance(Duration::from_millis(100));
+    nt.send(vec![new_message(3, 3, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&3].state, StateRole::Leader);
+}
+
+#[test]
+fn test_clock_lease_config() {
+    let mut c = new_test_config(1, 10, 1);
+    c.check_quorum = true;
+    c.lease_duration = Duration::from_millis(100);
+    assert!(c.validate().is_err());
+    c.read_only_option = ReadOnlyOption::LeaseBased;
+    assert!(c.validate().is_ok());
+    c.max_clock_drift = c.lease_duration;
+    assert!(c.validate().is_err());
+}
+
+#[test]
+fn test_stale_read_on_follower() {
+    let l = default_logger();
+    let mut nt = Network::new(vec![None, None, None], &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    let committed = nt.peers[&1].raft_log.committed;
+    assert_eq!(nt.peers[&3].leader_commit(), committed);
+
+    // Nothing is applied yet.
+    assert!(!nt.peers[&3].can_read_applied(committed));
+    assert!(!nt.peers[&3].can_read_stale(10));
+    nt.peers.get_mut(&3).unwrap().commit_apply(committed);
+    assert!(nt.peers[&3].can_read_applied(committed));
+    assert!(nt.peers[&3].can_read_stale(0));
+
+    // The data gets older while the leader can't be reached.
+    nt.isolate(3);
+    for _ in 0..3 {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert!(!nt.peers[&3].can_read_stale(2));
+    assert!(nt.peers[&3].can_read_stale(3));
+    let status = Status::new(&nt.peers[&3]);
+    assert_eq!(status.leader_commit, committed);
+    assert_eq!(status.leader_contact_elapsed, Some(3));
+
+    // Heartbeats tell the follower it is behind, even though their commit
+    // index is capped by what it matched.
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    nt.recover();
+    nt.ignore(MessageType::MsgAppend);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    assert_eq!(nt.peers[&3].leader_commit(), committed + 1);
+    assert_eq!(nt.peers[&3].raft_log.committed, committed);
+    assert!(!nt.peers[&3].can_read_stale(10));
+}
+
+#[test]
+fn test_priority_rebalance() {
+    let l = default_logger();
+    let mut peers = vec![];
+    for (id, priority) in [(1, 1), (2, 1), (3, 5)] {
+        let s = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+        let mut c = new_test_config(id, 10, 1);
+        c.priority = priority;
+        c.priority_rebalance_tick = 3;
+        peers.push(Some(new_test_raft_with_config(&c, s, &l)));
+    }
+    let mut nt = Network::new(peers, &l);
+    nt.send(vec![new_message(1, 1, MessageType::MsgHup, 0)]);
+    assert_eq!(nt.peers[&1].state, StateRole::Leader);
+
+    // A lagging voter is not a candidate.
+    nt.isolate(3);
+    nt.send(vec![new_message(1, 1, MessageType::MsgPropose, 1)]);
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    for _ in 0..5 {
+        nt.peers.get_mut(&1).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&1].lead_transferee, None);
+
+    // Once 3 catches up, leadership only moves after the hysteresis.
+    nt.recover();
+    nt.send(vec![new_message(1, 1, MessageType::MsgBeat, 0)]);
+    nt.peers.get_mut(&1).unwrap().read_messages();
+    for _ in 0..2 {
+        nt.peers.get_mut(&1).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&1].lead_transferee, None);
+    nt.peers.get_mut(&1).unwrap().tick();
+    assert_eq!(nt.peers[&1].lead_transferee, Some(3));
+    let msgs = nt.peers.get_mut(&1).unwrap().read_messages();
+    nt.send(msgs);
+    assert_eq!(nt.peers[&3].state, StateRole::Leader);
+
+    // And it doesn't go back to a lower priority.
+    nt.send(vec![new_message(3, 3, MessageType::MsgBeat, 0)]);
+    for _ in 0..5 {
+        nt.peers.get_mut(&3).unwrap().tick();
+    }
+    assert_eq!(nt.peers[&3].lead_transferee, None);
+}
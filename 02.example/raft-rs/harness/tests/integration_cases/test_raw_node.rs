@@ -732,6 +732,54 @@ fn test_raw_node_restart_from_snapshot() {
     assert!(!raw_node.has_ready());
 }
 
+// A survivor whose peers are lost for good restarts as a group of its own and
+// takes new members.
+#[test]
+fn test_raw_node_force_new_cluster() {
+    let l = default_logger();
+    let s = MemStorage::new_with_conf_state((vec![1, 2, 3], vec![]));
+    let entries = vec![
+        empty_entry(1, 1),
+        new_entry(2, 2, Some("foo")),
+        new_entry(2, 3, Some("bar")),
+    ];
+    s.wl().append(&entries).unwrap();
+    s.wl().set_hardstate(hard_state(2, 2, 2));
+    s.wl().force_new_cluster(vec![1]).unwrap();
+    assert_eq!(s.last_index().unwrap(), 2);
+
+    let mut config = new_test_config(1, 10, 1);
+    config.applied = 2;
+    let mut raw_node = RawNode::new(&config, s.clone(), &l).unwrap();
+    assert_eq!(raw_node.raft.term, 3);
+    raw_node.campaign().unwrap();
+    assert_eq!(raw_node.raft.state, StateRole::Leader);
+
+    raw_node
+        .propose_conf_change(vec![], conf_change(ConfChangeType::AddNode, 2))
+        .unwrap();
+    let mut committed = vec![];
+    while raw_node.has_ready() {
+        let mut rd = raw_node.ready();
+        s.wl().append(rd.entries()).unwrap();
+        if let Some(hs) = rd.hs() {
+            s.wl().set_hardstate(hs.clone());
+        }
+        committed.extend(rd.take_committed_entries());
+        let mut light_rd = raw_node.advance(rd);
+        committed.extend(light_rd.take_committed_entries());
+        raw_node.advance_apply();
+    }
+    for e in committed {
+        if e.get_entry_type() == EntryType::EntryConfChange {
+            let mut cc = ConfChange::default();
+            cc.merge_from_bytes(&e.data).unwrap();
+            raw_node.apply_conf_change(&cc).unwrap();
+        }
+    }
+    assert!(raw_node.raft.prs().conf().voters().contains(2));
+}
+
 // test_skip_bcast_commit ensures that empty commit message is not sent out
 // when skip_bcast_commit is true.
 #[test]
@@ -1912,3 +1960,97 @@ impl Storage for IgnoreSizeHintMemStorage {
         self.inner.snapshot(request_index, to)
     }
 }
+
+#[test]
+fn test_raw_node_proposal_outcome() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    let _ = raw_node.advance(rd);
+
+    let handle = raw_node.propose(vec![], b"data".to_vec()).unwrap().unwrap();
+    assert_eq!(handle.index, raw_node.raft.raft_log.last_index());
+    assert_eq!(handle.term, raw_node.raft.term);
+
+    let rd = raw_node.ready();
+    assert!(rd.proposals().is_empty());
+    s.wl().append(rd.entries()).unwrap();
+    let mut light_rd = raw_node.advance(rd);
+    let committed = light_rd.take_committed_entries();
+    assert_eq!(committed.last().unwrap().index, handle.index);
+    assert_eq!(
+        light_rd.proposals(),
+        &[ProposalOutcome::Committed(handle)][..]
+    );
+}
+
+#[test]
+fn test_raw_node_refused_conf_change_not_tracked() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    let _ = raw_node.advance(rd);
+
+    let handle = raw_node
+        .propose_conf_change(vec![], conf_change(ConfChangeType::AddNode, 2))
+        .unwrap()
+        .unwrap();
+    // The first conf change is still pending, so the leader refuses this one.
+    let refused = raw_node.propose_conf_change(vec![], conf_change(ConfChangeType::AddNode, 3));
+    assert_eq!(refused, Ok(None));
+    assert_eq!(raw_node.raft.raft_log.last_index(), handle.index + 1);
+
+    let mut outcomes = vec![];
+    while raw_node.has_ready() {
+        let rd = raw_node.ready();
+        outcomes.extend_from_slice(rd.proposals());
+        s.wl().append(rd.entries()).unwrap();
+        let mut light_rd = raw_node.advance(rd);
+        outcomes.extend_from_slice(light_rd.proposals());
+        let _ = light_rd.take_committed_entries();
+        raw_node.advance_apply();
+    }
+    assert_eq!(outcomes, vec![ProposalOutcome::Committed(handle)]);
+}
+
+#[test]
+fn test_raw_node_proposal_lost() {
+    let l = default_logger();
+    let s = new_storage();
+    let mut raw_node = new_raw_node(1, vec![1, 2, 3], 10, 1, s.clone(), &l);
+    raw_node.raft.become_candidate();
+    raw_node.raft.become_leader();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    let _ = raw_node.advance(rd);
+    let handle = raw_node.propose(vec![], b"data".to_vec()).unwrap().unwrap();
+    let rd = raw_node.ready();
+    s.wl().append(rd.entries()).unwrap();
+    let _ = raw_node.advance(rd);
+
+    // A new leader overwrites the index of the proposal.
+    let term = handle.term + 1;
+    let mut m = new_message_with_entries(
+        2,
+        1,
+        MessageType::MsgAppend,
+        vec![new_entry(term, handle.index, None)],
+    );
+    m.term = term;
+    m.index = handle.index - 1;
+    m.log_term = handle.term;
+    raw_node.step(m).unwrap();
+    let rd = raw_node.ready();
+    assert_eq!(rd.proposals(), &[ProposalOutcome::Lost(handle)][..]);
+
+    // Proposals forwarded to the leader are not tracked.
+    assert_eq!(raw_node.propose(vec![], b"data".to_vec()), Ok(None));
+}
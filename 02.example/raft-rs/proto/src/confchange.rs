@@ -18,7 +18,11 @@ pub fn new_conf_change_single(node_id: u64, ty: ConfChangeType) -> ConfChangeSin
 /// The supported operations are:
 /// - vn: make n a voter,
 /// - ln: make n a learner,
+/// - wn: make n a witness,
 /// - rn: remove n
+///
+/// A voter or witness can be given a weight other than 1 and a zone with
+/// suffixes, e.g. `v1:3@2` makes 1 a voter of weight 3 in zone 2.
 pub fn parse_conf_change(s: &str) -> Result<Vec<ConfChangeSingle>, String> {
     let s = s.trim();
     if s.is_empty() {
@@ -35,13 +39,34 @@ pub fn parse_conf_change(s: &str) -> Result<Vec<ConfChangeSingle>, String> {
         cc.set_change_type(match chars.next().unwrap() {
             'v' => ConfChangeType::AddNode,
             'l' => ConfChangeType::AddLearnerNode,
+            'w' => ConfChangeType::AddWitnessNode,
             'r' => ConfChangeType::RemoveNode,
             _ => return Err(format!("unknown token {}", tok)),
         });
-        cc.node_id = match chars.as_str().parse() {
+        let (rest, zone) = match chars.as_str().split_once('@') {
+            Some((rest, zone)) => (rest, Some(zone)),
+            None => (chars.as_str(), None),
+        };
+        let (id, weight) = match rest.split_once(':') {
+            Some((id, weight)) => (id, Some(weight)),
+            None => (rest, None),
+        };
+        cc.node_id = match id.parse() {
             Ok(id) => id,
             Err(e) => return Err(format!("parse token {} fail: {}", tok, e)),
         };
+        if let Some(weight) = weight {
+            cc.weight = match weight.parse() {
+                Ok(weight) => weight,
+                Err(e) => return Err(format!("parse token {} fail: {}", tok, e)),
+            };
+        }
+        if let Some(zone) = zone {
+            cc.zone = match zone.parse() {
+                Ok(zone) => zone,
+                Err(e) => return Err(format!("parse token {} fail: {}", tok, e)),
+            };
+        }
         ccs.push(cc);
     }
     Ok(ccs)
@@ -57,9 +82,16 @@ pub fn stringify_conf_change(ccs: &[ConfChangeSingle]) -> String {
         match cc.get_change_type() {
             ConfChangeType::AddNode => s.push('v'),
             ConfChangeType::AddLearnerNode => s.push('l'),
+            ConfChangeType::AddWitnessNode => s.push('w'),
             ConfChangeType::RemoveNode => s.push('r'),
         }
         write!(&mut s, "{}", cc.node_id).unwrap();
+        if cc.weight != 0 {
+            write!(&mut s, ":{}", cc.weight).unwrap();
+        }
+        if cc.zone != 0 {
+            write!(&mut s, "@{}", cc.zone).unwrap();
+        }
     }
     s
 }
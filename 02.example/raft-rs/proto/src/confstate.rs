@@ -1,6 +1,6 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
-use crate::eraftpb::ConfState;
+use crate::eraftpb::{ConfState, VoterWeight, VoterZone};
 
 fn eq_without_order(lhs: &[u64], rhs: &[u64]) -> bool {
     for l in lhs {
@@ -16,6 +16,14 @@ fn eq_without_order(lhs: &[u64], rhs: &[u64]) -> bool {
     true
 }
 
+fn weights_eq_without_order(lhs: &[VoterWeight], rhs: &[VoterWeight]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().all(|l| rhs.contains(l))
+}
+
+fn zones_eq_without_order(lhs: &[VoterZone], rhs: &[VoterZone]) -> bool {
+    lhs.len() == rhs.len() && lhs.iter().all(|l| rhs.contains(l))
+}
+
 // Returns true if the inputs describe the same configuration.
 #[must_use]
 pub fn conf_state_eq(lhs: &ConfState, rhs: &ConfState) -> bool {
@@ -27,6 +35,11 @@ pub fn conf_state_eq(lhs: &ConfState, rhs: &ConfState) -> bool {
         && lhs.get_learners() == rhs.get_learners()
         && lhs.get_voters_outgoing() == rhs.get_voters_outgoing()
         && lhs.get_learners_next() == rhs.get_learners_next()
+        && lhs.get_witnesses() == rhs.get_witnesses()
+        && lhs.get_weights() == rhs.get_weights()
+        && lhs.get_weights_outgoing() == rhs.get_weights_outgoing()
+        && lhs.get_zones() == rhs.get_zones()
+        && lhs.get_zones_outgoing() == rhs.get_zones_outgoing()
         && lhs.auto_leave == rhs.auto_leave
     {
         return true;
@@ -36,5 +49,10 @@ pub fn conf_state_eq(lhs: &ConfState, rhs: &ConfState) -> bool {
         && eq_without_order(lhs.get_learners(), rhs.get_learners())
         && eq_without_order(lhs.get_voters_outgoing(), rhs.get_voters_outgoing())
         && eq_without_order(lhs.get_learners_next(), rhs.get_learners_next())
+        && eq_without_order(lhs.get_witnesses(), rhs.get_witnesses())
+        && weights_eq_without_order(lhs.get_weights(), rhs.get_weights())
+        && weights_eq_without_order(lhs.get_weights_outgoing(), rhs.get_weights_outgoing())
+        && zones_eq_without_order(lhs.get_zones(), rhs.get_zones())
+        && zones_eq_without_order(lhs.get_zones_outgoing(), rhs.get_zones_outgoing())
         && lhs.auto_leave == rhs.auto_leave
 }
@@ -79,9 +79,7 @@ impl Changer<'_> {
                 "can't make a zero-voter config joint".to_owned(),
             ));
         }
-        cfg.voters
-            .outgoing
-            .extend(cfg.voters.incoming.iter().cloned());
+        cfg.voters.outgoing = cfg.voters.incoming.clone();
         self.apply(&mut cfg, &mut prs, ccs)?;
         cfg.auto_leave = auto_leave;
         check_invariants(&cfg, &prs)?;
@@ -119,6 +117,7 @@ impl Changer<'_> {
         for id in &*cfg.voters.outgoing {
             if !cfg.voters.incoming.contains(id) && !cfg.learners.contains(id) {
                 prs.changes.push((*id, MapChangeType::Remove));
+                cfg.witnesses.remove(id);
             }
         }
 
@@ -141,17 +140,41 @@ impl Changer<'_> {
         let (mut cfg, mut prs) = self.check_and_copy()?;
         self.apply(&mut cfg, &mut prs, ccs)?;
 
-        if cfg
-            .voters
-            .incoming
-            .symmetric_difference(&self.tracker.conf().voters.incoming)
-            .count()
-            > 1
-        {
+        let (old, new) = (&self.tracker.conf().voters.incoming, &cfg.voters.incoming);
+        if new.symmetric_difference(old).count() > 1 {
             return Err(Error::ConfChangeError(
                 "more than one voter changed without entering joint config".to_owned(),
             ));
         }
+        // Adding or removing a voter of weight 1 keeps any two quorums intersecting,
+        // any other change of weights needs joint consensus.
+        if !old.is_empty() {
+            if let Some(id) = new
+                .union(old)
+                .find(|id| new.weight(**id) != old.weight(**id))
+            {
+                return Err(Error::ConfChangeError(format!(
+                    "weight of voter {} changed without entering joint config",
+                    id
+                )));
+            }
+            // Moving a voter to another zone changes two zones at once, and
+            // switching between majority and hierarchical quorums changes all.
+            if let Some(id) = new
+                .intersection(old)
+                .find(|id| new.zone(**id) != old.zone(**id))
+            {
+                return Err(Error::ConfChangeError(format!(
+                    "zone of voter {} changed without entering joint config",
+                    id
+                )));
+            }
+            if new.is_hierarchical() != old.is_hierarchical() {
+                return Err(Error::ConfChangeError(
+                    "zones changed without entering joint config".to_owned(),
+                ));
+            }
+        }
         check_invariants(&cfg, &prs)?;
         Ok((cfg, prs.into_changes()))
     }
@@ -173,8 +196,17 @@ impl Changer<'_> {
                 continue;
             }
             match cc.get_change_type() {
-                ConfChangeType::AddNode => self.make_voter(cfg, prs, cc.node_id),
-                ConfChangeType::AddLearnerNode => self.make_learner(cfg, prs, cc.node_id),
+                ConfChangeType::AddNode => {
+                    let was_voter = cfg.voters.incoming.contains(&cc.node_id);
+                    self.make_voter(cfg, prs, cc.node_id)?;
+                    set_weight_and_zone(cfg, cc, was_voter);
+                }
+                ConfChangeType::AddLearnerNode => self.make_learner(cfg, prs, cc.node_id)?,
+                ConfChangeType::AddWitnessNode => {
+                    let was_voter = cfg.voters.incoming.contains(&cc.node_id);
+                    self.make_witness(cfg, prs, cc.node_id)?;
+                    set_weight_and_zone(cfg, cc, was_voter);
+                }
                 ConfChangeType::RemoveNode => self.remove(cfg, prs, cc.node_id),
             }
         }
@@ -185,15 +217,49 @@ impl Changer<'_> {
     }
 
     /// Adds or promotes the given ID to be a voter in the incoming majority config.
-    fn make_voter(&self, cfg: &mut Configuration, prs: &mut IncrChangeMap, id: u64) {
+    fn make_voter(&self, cfg: &mut Configuration, prs: &mut IncrChangeMap, id: u64) -> Result<()> {
         if !prs.contains(id) {
             self.init_progress(cfg, prs, id, false);
-            return;
+            return Ok(());
+        }
+        if cfg.witnesses.contains(&id) {
+            return Err(Error::ConfChangeError(format!(
+                "can't turn witness {} into a voter",
+                id
+            )));
         }
 
         cfg.voters.incoming.insert(id);
         cfg.learners.remove(&id);
         cfg.learners_next.remove(&id);
+        Ok(())
+    }
+
+    /// Adds the given ID as a witness voter in the incoming majority config.
+    ///
+    /// A witness keeps no entry data, so an existing voter or learner can't be
+    /// turned into a witness, and a witness can't be turned into anything else.
+    /// Re-adding a witness that is still in the outgoing config is allowed.
+    fn make_witness(
+        &self,
+        cfg: &mut Configuration,
+        prs: &mut IncrChangeMap,
+        id: u64,
+    ) -> Result<()> {
+        if !prs.contains(id) {
+            self.init_progress(cfg, prs, id, false);
+            cfg.witnesses.insert(id);
+            return Ok(());
+        }
+        if !cfg.witnesses.contains(&id) {
+            return Err(Error::ConfChangeError(format!(
+                "can't turn {} into a witness",
+                id
+            )));
+        }
+
+        cfg.voters.incoming.insert(id);
+        Ok(())
     }
 
     /// Makes the given ID a learner or stages it to be a learner once an active joint
@@ -207,14 +273,25 @@ impl Changer<'_> {
     /// because then we'd have to track a peer as a voter and learner simultaneously.
     /// Instead, we add the learner to LearnersNext, so that it will be added to Learners
     /// the moment the outgoing config is removed by LeaveJoint().
-    fn make_learner(&self, cfg: &mut Configuration, prs: &mut IncrChangeMap, id: u64) {
+    fn make_learner(
+        &self,
+        cfg: &mut Configuration,
+        prs: &mut IncrChangeMap,
+        id: u64,
+    ) -> Result<()> {
         if !prs.contains(id) {
             self.init_progress(cfg, prs, id, true);
-            return;
+            return Ok(());
+        }
+        if cfg.witnesses.contains(&id) {
+            return Err(Error::ConfChangeError(format!(
+                "can't turn witness {} into a learner",
+                id
+            )));
         }
 
         if cfg.learners.contains(&id) {
-            return;
+            return Ok(());
         }
 
         cfg.voters.incoming.remove(&id);
@@ -231,6 +308,7 @@ impl Changer<'_> {
         } else {
             cfg.learners.insert(id);
         }
+        Ok(())
     }
 
     /// Removes this peer as a voter or learner from the incoming config.
@@ -246,6 +324,7 @@ impl Changer<'_> {
         // If the peer is still a voter in the outgoing config, keep the Progress.
         if !cfg.voters.outgoing.contains(&id) {
             prs.changes.push((id, MapChangeType::Remove));
+            cfg.witnesses.remove(&id);
         }
     }
 
@@ -279,6 +358,17 @@ impl Changer<'_> {
     }
 }
 
+/// Sets the weight and zone of a voter added by `cc`. A voter that was already
+/// in the incoming config keeps them, unless the change sets new ones.
+fn set_weight_and_zone(cfg: &mut Configuration, cc: &ConfChangeSingle, was_voter: bool) {
+    if !was_voter || cc.weight != 0 {
+        cfg.voters.incoming.set_weight(cc.node_id, cc.weight);
+    }
+    if !was_voter || cc.zone != 0 {
+        cfg.voters.incoming.set_zone(cc.node_id, cc.zone);
+    }
+}
+
 /// Makes sure that the config and progress are compatible with each other.
 /// This is used to check both what the Changer is initialized with, as well
 /// as what it returns.
@@ -336,6 +426,30 @@ fn check_invariants(cfg: &Configuration, prs: &IncrChangeMap) -> Result<()> {
         }
     }
 
+    for id in &cfg.witnesses {
+        if !cfg.voters().contains(*id) {
+            return Err(Error::ConfChangeError(format!(
+                "witness {} is not a voter",
+                id
+            )));
+        }
+        if cfg.learners_next.contains(id) {
+            return Err(Error::ConfChangeError(format!(
+                "{} is in witnesses and learners_next",
+                id
+            )));
+        }
+    }
+
+    for voters in [&cfg.voters.incoming, &cfg.voters.outgoing] {
+        if !voters.is_flexible_quorum_safe() {
+            return Err(Error::ConfChangeError(format!(
+                "flexible quorum is unsafe for voters {}",
+                voters
+            )));
+        }
+    }
+
     if !super::joint(cfg) {
         // Etcd enforces outgoing and learner_next to be nil map. But there is no nil
         // in rust. We just check empty for simplicity.
@@ -41,13 +41,18 @@ fn to_conf_change_single(cs: &ConfState) -> (Vec<ConfChangeSingle>, Vec<ConfChan
     // as desired.
     let mut incoming = Vec::new();
     let mut outgoing = Vec::new();
+    // Witnesses are added as witnesses wherever they show up as voters.
+    let voter_type = |id: &u64| {
+        if cs.get_witnesses().contains(id) {
+            ConfChangeType::AddWitnessNode
+        } else {
+            ConfChangeType::AddNode
+        }
+    };
     for id in cs.get_voters_outgoing() {
         // If there are outgoing voters, first add them one by one so that the
         // (non-joint) config has them all.
-        outgoing.push(raft_proto::new_conf_change_single(
-            *id,
-            ConfChangeType::AddNode,
-        ));
+        outgoing.push(raft_proto::new_conf_change_single(*id, voter_type(id)));
     }
 
     // We're done constructing the outgoing slice, now on to the incoming one
@@ -62,10 +67,7 @@ fn to_conf_change_single(cs: &ConfState) -> (Vec<ConfChangeSingle>, Vec<ConfChan
     }
     // Then we'll add the incoming voters and learners.
     for id in cs.get_voters() {
-        incoming.push(raft_proto::new_conf_change_single(
-            *id,
-            ConfChangeType::AddNode,
-        ));
+        incoming.push(raft_proto::new_conf_change_single(*id, voter_type(id)));
     }
     for id in cs.get_learners() {
         incoming.push(raft_proto::new_conf_change_single(
@@ -103,5 +105,27 @@ pub fn restore(tracker: &mut ProgressTracker, next_idx: u64, cs: &ConfState) ->
         let (cfg, changes) = Changer::new(tracker).enter_joint(cs.auto_leave, &incoming)?;
         tracker.apply_conf(cfg, changes, next_idx);
     }
+    // The weights and zones were agreed on when the ConfState was created, so they
+    // are set directly instead of being checked one change at a time.
+    if !cs.get_weights().is_empty()
+        || !cs.get_weights_outgoing().is_empty()
+        || !cs.get_zones().is_empty()
+        || !cs.get_zones_outgoing().is_empty()
+    {
+        let mut cfg = tracker.conf().clone();
+        for w in cs.get_weights() {
+            cfg.voters.incoming.set_weight(w.id, w.weight);
+        }
+        for w in cs.get_weights_outgoing() {
+            cfg.voters.outgoing.set_weight(w.id, w.weight);
+        }
+        for z in cs.get_zones() {
+            cfg.voters.incoming.set_zone(z.id, z.zone);
+        }
+        for z in cs.get_zones_outgoing() {
+            cfg.voters.outgoing.set_zone(z.id, z.zone);
+        }
+        tracker.apply_conf(cfg, vec![], next_idx);
+    }
     Ok(())
 }
@@ -20,6 +20,7 @@ use super::{
     errors::{Error, Result},
     INVALID_ID,
 };
+use std::time::Duration;
 
 /// Config contains the parameters to start a raft.
 #[derive(Clone)]
@@ -98,6 +99,42 @@ pub struct Config {
 
     /// Max size for committed entries in a `Ready`.
     pub max_committed_size_per_ready: u64,
+
+    /// The number of ticks a leader must stay idle before it asks the group to
+    /// hibernate. A hibernating group stops ticking until it is woken up by a
+    /// proposal, a message or `RawNode::wake`.
+    /// If it is 0, hibernation is disabled.
+    pub hibernate_tick: usize,
+
+    /// The number of ticks a voter with a higher priority than the leader must
+    /// stay fully caught up before the leader transfers leadership to it. It keeps
+    /// leadership from moving on short-lived catch ups.
+    /// If it is 0, leadership is never rebalanced automatically.
+    pub priority_rebalance_tick: usize,
+
+    /// The voter weight needed to win an election when flexible quorums are used.
+    /// It must be more than half of the total weight, and together with
+    /// `commit_quorum` it must exceed the total weight, so that any election
+    /// quorum intersects any commit quorum. Configurations violating this are
+    /// refused by conf changes. If it is 0, majority quorums are used.
+    pub election_quorum: u64,
+
+    /// The voter weight needed to commit an entry when flexible quorums are used.
+    /// It must not be greater than `election_quorum`, the point of flexible quorums
+    /// is to make commits cheaper than elections. If it is 0, majority quorums are
+    /// used.
+    pub commit_quorum: u64,
+
+    /// The length of a leader lease measured with the clock set by
+    /// `RawNode::set_clock`. A `LeaseBased` leader only serves reads while a quorum
+    /// acknowledged one of its heartbeats within the lease, and a follower refuses
+    /// to vote for others within the lease after hearing from its leader.
+    /// If it is zero, the lease is counted in election ticks.
+    pub lease_duration: Duration,
+
+    /// The maximum drift between the clocks of two nodes over `lease_duration`.
+    /// The leader gives up its lease that much earlier than the followers.
+    pub max_clock_drift: Duration,
 }
 
 impl Default for Config {
@@ -120,6 +157,12 @@ impl Default for Config {
             priority: 0,
             max_uncommitted_size: NO_LIMIT,
             max_committed_size_per_ready: NO_LIMIT,
+            hibernate_tick: 0,
+            priority_rebalance_tick: 0,
+            election_quorum: 0,
+            commit_quorum: 0,
+            lease_duration: Duration::ZERO,
+            max_clock_drift: Duration::ZERO,
         }
     }
 }
@@ -205,6 +248,41 @@ impl Config {
             ));
         }
 
+        if self.hibernate_tick != 0 && self.hibernate_tick < self.election_tick {
+            return Err(Error::ConfigInvalid(format!(
+                "hibernate tick {} must not be less than election_tick {}",
+                self.hibernate_tick, self.election_tick
+            )));
+        }
+
+        if (self.election_quorum == 0) != (self.commit_quorum == 0) {
+            return Err(Error::ConfigInvalid(format!(
+                "election quorum {} and commit quorum {} must be both set or both 0",
+                self.election_quorum, self.commit_quorum
+            )));
+        }
+
+        if self.commit_quorum > self.election_quorum {
+            return Err(Error::ConfigInvalid(format!(
+                "commit quorum {} must not be greater than election quorum {}",
+                self.commit_quorum, self.election_quorum
+            )));
+        }
+
+        if self.lease_duration > Duration::ZERO {
+            if self.read_only_option != ReadOnlyOption::LeaseBased {
+                return Err(Error::ConfigInvalid(
+                    "lease_duration requires read_only_option == LeaseBased".to_owned(),
+                ));
+            }
+            if self.max_clock_drift >= self.lease_duration {
+                return Err(Error::ConfigInvalid(format!(
+                    "max clock drift {:?} must be less than lease duration {:?}",
+                    self.max_clock_drift, self.lease_duration
+                )));
+            }
+        }
+
         Ok(())
     }
 }
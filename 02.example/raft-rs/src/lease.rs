@@ -0,0 +1,168 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Leader leases measured in monotonic time.
+//!
+//! With `ReadOnlyOption::LeaseBased`, a leader answers reads locally as long as
+//! it holds a lease. Counting election ticks makes the real length of the lease
+//! depend on how regularly the application calls `tick`. When
+//! `Config::lease_duration` is set, the lease is measured with a `Clock` instead:
+//!
+//! - Every heartbeat round carries a sequence number in its context. When a
+//!   quorum has acknowledged rounds sent at or after time `t`, the leader holds
+//!   the lease until `t + lease_duration - max_clock_drift`.
+//! - A follower that heard from its leader less than `lease_duration` ago
+//!   refuses to vote for other candidates, so no new leader can be elected
+//!   before the old lease expires, as long as the clocks drift apart by no more
+//!   than `max_clock_drift` over a lease.
+
+use std::collections::VecDeque;
+use std::convert::TryInto;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::{HashMap, HashSet};
+
+/// A source of monotonic time for leader leases.
+pub trait Clock: Send + Sync {
+    /// Returns the current time. It must never go backwards.
+    fn now(&self) -> Instant;
+}
+
+/// A `Clock` backed by `Instant::now`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    #[inline]
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+pub(crate) struct Lease {
+    clock: Arc<dyn Clock>,
+    duration: Duration,
+    max_drift: Duration,
+
+    // The sequence number of the last heartbeat round.
+    seq: u64,
+    // Heartbeat rounds that may still extend the lease, oldest first.
+    rounds: VecDeque<(u64, Instant)>,
+    // The send time of the latest round acknowledged by each peer.
+    acks: HashMap<u64, Instant>,
+    // When the lease of the leader expires.
+    expire: Option<Instant>,
+    // When the follower last heard from its leader.
+    leader_contact: Option<Instant>,
+}
+
+impl Lease {
+    pub fn new(duration: Duration, max_drift: Duration) -> Lease {
+        Lease {
+            clock: Arc::new(MonotonicClock),
+            duration,
+            max_drift,
+            seq: 0,
+            rounds: VecDeque::new(),
+            acks: HashMap::default(),
+            expire: None,
+            leader_contact: None,
+        }
+    }
+
+    /// Whether the lease is measured with the clock.
+    #[inline]
+    pub fn enabled(&self) -> bool {
+        self.duration > Duration::ZERO
+    }
+
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    pub fn reset(&mut self) {
+        self.rounds.clear();
+        self.acks.clear();
+        self.expire = None;
+        self.leader_contact = None;
+    }
+
+    /// Starts a new heartbeat round, returns the context to send with it.
+    pub fn new_round(&mut self) -> Vec<u8> {
+        let now = self.clock.now();
+        // Rounds that can't extend the lease past now are useless.
+        while let Some((_, sent)) = self.rounds.front() {
+            if *sent + self.valid_for() > now {
+                break;
+            }
+            self.rounds.pop_front();
+        }
+        self.seq += 1;
+        self.rounds.push_back((self.seq, now));
+        self.seq.to_be_bytes().to_vec()
+    }
+
+    /// Records that `from` acknowledged the heartbeat round carried by `ctx`.
+    /// Returns false if the round is unknown or too old to matter.
+    pub fn ack(&mut self, from: u64, ctx: &[u8]) -> bool {
+        let seq = match ctx.try_into() {
+            Ok(bytes) => u64::from_be_bytes(bytes),
+            Err(_) => return false,
+        };
+        let sent = match self.rounds.iter().find(|(s, _)| *s == seq) {
+            Some((_, sent)) => *sent,
+            None => return false,
+        };
+        let ack = self.acks.entry(from).or_insert(sent);
+        if *ack < sent {
+            *ack = sent;
+        }
+        true
+    }
+
+    /// Extends the lease to the latest round acknowledged by a quorum, which is
+    /// checked by `has_quorum`. `self_id` always counts as up to date.
+    pub fn renew(&mut self, self_id: u64, has_quorum: impl Fn(&HashSet<u64>) -> bool) {
+        let mut times: Vec<Instant> = self.acks.values().cloned().collect();
+        times.sort_unstable_by(|a, b| b.cmp(a));
+        for t in times {
+            let mut ids: HashSet<u64> = self
+                .acks
+                .iter()
+                .filter(|(_, ack)| **ack >= t)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.insert(self_id);
+            if has_quorum(&ids) {
+                let expire = t + self.valid_for();
+                if self.expire.map_or(true, |e| e < expire) {
+                    self.expire = Some(expire);
+                }
+                return;
+            }
+        }
+    }
+
+    /// Whether the leader still holds the lease.
+    pub fn is_valid(&self) -> bool {
+        self.expire.map_or(false, |e| self.clock.now() < e)
+    }
+
+    /// Records that the follower heard from its leader.
+    pub fn contact(&mut self) {
+        if self.enabled() {
+            self.leader_contact = Some(self.clock.now());
+        }
+    }
+
+    /// Whether the follower heard from its leader within the lease duration.
+    pub fn recently_contacted(&self) -> bool {
+        self.leader_contact
+            .map_or(false, |c| self.clock.now() < c + self.duration)
+    }
+
+    // The length of a lease as seen by the leader.
+    fn valid_for(&self) -> Duration {
+        self.duration.saturating_sub(self.max_drift)
+    }
+}
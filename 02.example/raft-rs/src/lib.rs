@@ -486,6 +486,9 @@ before taking old, removed peers offline.
 // We use `default` method a lot to be support prost and rust-protobuf at the
 // same time. And reassignment can be optimized by compiler.
 #![allow(clippy::field_reassign_with_default)]
+// It recommends `is_none_or` and `is_some_and`, which need a newer toolchain
+// than the one the crate builds with.
+#![allow(clippy::unnecessary_map_or)]
 
 macro_rules! fatal {
     ($logger:expr, $msg:expr) => {{
@@ -505,7 +508,9 @@ macro_rules! fatal {
 mod confchange;
 mod config;
 mod errors;
+mod lease;
 mod log_unstable;
+pub mod multi_raft;
 mod quorum;
 #[cfg(test)]
 pub mod raft;
@@ -518,6 +523,7 @@ mod status;
 pub mod storage;
 mod tracker;
 pub mod util;
+pub mod wal_storage;
 
 pub use crate::raft::{
     vote_resp_msg_type, Raft, SoftState, StateRole, CAMPAIGN_ELECTION, CAMPAIGN_PRE_ELECTION,
@@ -526,14 +532,18 @@ pub use crate::raft::{
 pub use confchange::{Changer, MapChange};
 pub use config::Config;
 pub use errors::{Error, Result, StorageError};
+pub use lease::{Clock, MonotonicClock};
 pub use log_unstable::Unstable;
+pub use multi_raft::{GroupMessage, MultiLightReady, MultiRaft, MultiReady};
 pub use quorum::joint::Configuration as JointConfig;
 pub use quorum::majority::Configuration as MajorityConfig;
 pub use raft_log::{RaftLog, NO_LIMIT};
 pub use raft_proto::eraftpb;
 #[allow(deprecated)]
 pub use raw_node::is_empty_snap;
-pub use raw_node::{LightReady, Peer, RawNode, Ready, SnapshotStatus};
+pub use raw_node::{
+    LightReady, Peer, ProposalHandle, ProposalOutcome, RawNode, Ready, SnapshotStatus,
+};
 pub use read_only::{ReadOnlyOption, ReadState};
 pub use status::Status;
 pub use storage::{GetEntriesContext, RaftState, Storage};
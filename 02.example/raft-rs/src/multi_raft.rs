@@ -0,0 +1,418 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A host that drives many Raft groups inside one process.
+//!
+//! Every group is still a plain [`RawNode`], but the host takes care of the
+//! plumbing that is wasteful to repeat per group: messages are routed to their
+//! group by group (region) id, only active groups are ticked, the `Ready`s of
+//! all groups are collected into one batch so the application can persist them
+//! with a single write, and outgoing messages are merged per destination store.
+//!
+//! Groups that hibernate (see `Config::hibernate_tick`) are deactivated, so idle
+//! groups cost nothing until a proposal or a message arrives. When a store is
+//! considered down, [`MultiRaft::wake_store`] wakes the groups led from it.
+
+use std::collections::hash_map::Entry as MapEntry;
+use std::mem;
+
+use crate::eraftpb::Message;
+use crate::errors::{Error, Result};
+use crate::raw_node::{LightReady, ProposalHandle, RawNode, Ready};
+use crate::storage::Storage;
+use crate::{HashMap, HashSet, INVALID_ID};
+
+/// A raft message tagged with the group it belongs to.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GroupMessage {
+    /// The ID of the group (region) the message belongs to.
+    pub group_id: u64,
+    /// The raft message itself.
+    pub msg: Message,
+}
+
+impl GroupMessage {
+    /// Creates a new group message.
+    pub fn new(group_id: u64, msg: Message) -> GroupMessage {
+        GroupMessage { group_id, msg }
+    }
+}
+
+/// Outgoing messages merged per destination store.
+pub type StoreMessages = HashMap<u64, Vec<GroupMessage>>;
+
+/// MultiReady merges the `Ready`s of all groups that have outstanding work.
+///
+/// Entries, hard states and snapshots of every group should be persisted in
+/// one batch before calling [`MultiRaft::advance`]. The messages of the
+/// readies are taken out and merged per destination store.
+#[derive(Default, Debug)]
+pub struct MultiReady {
+    readies: Vec<(u64, Ready)>,
+    messages: StoreMessages,
+    persisted_messages: StoreMessages,
+}
+
+impl MultiReady {
+    /// Returns true if no group has a ready.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.readies.is_empty()
+    }
+
+    /// The readies of the groups, tagged with their group ID.
+    #[inline]
+    pub fn readies(&self) -> &[(u64, Ready)] {
+        &self.readies
+    }
+
+    /// The mutable readies of the groups, tagged with their group ID.
+    #[inline]
+    pub fn mut_readies(&mut self) -> &mut [(u64, Ready)] {
+        &mut self.readies
+    }
+
+    /// Returns true if any ready in the batch must be synced.
+    pub fn must_sync(&self) -> bool {
+        self.readies.iter().any(|(_, rd)| rd.must_sync())
+    }
+
+    /// Messages that can be sent immediately, merged per destination store.
+    #[inline]
+    pub fn messages(&self) -> &StoreMessages {
+        &self.messages
+    }
+
+    /// Take the messages that can be sent immediately.
+    #[inline]
+    pub fn take_messages(&mut self) -> StoreMessages {
+        mem::take(&mut self.messages)
+    }
+
+    /// Messages that must only be sent AFTER the whole batch is persisted,
+    /// merged per destination store.
+    #[inline]
+    pub fn persisted_messages(&self) -> &StoreMessages {
+        &self.persisted_messages
+    }
+
+    /// Take the messages that must only be sent after persistence.
+    #[inline]
+    pub fn take_persisted_messages(&mut self) -> StoreMessages {
+        mem::take(&mut self.persisted_messages)
+    }
+}
+
+/// MultiLightReady merges the `LightReady`s returned by advancing a
+/// [`MultiReady`].
+#[derive(Default, Debug)]
+pub struct MultiLightReady {
+    light_readies: Vec<(u64, LightReady)>,
+    messages: StoreMessages,
+}
+
+impl MultiLightReady {
+    /// The light readies of the groups, tagged with their group ID.
+    #[inline]
+    pub fn light_readies(&self) -> &[(u64, LightReady)] {
+        &self.light_readies
+    }
+
+    /// The mutable light readies of the groups, tagged with their group ID.
+    #[inline]
+    pub fn mut_light_readies(&mut self) -> &mut [(u64, LightReady)] {
+        &mut self.light_readies
+    }
+
+    /// Messages to be sent, merged per destination store.
+    #[inline]
+    pub fn messages(&self) -> &StoreMessages {
+        &self.messages
+    }
+
+    /// Take the messages.
+    #[inline]
+    pub fn take_messages(&mut self) -> StoreMessages {
+        mem::take(&mut self.messages)
+    }
+}
+
+/// MultiRaft hosts many Raft groups and drives them together.
+///
+/// Peers are mapped to the stores hosting them with [`MultiRaft::set_peer_store`].
+/// A peer that is not mapped is assumed to live on a store with the same ID as
+/// the peer, which fits deployments where every store hosts one peer per group.
+pub struct MultiRaft<T: Storage> {
+    groups: HashMap<u64, RawNode<T>>,
+    // Groups that are driven by `tick`.
+    active: HashSet<u64>,
+    // Groups that may have pending readiness.
+    dirty: HashSet<u64>,
+    // Maps peer ID to store ID.
+    stores: HashMap<u64, u64>,
+}
+
+impl<T: Storage> Default for MultiRaft<T> {
+    fn default() -> Self {
+        MultiRaft {
+            groups: HashMap::default(),
+            active: HashSet::default(),
+            dirty: HashSet::default(),
+            stores: HashMap::default(),
+        }
+    }
+}
+
+impl<T: Storage> MultiRaft<T> {
+    /// Creates an empty host.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a group to the host. The group starts active.
+    pub fn add_group(&mut self, group_id: u64, node: RawNode<T>) -> Result<()> {
+        match self.groups.entry(group_id) {
+            MapEntry::Occupied(_) => Err(Error::Exists {
+                id: group_id,
+                set: "groups",
+            }),
+            MapEntry::Vacant(e) => {
+                e.insert(node);
+                self.active.insert(group_id);
+                self.dirty.insert(group_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes a group from the host and returns it.
+    pub fn remove_group(&mut self, group_id: u64) -> Option<RawNode<T>> {
+        self.active.remove(&group_id);
+        self.dirty.remove(&group_id);
+        self.groups.remove(&group_id)
+    }
+
+    /// Grabs a reference to a group.
+    #[inline]
+    pub fn group(&self, group_id: u64) -> Option<&RawNode<T>> {
+        self.groups.get(&group_id)
+    }
+
+    /// Grabs a mutable reference to a group.
+    ///
+    /// The group is considered to have pending readiness afterwards.
+    pub fn group_mut(&mut self, group_id: u64) -> Option<&mut RawNode<T>> {
+        let node = self.groups.get_mut(&group_id)?;
+        self.dirty.insert(group_id);
+        Some(node)
+    }
+
+    /// Returns an iterator over the IDs of all hosted groups.
+    pub fn group_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.groups.keys().cloned()
+    }
+
+    /// The number of hosted groups.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.groups.len()
+    }
+
+    /// Returns true if no group is hosted.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// Records that `peer_id` lives on `store_id`.
+    pub fn set_peer_store(&mut self, peer_id: u64, store_id: u64) {
+        self.stores.insert(peer_id, store_id);
+    }
+
+    /// Returns the store hosting the given peer.
+    #[inline]
+    pub fn peer_store(&self, peer_id: u64) -> u64 {
+        self.stores.get(&peer_id).cloned().unwrap_or(peer_id)
+    }
+
+    /// Marks the group as active, so it is driven by `tick`.
+    pub fn activate(&mut self, group_id: u64) {
+        if self.groups.contains_key(&group_id) {
+            self.active.insert(group_id);
+        }
+    }
+
+    /// Marks the group as inactive, so it is skipped by `tick` until it
+    /// receives a message or a proposal.
+    pub fn deactivate(&mut self, group_id: u64) {
+        self.active.remove(&group_id);
+    }
+
+    /// Checks if the group is active.
+    #[inline]
+    pub fn is_active(&self, group_id: u64) -> bool {
+        self.active.contains(&group_id)
+    }
+
+    /// The number of active groups.
+    #[inline]
+    pub fn active_count(&self) -> usize {
+        self.active.len()
+    }
+
+    /// Routes the message to its group and steps it. The group becomes active.
+    pub fn step(&mut self, m: GroupMessage) -> Result<()> {
+        let node = self.wake_up(m.group_id)?;
+        node.step(m.msg)
+    }
+
+    /// Proposes data to be appended to the log of the given group. The group
+    /// becomes active.
+    pub fn propose(
+        &mut self,
+        group_id: u64,
+        context: Vec<u8>,
+        data: Vec<u8>,
+    ) -> Result<Option<ProposalHandle>> {
+        let node = self.wake_up(group_id)?;
+        node.propose(context, data)
+    }
+
+    /// Ticks all active groups. Groups that hibernated are deactivated.
+    ///
+    /// Returns true to indicate that there will probably be some readiness
+    /// which needs to be handled.
+    pub fn tick(&mut self) -> bool {
+        let mut has_ready = false;
+        let groups = &mut self.groups;
+        let dirty = &mut self.dirty;
+        self.active.retain(|id| {
+            let node = groups.get_mut(id).unwrap();
+            if node.tick() {
+                dirty.insert(*id);
+                has_ready = true;
+            }
+            !node.is_hibernated()
+        });
+        has_ready
+    }
+
+    /// Wakes the group up from hibernation and activates it.
+    pub fn wake(&mut self, group_id: u64) -> Result<()> {
+        let node = self.wake_up(group_id)?;
+        node.wake();
+        Ok(())
+    }
+
+    /// Wakes all hibernating groups whose leader lives on the given store.
+    ///
+    /// It should be called when the liveness check of the store fails, so the
+    /// followers can elect a new leader.
+    pub fn wake_store(&mut self, store_id: u64) {
+        let ids: Vec<u64> = self
+            .groups
+            .iter()
+            .filter(|(_, node)| {
+                let leader_id = node.raft.leader_id;
+                node.is_hibernated()
+                    && leader_id != INVALID_ID
+                    && leader_id != node.raft.id
+                    && self.peer_store(leader_id) == store_id
+            })
+            .map(|(id, _)| *id)
+            .collect();
+        for id in ids {
+            let _ = self.wake(id);
+        }
+    }
+
+    /// Checks if any group has pending readiness.
+    pub fn has_ready(&self) -> bool {
+        self.dirty
+            .iter()
+            .any(|id| self.groups.get(id).map_or(false, |n| n.has_ready()))
+    }
+
+    /// Collects the readies of all groups with outstanding work.
+    ///
+    /// The returned `MultiReady` *MUST* be handled and passed back via
+    /// [`MultiRaft::advance`] before stepping any of the included groups again.
+    pub fn ready(&mut self) -> MultiReady {
+        let mut mrd = MultiReady::default();
+        let mut ids: Vec<u64> = self.dirty.drain().collect();
+        ids.sort_unstable();
+        for id in ids {
+            let node = match self.groups.get_mut(&id) {
+                Some(node) if node.has_ready() => node,
+                _ => continue,
+            };
+            let mut rd = node.ready();
+            route(&self.stores, id, rd.take_messages(), &mut mrd.messages);
+            route(
+                &self.stores,
+                id,
+                rd.take_persisted_messages(),
+                &mut mrd.persisted_messages,
+            );
+            mrd.readies.push((id, rd));
+        }
+        mrd
+    }
+
+    /// Advances all readies in the batch after it is fully persisted.
+    ///
+    /// Returns the merged light readies, see [`RawNode::advance`] for details.
+    /// [`MultiRaft::advance_apply`] should be called for every group once its
+    /// committed entries are applied.
+    pub fn advance(&mut self, mrd: MultiReady) -> MultiLightReady {
+        let mut mlrd = MultiLightReady::default();
+        for (id, rd) in mrd.readies {
+            let node = match self.groups.get_mut(&id) {
+                Some(node) => node,
+                None => continue,
+            };
+            let mut light_rd = node.advance(rd);
+            route(
+                &self.stores,
+                id,
+                light_rd.take_messages(),
+                &mut mlrd.messages,
+            );
+            self.dirty.insert(id);
+            mlrd.light_readies.push((id, light_rd));
+        }
+        mlrd
+    }
+
+    /// Advances the apply index of the group to the last committed entries
+    /// given before.
+    pub fn advance_apply(&mut self, group_id: u64) {
+        if let Some(node) = self.groups.get_mut(&group_id) {
+            node.advance_apply();
+            self.dirty.insert(group_id);
+        }
+    }
+
+    fn wake_up(&mut self, group_id: u64) -> Result<&mut RawNode<T>> {
+        let node = match self.groups.get_mut(&group_id) {
+            Some(node) => node,
+            None => {
+                return Err(Error::NotExists {
+                    id: group_id,
+                    set: "groups",
+                })
+            }
+        };
+        self.active.insert(group_id);
+        self.dirty.insert(group_id);
+        Ok(node)
+    }
+}
+
+fn route(stores: &HashMap<u64, u64>, group_id: u64, msgs: Vec<Message>, to: &mut StoreMessages) {
+    for msg in msgs {
+        let store_id = stores.get(&msg.to).cloned().unwrap_or(msg.to);
+        to.entry(store_id)
+            .or_default()
+            .push(GroupMessage::new(group_id, msg));
+    }
+}
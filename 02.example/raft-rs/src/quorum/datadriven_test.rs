@@ -27,6 +27,20 @@ fn test_quorum(data: &TestData) -> String {
     // group id of each nodes in the config.
     let mut gids: Vec<u64> = Vec::new();
 
+    // Voting weight of each nodes in the config, in the same order as idxs. An
+    // underscore denotes the default weight 1. A node has the same weight in
+    // both majority configs.
+    let mut weights: Vec<u64> = Vec::new();
+
+    // Zone of each nodes in the config, in the same order as idxs. An
+    // underscore denotes no zone. A node is in the same zone in both majority
+    // configs.
+    let mut zones: Vec<u64> = Vec::new();
+
+    // Flexible quorum sizes as (election, commit), applied to both majority
+    // configs.
+    let mut flexible: Vec<u64> = Vec::new();
+
     // Votes. These are initialized similar to idxs except the only values
     // used are 1 (voted against) and 2 (voted for). This looks awkward,
     // but is convenient because it allows sharing code between the two.
@@ -72,6 +86,24 @@ fn test_quorum(data: &TestData) -> String {
                     }
                     gids.push(n);
                 }
+                "fq" => {
+                    let n: u64 = val.parse().expect("type of n should be u64");
+                    flexible.push(n);
+                }
+                "zone" => {
+                    let mut n: u64 = 0;
+                    if val != "_" {
+                        n = val.parse().expect("type of n should be u64");
+                    }
+                    zones.push(n);
+                }
+                "weight" => {
+                    let mut n: u64 = 1;
+                    if val != "_" {
+                        n = val.parse().expect("type of n should be u64");
+                    }
+                    weights.push(n);
+                }
                 "votes" => match val.as_str() {
                     "y" => votes.push(Index {
                         index: 2,
@@ -100,8 +132,8 @@ fn test_quorum(data: &TestData) -> String {
     let idsj_set: HashSet<u64> = idsj.iter().cloned().collect();
 
     // Build the two majority configs.
-    let c = MajorityConfig::new(ids_set);
-    let cj = MajorityConfig::new(idsj_set);
+    let mut c = MajorityConfig::new(ids_set);
+    let mut cj = MajorityConfig::new(idsj_set);
 
     let make_lookuper = |idxs: &[Index], ids: &[u64], idsj: &[u64]| -> AckIndexer {
         let mut l = AckIndexer::default();
@@ -126,7 +158,7 @@ fn test_quorum(data: &TestData) -> String {
     // verify length of voters
     let mut input = idxs.len();
 
-    if data.cmd.as_str() == "vote" {
+    if data.cmd.as_str() == "vote" || data.cmd.as_str() == "lease" {
         input = votes.len();
     }
 
@@ -157,6 +189,56 @@ fn test_quorum(data: &TestData) -> String {
         }
     }
 
+    // verify length of weights
+    if !weights.is_empty() {
+        if weights.len() != voters {
+            return format!(
+                "error: mismatched input (explicit or _) for weights {:?}: {:?}",
+                voters,
+                weights.len()
+            );
+        }
+        let mut seen = HashSet::default();
+        let ordered = ids.iter().chain(&idsj).filter(|id| seen.insert(**id));
+        for (id, weight) in ordered.zip(weights) {
+            if c.contains(id) {
+                c.set_weight(*id, weight);
+            }
+            if cj.contains(id) {
+                cj.set_weight(*id, weight);
+            }
+        }
+    }
+
+    // verify length of zones
+    if !zones.is_empty() {
+        if zones.len() != voters {
+            return format!(
+                "error: mismatched input (explicit or _) for zones {:?}: {:?}",
+                voters,
+                zones.len()
+            );
+        }
+        let mut seen = HashSet::default();
+        let ordered = ids.iter().chain(&idsj).filter(|id| seen.insert(**id));
+        for (id, zone) in ordered.zip(zones) {
+            if c.contains(id) {
+                c.set_zone(*id, zone);
+            }
+            if cj.contains(id) {
+                cj.set_zone(*id, zone);
+            }
+        }
+    }
+
+    if !flexible.is_empty() {
+        if flexible.len() != 2 {
+            return format!("error: fq takes (election, commit), got {:?}", flexible);
+        }
+        c.set_flexible_quorum(flexible[0], flexible[1]);
+        cj.set_flexible_quorum(flexible[0], flexible[1]);
+    }
+
     // buffer for expected value
     let mut buf = String::new();
 
@@ -285,24 +367,38 @@ fn test_quorum(data: &TestData) -> String {
             )
             .unwrap();
         }
-        "vote" => {
+        "vote" | "lease" => {
             let ll = make_lookuper(&votes, &ids, &idsj);
             let mut l = HashMap::default();
             for (id, v) in ll {
                 l.insert(id, v.index != 1);
             }
 
+            // "lease" tallies the votes with the quorum a leader needs to stay in
+            // charge instead of the one needed to win an election.
+            let lease = data.cmd.as_str() == "lease";
+            let joint_result = |cc: JointConfig| {
+                if lease {
+                    cc.lease_result(|id| l.get(&id).cloned())
+                } else {
+                    cc.vote_result(|id| l.get(&id).cloned())
+                }
+            };
+
             let r;
             if joint {
                 // Run a joint quorum test case.
-                r = JointConfig::new_joint_from_majorities(c.clone(), cj.clone())
-                    .vote_result(|id| l.get(&id).cloned());
+                r = joint_result(JointConfig::new_joint_from_majorities(
+                    c.clone(),
+                    cj.clone(),
+                ));
                 // Interchanging the majorities shouldn't make a difference. If it does, print.
-                let ar = JointConfig::new_joint_from_majorities(cj, c)
-                    .vote_result(|id| l.get(&id).cloned());
+                let ar = joint_result(JointConfig::new_joint_from_majorities(cj, c));
                 if ar != r {
                     writeln!(buf, "{} <-- via symmetry", ar).unwrap();
                 }
+            } else if lease {
+                r = c.lease_result(|id| l.get(&id).cloned());
             } else {
                 r = c.vote_result(|id| l.get(&id).cloned());
             }
@@ -66,6 +66,24 @@ impl Configuration {
         }
     }
 
+    /// Same as `vote_result`, but uses `MajorityConfig::lease_result` for both
+    /// majorities.
+    pub fn lease_result(&self, check: impl Fn(u64) -> Option<bool>) -> VoteResult {
+        let i = self.incoming.lease_result(&check);
+        let o = self.outgoing.lease_result(check);
+        match (i, o) {
+            (VoteResult::Won, VoteResult::Won) => VoteResult::Won,
+            (VoteResult::Lost, _) | (_, VoteResult::Lost) => VoteResult::Lost,
+            _ => VoteResult::Pending,
+        }
+    }
+
+    /// Sets the flexible quorum sizes of both majorities.
+    pub fn set_flexible_quorum(&mut self, election: u64, commit: u64) {
+        self.incoming.set_flexible_quorum(election, commit);
+        self.outgoing.set_flexible_quorum(election, commit);
+    }
+
     /// Clears all IDs.
     pub fn clear(&mut self) {
         self.incoming.clear();
@@ -93,6 +111,18 @@ impl Configuration {
     /// given lookuper.
     #[cfg(test)]
     pub(crate) fn describe(&self, l: &impl AckedIndexer) -> String {
-        MajorityConfig::new(self.ids().iter().collect()).describe(l)
+        let mut c = MajorityConfig::new(self.ids().iter().collect());
+        for (id, weight) in self
+            .incoming
+            .weights()
+            .iter()
+            .chain(self.outgoing.weights())
+        {
+            c.set_weight(*id, *weight);
+        }
+        for (id, zone) in self.incoming.zones().iter().chain(self.outgoing.zones()) {
+            c.set_zone(*id, *zone);
+        }
+        c.describe(l)
     }
 }
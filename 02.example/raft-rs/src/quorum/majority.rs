@@ -1,7 +1,7 @@
 // Copyright 2020 TiKV Project Authors. Licensed under Apache-2.0.
 
 use super::{AckedIndexer, Index, VoteResult};
-use crate::{DefaultHashBuilder, HashSet};
+use crate::{DefaultHashBuilder, HashMap, HashSet};
 
 use std::collections::hash_set::Iter;
 use std::fmt::Formatter;
@@ -10,9 +10,27 @@ use std::ops::{Deref, DerefMut};
 use std::{cmp, slice, u64};
 
 /// A set of IDs that uses majority quorums to make decisions.
+///
+/// Every voter carries a weight, 1 by default. A decision needs the support of
+/// voters whose weights sum up to more than half of the total weight.
+///
+/// With flexible quorums, elections need the election quorum and commits need
+/// the commit quorum instead, both measured in weight.
+///
+/// Once any voter is labeled with a zone, the configuration turns into a
+/// hierarchical quorum: a decision needs a majority of zones, and within each of
+/// these zones the support of voters holding a majority of the zone's weight.
+/// Voters without a label form zone 0. Flexible quorum sizes don't apply then.
 #[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct Configuration {
     voters: HashSet<u64>,
+    // Weights other than the default one, keyed by voter.
+    weights: HashMap<u64, u64>,
+    // Zones other than the default zone 0, keyed by voter.
+    zones: HashMap<u64, u64>,
+    // Flexible quorum sizes, 0 means a majority of the total weight.
+    election_quorum: u64,
+    commit_quorum: u64,
 }
 
 impl std::fmt::Display for Configuration {
@@ -22,7 +40,16 @@ impl std::fmt::Display for Configuration {
             "({})",
             self.voters
                 .iter()
-                .map(|x| x.to_string())
+                .map(|x| {
+                    let mut s = x.to_string();
+                    if let Some(w) = self.weights.get(x) {
+                        s.push_str(&format!(":{}", w));
+                    }
+                    if let Some(z) = self.zones.get(x) {
+                        s.push_str(&format!("@{}", z));
+                    }
+                    s
+                })
                 .collect::<Vec<String>>()
                 .join(" ")
         )
@@ -32,16 +59,140 @@ impl std::fmt::Display for Configuration {
 impl Configuration {
     /// Creates a new configuration using the given IDs.
     pub fn new(voters: HashSet<u64>) -> Configuration {
-        Configuration { voters }
+        Configuration {
+            voters,
+            weights: HashMap::default(),
+            zones: HashMap::default(),
+            election_quorum: 0,
+            commit_quorum: 0,
+        }
     }
 
     /// Creates an empty configuration with given capacity.
     pub fn with_capacity(cap: usize) -> Configuration {
         Configuration {
             voters: HashSet::with_capacity_and_hasher(cap, DefaultHashBuilder::default()),
+            weights: HashMap::default(),
+            zones: HashMap::default(),
+            election_quorum: 0,
+            commit_quorum: 0,
+        }
+    }
+
+    /// Returns the weight of the given voter. IDs that have never been given a
+    /// weight count as 1.
+    #[inline]
+    pub fn weight(&self, id: u64) -> u64 {
+        self.weights.get(&id).cloned().unwrap_or(1)
+    }
+
+    /// Sets the weight of the given voter, 0 resets it to the default weight 1.
+    pub fn set_weight(&mut self, id: u64, weight: u64) {
+        if weight == 0 || weight == 1 {
+            self.weights.remove(&id);
+        } else {
+            self.weights.insert(id, weight);
         }
     }
 
+    /// Returns the voters whose weights are not 1, along with their weights.
+    pub fn weights(&self) -> &HashMap<u64, u64> {
+        &self.weights
+    }
+
+    /// Returns the sum of the weights of all voters.
+    pub fn total_weight(&self) -> u64 {
+        self.voters.iter().map(|id| self.weight(*id)).sum()
+    }
+
+    /// Returns the zone of the given voter, 0 if it has never been labeled.
+    #[inline]
+    pub fn zone(&self, id: u64) -> u64 {
+        self.zones.get(&id).cloned().unwrap_or(0)
+    }
+
+    /// Labels the given voter with a zone, 0 removes the label.
+    pub fn set_zone(&mut self, id: u64, zone: u64) {
+        if zone == 0 {
+            self.zones.remove(&id);
+        } else {
+            self.zones.insert(id, zone);
+        }
+    }
+
+    /// Returns the voters labeled with a zone, along with their zones.
+    pub fn zones(&self) -> &HashMap<u64, u64> {
+        &self.zones
+    }
+
+    /// Returns true if decisions are made by a majority of zones.
+    #[inline]
+    pub fn is_hierarchical(&self) -> bool {
+        !self.zones.is_empty()
+    }
+
+    // Splits the voters into one majority config per zone, sorted by zone.
+    fn zone_configs(&self) -> Vec<Configuration> {
+        let mut zones: HashMap<u64, Configuration> = HashMap::default();
+        for id in &self.voters {
+            let c = zones.entry(self.zone(*id)).or_default();
+            c.voters.insert(*id);
+            c.set_weight(*id, self.weight(*id));
+        }
+        let mut zones: Vec<_> = zones.into_iter().collect();
+        zones.sort_by_key(|(zone, _)| *zone);
+        zones.into_iter().map(|(_, c)| c).collect()
+    }
+
+    /// Sets the flexible quorum sizes. Elections then need the support of voters
+    /// holding `election` weight, and entries are committed once acked by voters
+    /// holding `commit` weight. Sizes larger than the total weight are capped to
+    /// it, and 0 for either falls back to majority quorums.
+    pub fn set_flexible_quorum(&mut self, election: u64, commit: u64) {
+        self.election_quorum = election;
+        self.commit_quorum = commit;
+    }
+
+    /// Returns true if the flexible quorum sizes, if any, are safe for the
+    /// current voters. That is, an election quorum intersects any other election
+    /// quorum as well as any commit quorum.
+    pub fn is_flexible_quorum_safe(&self) -> bool {
+        if self.election_quorum == 0 || self.commit_quorum == 0 || self.voters.is_empty() {
+            return true;
+        }
+        let total = self.total_weight();
+        let election = cmp::min(self.election_quorum, total);
+        let commit = cmp::min(self.commit_quorum, total);
+        election > total / 2 && election + commit > total
+    }
+
+    // Returns the weights needed to win an election and to commit an entry.
+    fn quorums(&self) -> (u64, u64) {
+        let total = self.total_weight();
+        if self.election_quorum == 0 || self.commit_quorum == 0 || !self.is_flexible_quorum_safe() {
+            let majority = total / 2 + 1;
+            return (majority, majority);
+        }
+        (
+            cmp::min(self.election_quorum, total),
+            cmp::min(self.commit_quorum, total),
+        )
+    }
+
+    /// Removes the given voter along with its weight and zone.
+    pub fn remove(&mut self, id: &u64) -> bool {
+        self.weights.remove(id);
+        self.zones.remove(id);
+        self.voters.remove(id)
+    }
+
+    /// Clears all voters, weights and zones.
+    pub fn clear(&mut self) {
+        self.voters.clear();
+        self.weights.clear();
+        self.zones.clear();
+    }
+
     /// Returns an iterator over voters.
     pub fn ids(&self) -> Iter<'_, u64> {
         self.voters.iter()
@@ -74,6 +225,13 @@ impl Configuration {
             return (u64::MAX, true);
         }
 
+        if self.is_hierarchical() {
+            return (self.hierarchical_committed_index(l), false);
+        }
+        if !self.weights.is_empty() {
+            return self.weighted_committed_index(use_group_commit, l);
+        }
+
         let mut stack_arr: [MaybeUninit<Index>; 7] = unsafe { MaybeUninit::uninit().assume_init() };
         let mut heap_arr;
         let matched = if self.voters.len() <= 7 {
@@ -94,33 +252,51 @@ impl Configuration {
         // Reverse sort.
         matched.sort_by(|a, b| b.index.cmp(&a.index));
 
-        let quorum = crate::majority(matched.len());
-        let quorum_index = matched[quorum - 1];
-        if !use_group_commit {
-            return (quorum_index.index, false);
-        }
-        let (quorum_commit_index, mut checked_group_id) =
-            (quorum_index.index, quorum_index.group_id);
-        let mut single_group = true;
-        for m in matched.iter() {
-            if m.group_id == 0 {
-                single_group = false;
-                continue;
-            }
-            if checked_group_id == 0 {
-                checked_group_id = m.group_id;
-                continue;
-            }
-            if checked_group_id == m.group_id {
-                continue;
+        let quorum = self.quorums().1 as usize;
+        group_committed_index(use_group_commit, matched, matched[quorum - 1])
+    }
+
+    // Same as `committed_index`, but the quorum index is the highest index acked
+    // by voters holding the commit quorum weight.
+    fn weighted_committed_index(
+        &self,
+        use_group_commit: bool,
+        l: &impl AckedIndexer,
+    ) -> (u64, bool) {
+        let mut matched: Vec<(Index, u64)> = self
+            .voters
+            .iter()
+            .map(|v| (l.acked_index(*v).unwrap_or_default(), self.weight(*v)))
+            .collect();
+        // Reverse sort.
+        matched.sort_by_key(|m| cmp::Reverse(m.0.index));
+
+        let quorum = self.quorums().1;
+        let mut acked = 0;
+        let mut quorum_index = matched[matched.len() - 1].0;
+        for (index, weight) in &matched {
+            acked += weight;
+            if acked >= quorum {
+                quorum_index = *index;
+                break;
             }
-            return (cmp::min(m.index, quorum_commit_index), true);
-        }
-        if single_group {
-            (quorum_commit_index, false)
-        } else {
-            (matched.last().unwrap().index, false)
         }
+        let matched: Vec<Index> = matched.into_iter().map(|(index, _)| index).collect();
+        group_committed_index(use_group_commit, &matched, quorum_index)
+    }
+
+    // The committed index of a hierarchical quorum is the highest index
+    // committed in a majority of zones. Group commit doesn't apply, zones
+    // already make sure an index is replicated across groups.
+    fn hierarchical_committed_index(&self, l: &impl AckedIndexer) -> u64 {
+        let mut matched: Vec<u64> = self
+            .zone_configs()
+            .iter()
+            .map(|c| c.committed_index(false, l).0)
+            .collect();
+        // Reverse sort.
+        matched.sort_by_key(|index| cmp::Reverse(*index));
+        matched[crate::majority(matched.len()) - 1]
     }
 
     /// Takes a mapping of voters to yes/no (true/false) votes and returns
@@ -128,6 +304,50 @@ impl Configuration {
     /// yes/no has been reached), won (a quorum of yes has been reached), or lost (a
     /// quorum of no has been reached).
     pub fn vote_result(&self, check: impl Fn(u64) -> Option<bool>) -> VoteResult {
+        if self.is_hierarchical() {
+            return self.hierarchical_result(check);
+        }
+        self.tally(check, self.quorums().0)
+    }
+
+    // Tallies the results of all zones like votes of a majority config.
+    fn hierarchical_result(&self, check: impl Fn(u64) -> Option<bool>) -> VoteResult {
+        let zones = self.zone_configs();
+        let (mut won, mut pending) = (0, 0);
+        for c in &zones {
+            match c.tally(&check, c.quorums().0) {
+                VoteResult::Won => won += 1,
+                VoteResult::Pending => pending += 1,
+                VoteResult::Lost => (),
+            }
+        }
+        let q = crate::majority(zones.len());
+        if won >= q {
+            VoteResult::Won
+        } else if won + pending >= q {
+            VoteResult::Pending
+        } else {
+            VoteResult::Lost
+        }
+    }
+
+    /// Same as `vote_result`, but only asks for enough support to make sure no
+    /// other node can win an election without any of the supporters. This is
+    /// the quorum a leader needs to stay in charge and to serve reads.
+    ///
+    /// It's the same as `vote_result`, unless flexible quorums are used.
+    pub fn lease_result(&self, check: impl Fn(u64) -> Option<bool>) -> VoteResult {
+        if self.is_hierarchical() {
+            return self.hierarchical_result(check);
+        }
+        let (election, _) = self.quorums();
+        if self.election_quorum == 0 || self.commit_quorum == 0 {
+            return self.tally(check, election);
+        }
+        self.tally(check, self.total_weight() + 1 - election)
+    }
+
+    fn tally(&self, check: impl Fn(u64) -> Option<bool>, quorum: u64) -> VoteResult {
         if self.voters.is_empty() {
             // By convention, the elections on an empty config win. This comes in
             // handy with joint quorums because it'll make a half-populated joint
@@ -138,15 +358,14 @@ impl Configuration {
         let (mut yes, mut missing) = (0, 0);
         for v in &self.voters {
             match check(*v) {
-                Some(true) => yes += 1,
-                None => missing += 1,
+                Some(true) => yes += self.weight(*v),
+                None => missing += self.weight(*v),
                 _ => (),
             }
         }
-        let q = crate::majority(self.voters.len());
-        if yes >= q {
+        if yes >= quorum {
             VoteResult::Won
-        } else if yes + missing >= q {
+        } else if yes + missing >= quorum {
             VoteResult::Pending
         } else {
             VoteResult::Lost
@@ -218,17 +437,24 @@ impl Configuration {
                     buf.push_str("x".repeat(tup.bar).as_str());
                     buf.push('>');
                     buf.push_str(" ".repeat(n - tup.bar).as_str());
-                    writeln!(buf, " {:>5}    (id={})", format!("{}", idx), tup.id)
-                        .expect("Error occurred while trying to write in String");
+                    writeln!(
+                        buf,
+                        " {:>5}    (id={}{})",
+                        format!("{}", idx),
+                        tup.id,
+                        self.describe_weight(tup.id)
+                    )
+                    .expect("Error occurred while trying to write in String");
                 }
                 None => {
                     buf.push('?');
                     buf.push_str(" ".repeat(n).as_str());
                     writeln!(
                         buf,
-                        " {:>5}    (id={})",
+                        " {:>5}    (id={}{})",
                         format!("{}", Index::default()),
-                        tup.id
+                        tup.id,
+                        self.describe_weight(tup.id)
                     )
                     .expect("Error occurred while trying to write in String");
                 }
@@ -236,6 +462,51 @@ impl Configuration {
         }
         buf
     }
+
+    #[cfg(test)]
+    fn describe_weight(&self, id: u64) -> String {
+        let mut s = String::new();
+        if let Some(w) = self.weights.get(&id) {
+            s.push_str(&format!(" w={}", w));
+        }
+        if let Some(z) = self.zones.get(&id) {
+            s.push_str(&format!(" z={}", z));
+        }
+        s
+    }
+}
+
+// Applies the group commit algorithm on top of the quorum index, `matched` must be
+// sorted by index in descending order.
+fn group_committed_index(
+    use_group_commit: bool,
+    matched: &[Index],
+    quorum_index: Index,
+) -> (u64, bool) {
+    if !use_group_commit {
+        return (quorum_index.index, false);
+    }
+    let (quorum_commit_index, mut checked_group_id) = (quorum_index.index, quorum_index.group_id);
+    let mut single_group = true;
+    for m in matched.iter() {
+        if m.group_id == 0 {
+            single_group = false;
+            continue;
+        }
+        if checked_group_id == 0 {
+            checked_group_id = m.group_id;
+            continue;
+        }
+        if checked_group_id == m.group_id {
+            continue;
+        }
+        return (cmp::min(m.index, quorum_commit_index), true);
+    }
+    if single_group {
+        (quorum_commit_index, false)
+    } else {
+        (matched.last().unwrap().index, false)
+    }
 }
 
 impl Deref for Configuration {
@@ -14,9 +14,13 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+//! The Raft state machine of a single peer. It's only public in tests, to be
+//! driven directly.
+
 use std::cmp;
 use std::convert::TryFrom;
 use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
 
 use crate::eraftpb::{
     ConfChange, ConfChangeV2, ConfState, Entry, EntryType, HardState, Message, MessageType,
@@ -33,6 +37,7 @@ use getset::Getters;
 use slog::{debug, error, info, o, trace, warn};
 
 use super::errors::{Error, Result, StorageError};
+use super::lease::{Clock, Lease};
 use super::raft_log::RaftLog;
 use super::read_only::{ReadOnly, ReadOnlyOption, ReadState};
 use super::storage::{GetEntriesContext, GetEntriesFor, Storage};
@@ -41,6 +46,7 @@ use crate::confchange::Changer;
 use crate::quorum::VoteResult;
 use crate::util;
 use crate::util::NO_LIMIT;
+use crate::HashSet;
 use crate::{confchange, Progress, ProgressState, ProgressTracker};
 
 // CAMPAIGN_PRE_ELECTION represents the first phase of a normal election when
@@ -261,6 +267,39 @@ pub struct RaftCore<T: Storage> {
 
     /// Max size per committed entries in a `Read`.
     pub(crate) max_committed_size_per_ready: u64,
+
+    /// Whether the node is hibernating. A hibernating node ignores ticks until
+    /// it is woken up by a message or `wake`.
+    pub hibernated: bool,
+
+    hibernate_tick: usize,
+
+    /// Number of ticks the leader has stayed idle.
+    idle_elapsed: usize,
+
+    /// Peers that agreed to hibernate at the current last index.
+    hibernate_acks: HashSet<u64>,
+
+    priority_rebalance_tick: usize,
+
+    /// The voter leadership is going to be rebalanced to, and for how many ticks
+    /// it has been the best choice.
+    rebalance_target: Option<(u64, usize)>,
+
+    /// Set when the node wakes up from hibernation. The lease is not trusted
+    /// until a follower hears from its leader again, or a leader confirms
+    /// that the quorum is still active.
+    lease_suspended: bool,
+
+    /// The leader lease measured with a clock, see `Config::lease_duration`.
+    lease: Lease,
+
+    /// The highest commit index heard from a leader.
+    leader_commit: u64,
+
+    /// Number of ticks since the node last heard from a leader, `None` if it
+    /// never did.
+    leader_contact_elapsed: Option<usize>,
 }
 
 /// A struct that represents the raft consensus itself. Stores details concerning the current
@@ -312,6 +351,17 @@ fn get_priority(m: &Message) -> i64 {
     }
 }
 
+// Witnesses only keep the metadata of normal entries. Configuration changes
+// are kept so they can still track the membership.
+fn strip_entries_data(ents: &mut [Entry]) {
+    for e in ents {
+        if e.get_entry_type() == EntryType::EntryNormal {
+            e.clear_data();
+            e.clear_context();
+        }
+    }
+}
+
 /// Maps vote and pre_vote message types to their correspond responses.
 pub fn vote_resp_msg_type(t: MessageType) -> MessageType {
     match t {
@@ -369,8 +419,20 @@ impl<T: Storage> Raft<T> {
                     last_log_tail_index: 0,
                 },
                 max_committed_size_per_ready: c.max_committed_size_per_ready,
+                hibernated: false,
+                hibernate_tick: c.hibernate_tick,
+                idle_elapsed: 0,
+                hibernate_acks: HashSet::default(),
+                priority_rebalance_tick: c.priority_rebalance_tick,
+                rebalance_target: None,
+                lease_suspended: false,
+                lease: Lease::new(c.lease_duration, c.max_clock_drift),
+                leader_commit: 0,
+                leader_contact_elapsed: None,
             },
         };
+        r.prs
+            .set_flexible_quorum(c.election_quorum, c.commit_quorum);
         confchange::restore(&mut r.prs, r.r.raft_log.last_index(), conf_state)?;
         let new_cs = r.post_conf_change();
         if !raft_proto::conf_state_eq(&new_cs, conf_state) {
@@ -465,8 +527,65 @@ impl<T: Storage> Raft<T> {
     }
 
     /// Returns whether the current raft is in lease.
+    ///
+    /// If `Config::lease_duration` is set, the lease must also not have expired
+    /// according to the clock.
     pub fn in_lease(&self) -> bool {
-        self.state == StateRole::Leader && self.check_quorum
+        if self.state != StateRole::Leader || !self.check_quorum || self.lease_suspended {
+            return false;
+        }
+        if !self.lease.enabled() {
+            return true;
+        }
+        self.lease.is_valid() || self.prs.has_quorum(&[self.id].iter().cloned().collect())
+    }
+
+    /// Returns the highest commit index known from a leader. A leader returns its
+    /// own commit index.
+    pub fn leader_commit(&self) -> u64 {
+        if self.state == StateRole::Leader {
+            return self.raft_log.committed;
+        }
+        self.leader_commit
+    }
+
+    /// Returns the number of ticks since the node last heard from a leader, `None`
+    /// if it never did. A leader returns 0.
+    pub fn leader_contact_elapsed(&self) -> Option<usize> {
+        if self.state == StateRole::Leader {
+            return Some(0);
+        }
+        self.leader_contact_elapsed
+    }
+
+    /// Checks whether data up to `index` has been applied, so a follower or
+    /// learner can serve a read that only needs to observe `index` without
+    /// asking the leader.
+    pub fn can_read_applied(&self, index: u64) -> bool {
+        self.raft_log.applied >= index
+    }
+
+    /// Checks whether the applied data is no older than `max_ticks`, that is, it
+    /// includes everything the leader had committed when the node heard from it
+    /// within the last `max_ticks` ticks. Reads served this way may be stale
+    /// but never go back in time.
+    pub fn can_read_stale(&self, max_ticks: usize) -> bool {
+        match self.leader_contact_elapsed() {
+            Some(elapsed) => elapsed <= max_ticks && self.raft_log.applied >= self.leader_commit(),
+            None => false,
+        }
+    }
+
+    // Records that the leader was heard from with the given commit index.
+    fn leader_contacted(&mut self, commit: u64) {
+        self.leader_commit = cmp::max(self.leader_commit, commit);
+        self.leader_contact_elapsed = Some(0);
+    }
+
+    /// Sets the clock used to measure the leader lease. `MonotonicClock` is used
+    /// by default.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.lease.set_clock(clock);
     }
 
     /// For testing leader lease
@@ -697,10 +816,14 @@ impl<T: Storage> RaftCore<T> {
             }
             fatal!(self.logger, "unexpected error: {:?}", e);
         }
-        let snapshot = snapshot_r.unwrap();
+        let mut snapshot = snapshot_r.unwrap();
         if snapshot.get_metadata().index == 0 {
             fatal!(self.logger, "need non-empty snapshot");
         }
+        if pr.is_witness {
+            // Witness only needs the metadata.
+            snapshot.clear_data();
+        }
         let (sindex, sterm) = (snapshot.get_metadata().index, snapshot.get_metadata().term);
         m.set_snapshot(snapshot);
         debug!(
@@ -827,6 +950,9 @@ impl<T: Storage> RaftCore<T> {
             let term = self.raft_log.term(pr.next_idx - 1);
             match (term, ents) {
                 (Ok(term), Ok(mut ents)) => {
+                    if pr.is_witness {
+                        strip_entries_data(&mut ents);
+                    }
                     if self.batch_append && self.try_batching(to, msgs, pr, &mut ents) {
                         return true;
                     }
@@ -867,6 +993,7 @@ impl<T: Storage> RaftCore<T> {
         m.set_msg_type(MessageType::MsgHeartbeat);
         let commit = cmp::min(pr.matched, self.raft_log.committed);
         m.commit = commit;
+        m.leader_commit = self.raft_log.committed;
         if let Some(context) = ctx {
             m.context = context.into();
         }
@@ -917,7 +1044,10 @@ impl<T: Storage> Raft<T> {
 
     /// Sends RPC, without entries to all the peers.
     pub fn bcast_heartbeat(&mut self) {
-        let ctx = self.read_only.last_pending_request_ctx();
+        let mut ctx = self.read_only.last_pending_request_ctx();
+        if ctx.is_none() && self.lease.enabled() && self.state == StateRole::Leader {
+            ctx = Some(self.r.lease.new_round());
+        }
         self.bcast_heartbeat_with_ctx(ctx)
     }
 
@@ -993,6 +1123,13 @@ impl<T: Storage> Raft<T> {
         self.election_elapsed = 0;
         self.heartbeat_elapsed = 0;
 
+        self.hibernated = false;
+        self.idle_elapsed = 0;
+        self.hibernate_acks.clear();
+        self.lease_suspended = false;
+        self.lease.reset();
+        self.rebalance_target = None;
+
         self.abort_leader_transfer();
 
         self.prs.reset_votes();
@@ -1066,6 +1203,14 @@ impl<T: Storage> Raft<T> {
 
     /// Returns true to indicate that there will probably be some readiness need to be handled.
     pub fn tick(&mut self) -> bool {
+        // A hibernating node doesn't hear from the leader either, its data gets
+        // older all the same.
+        if let Some(elapsed) = self.leader_contact_elapsed.as_mut() {
+            *elapsed += 1;
+        }
+        if self.hibernated {
+            return false;
+        }
         match self.state {
             StateRole::Follower | StateRole::PreCandidate | StateRole::Candidate => {
                 self.tick_election()
@@ -1113,15 +1258,226 @@ impl<T: Storage> Raft<T> {
             return has_ready;
         }
 
+        if self.hibernate_tick > 0 {
+            if self.is_idle() {
+                self.idle_elapsed += 1;
+            } else {
+                self.idle_elapsed = 0;
+                self.hibernate_acks.clear();
+            }
+        }
+
+        if self.priority_rebalance_tick > 0 && self.maybe_rebalance() {
+            has_ready = true;
+        }
+
         if self.heartbeat_elapsed >= self.heartbeat_timeout {
             self.heartbeat_elapsed = 0;
             has_ready = true;
-            let m = new_message(INVALID_ID, MessageType::MsgBeat, Some(self.id));
-            let _ = self.step(m);
+            if self.hibernate_tick > 0 && self.idle_elapsed >= self.hibernate_tick {
+                // Asking to hibernate also works as a heartbeat.
+                self.bcast_hibernate();
+            } else {
+                let m = new_message(INVALID_ID, MessageType::MsgBeat, Some(self.id));
+                let _ = self.step(m);
+            }
         }
         has_ready
     }
 
+    // Transfers leadership to the voter with the highest priority once it has
+    // stayed caught up for `priority_rebalance_tick` ticks. Returns true if the
+    // transfer is started.
+    fn maybe_rebalance(&mut self) -> bool {
+        if self.lead_transferee.is_some() {
+            self.rebalance_target = None;
+            return false;
+        }
+        let (self_id, last_index) = (self.id, self.raft_log.last_index());
+        let conf = self.prs.conf();
+        let best = self
+            .prs
+            .iter()
+            .filter(|(id, pr)| {
+                **id != self_id
+                    && pr.priority > self.priority
+                    && pr.matched == last_index
+                    && pr.recent_active
+                    && conf.voters.contains(**id)
+                    && !conf.witnesses.contains(*id)
+            })
+            .max_by_key(|(id, pr)| (pr.priority, cmp::Reverse(**id)))
+            .map(|(id, _)| *id);
+        let target = match best {
+            Some(id) => id,
+            None => {
+                self.rebalance_target = None;
+                return false;
+            }
+        };
+        let elapsed = match self.rebalance_target {
+            Some((id, elapsed)) if id == target => elapsed + 1,
+            _ => 1,
+        };
+        if elapsed < self.priority_rebalance_tick {
+            self.rebalance_target = Some((target, elapsed));
+            return false;
+        }
+        self.rebalance_target = None;
+        info!(
+            self.logger,
+            "rebalancing leadership to a voter with higher priority";
+            "to" => target,
+        );
+        let m = new_message(INVALID_ID, MessageType::MsgTransferLeader, Some(target));
+        let _ = self.step(m);
+        true
+    }
+
+    /// Wakes the node up from hibernation.
+    ///
+    /// Hibernating groups don't exchange heartbeats, so nobody notices a dead
+    /// leader by itself. The application should call this when its own liveness
+    /// check of the leader fails. A woken follower pokes its leader, and a woken
+    /// leader broadcasts heartbeats, so the group resumes ticking.
+    pub fn wake(&mut self) {
+        if !self.hibernated {
+            return;
+        }
+        info!(
+            self.logger,
+            "wake up from hibernation";
+            "term" => self.term,
+            "state" => ?self.state,
+        );
+        self.hibernated = false;
+        self.idle_elapsed = 0;
+        self.hibernate_acks.clear();
+        self.election_elapsed = 0;
+        self.heartbeat_elapsed = 0;
+        self.lease_suspended = true;
+        if self.state == StateRole::Leader {
+            let self_id = self.id;
+            for (&id, pr) in self.mut_prs().iter_mut() {
+                pr.recent_active = id == self_id;
+            }
+            self.bcast_heartbeat();
+        } else if self.leader_id != INVALID_ID {
+            // Rejecting hibernation wakes the leader up.
+            let mut to_send = new_message(self.leader_id, MessageType::MsgHibernateResponse, None);
+            to_send.index = self.raft_log.last_index();
+            to_send.reject = true;
+            self.r.send(to_send, &mut self.msgs);
+        }
+    }
+
+    fn hibernate(&mut self) {
+        info!(
+            self.logger,
+            "hibernate";
+            "term" => self.term,
+            "state" => ?self.state,
+            "last index" => self.raft_log.last_index(),
+        );
+        self.hibernated = true;
+        self.idle_elapsed = 0;
+        self.hibernate_acks.clear();
+    }
+
+    // A leader is idle if there is nothing to replicate, commit or read, so
+    // the group can safely stop ticking.
+    fn is_idle(&self) -> bool {
+        let last_index = self.raft_log.last_index();
+        self.lead_transferee.is_none()
+            && !self.has_pending_conf()
+            && self.read_only.pending_read_count() == 0
+            && self.raft_log.committed == last_index
+            && self.prs().iter().all(|(_, pr)| {
+                pr.matched == last_index && pr.pending_request_snapshot == INVALID_INDEX
+            })
+    }
+
+    fn bcast_hibernate(&mut self) {
+        let self_id = self.id;
+        if self.prs().progress().len() == 1 {
+            self.hibernate();
+            return;
+        }
+        let last_index = self.raft_log.last_index();
+        let last_term = self.raft_log.last_term();
+        let committed = self.raft_log.committed;
+        let ids: Vec<u64> = self.prs().progress().keys().cloned().collect();
+        for id in ids {
+            if id == self_id {
+                continue;
+            }
+            let mut m = new_message(id, MessageType::MsgHibernate, None);
+            m.index = last_index;
+            m.log_term = last_term;
+            m.commit = committed;
+            self.r.send(m, &mut self.msgs);
+        }
+    }
+
+    fn handle_hibernate(&mut self, m: &Message) {
+        let mut to_send = new_message(m.from, MessageType::MsgHibernateResponse, None);
+        to_send.index = self.raft_log.last_index();
+        if self.hibernate_tick > 0
+            && self.pending_request_snapshot == INVALID_INDEX
+            && self.raft_log.last_index() == m.index
+            && self.raft_log.persisted == m.index
+            && self.raft_log.match_term(m.index, m.log_term)
+        {
+            self.raft_log.commit_to(m.commit);
+            self.hibernate();
+        } else {
+            to_send.reject = true;
+        }
+        self.r.send(to_send, &mut self.msgs);
+    }
+
+    fn handle_hibernate_response(&mut self, m: &Message) {
+        match self.prs.get_mut(m.from) {
+            Some(pr) => pr.recent_active = true,
+            None => return,
+        }
+        if m.reject {
+            self.idle_elapsed = 0;
+            self.hibernate_acks.clear();
+            return;
+        }
+        if self.hibernate_tick == 0
+            || self.idle_elapsed < self.hibernate_tick
+            || m.index != self.raft_log.last_index()
+            || !self.is_idle()
+        {
+            return;
+        }
+        self.hibernate_acks.insert(m.from);
+        let self_id = self.id;
+        if self
+            .prs()
+            .progress()
+            .keys()
+            .all(|id| *id == self_id || self.hibernate_acks.contains(id))
+        {
+            self.hibernate();
+        }
+    }
+
+    // Resumes the lease suspended by hibernation once a quorum is known active.
+    fn maybe_resume_lease(&mut self) {
+        let active: HashSet<u64> = self
+            .prs()
+            .iter()
+            .filter(|(_, pr)| pr.recent_active)
+            .map(|(id, _)| *id)
+            .collect();
+        if self.prs().has_quorum(&active) {
+            self.lease_suspended = false;
+        }
+    }
+
     /// Converts this node to a follower.
     pub fn become_follower(&mut self, term: u64, leader_id: u64) {
         let pending_request_snapshot = self.pending_request_snapshot;
@@ -1322,6 +1678,14 @@ impl<T: Storage> Raft<T> {
     /// Steps the raft along via a message. This should be called everytime your raft receives a
     /// message from a peer.
     pub fn step(&mut self, m: Message) -> Result<()> {
+        // Any message except the hibernation handshake wakes the node up.
+        if self.hibernated
+            && m.get_msg_type() != MessageType::MsgHibernate
+            && (m.get_msg_type() != MessageType::MsgHibernateResponse || m.reject)
+        {
+            self.wake();
+        }
+
         // Handle the message term, which may result in our stepping down to a follower.
         if m.term == 0 {
             // local message
@@ -1332,7 +1696,9 @@ impl<T: Storage> Raft<T> {
                 let force = m.context == CAMPAIGN_TRANSFER;
                 let in_lease = self.check_quorum
                     && self.leader_id != INVALID_ID
-                    && self.election_elapsed < self.election_timeout;
+                    && (self.election_elapsed < self.election_timeout
+                        || self.lease.recently_contacted())
+                    && !self.lease_suspended;
                 if !force && in_lease {
                     // if a server receives RequestVote request within the minimum election
                     // timeout of hearing from a current leader, it does not update its term
@@ -1384,6 +1750,7 @@ impl<T: Storage> Raft<T> {
                 if m.get_msg_type() == MessageType::MsgAppend
                     || m.get_msg_type() == MessageType::MsgHeartbeat
                     || m.get_msg_type() == MessageType::MsgSnapshot
+                    || m.get_msg_type() == MessageType::MsgHibernate
                 {
                     self.become_follower(m.term, m.from);
                 } else {
@@ -1521,6 +1888,14 @@ impl<T: Storage> Raft<T> {
             );
             return;
         }
+        if self.prs.conf().witnesses.contains(&self.id) {
+            warn!(
+                self.logger,
+                "witness can not campaign";
+                "term" => self.term,
+            );
+            return;
+        }
 
         // If there is a pending snapshot, its index will be returned by
         // `maybe_first_index`. Note that snapshot updates configuration
@@ -1837,6 +2212,7 @@ impl<T: Storage> Raft<T> {
         };
         // update followers committed index via heartbeat response
         pr.update_committed(m.commit);
+        pr.priority = m.priority;
         pr.recent_active = true;
         pr.resume();
 
@@ -1850,6 +2226,16 @@ impl<T: Storage> Raft<T> {
             self.r.send_append(m.from, pr, &mut self.msgs);
         }
 
+        if self.lease_suspended {
+            self.maybe_resume_lease();
+        }
+
+        if self.lease.enabled() && self.r.lease.ack(m.from, &m.context) {
+            let (self_id, prs) = (self.r.id, &self.prs);
+            self.r.lease.renew(self_id, |ids| prs.has_quorum(ids));
+            return;
+        }
+
         if self.read_only.option != ReadOnlyOption::Safe || m.context.is_empty() {
             return;
         }
@@ -1877,7 +2263,7 @@ impl<T: Storage> Raft<T> {
         }
 
         let from = m.from;
-        if self.prs.conf().learners.contains(&from) {
+        if self.prs.conf().learners.contains(&from) || self.prs.conf().witnesses.contains(&from) {
             debug!(
                 self.logger,
                 "ignored transferring leadership";
@@ -2016,6 +2402,8 @@ impl<T: Storage> Raft<T> {
                     );
                     let term = self.term;
                     self.become_follower(term, INVALID_ID);
+                } else {
+                    self.lease_suspended = false;
                 }
                 return Ok(());
             }
@@ -2129,6 +2517,20 @@ impl<T: Storage> Raft<T> {
                         self.bcast_heartbeat_with_ctx(Some(ctx));
                     }
                     ReadOnlyOption::LeaseBased => {
+                        if self.lease_suspended {
+                            debug!(
+                                self.logger,
+                                "dropping lease read since the lease is suspended after hibernation";
+                            );
+                            return Ok(());
+                        }
+                        if self.lease.enabled() && !self.in_lease() {
+                            debug!(
+                                self.logger,
+                                "dropping lease read since the lease has expired";
+                            );
+                            return Ok(());
+                        }
                         let read_index = self.raft_log.committed;
                         if let Some(m) = self.handle_ready_read_index(m, read_index) {
                             self.r.send(m, &mut self.msgs);
@@ -2147,6 +2549,9 @@ impl<T: Storage> Raft<T> {
             MessageType::MsgHeartbeatResponse => {
                 self.handle_heartbeat_response(&m);
             }
+            MessageType::MsgHibernateResponse => {
+                self.handle_hibernate_response(&m);
+            }
             MessageType::MsgSnapStatus => {
                 self.handle_snapshot_status(&m);
             }
@@ -2324,18 +2729,35 @@ impl<T: Storage> Raft<T> {
             MessageType::MsgAppend => {
                 self.election_elapsed = 0;
                 self.leader_id = m.from;
+                self.lease_suspended = false;
+                self.lease.contact();
+                self.leader_contacted(m.commit);
                 self.handle_append_entries(&m);
             }
             MessageType::MsgHeartbeat => {
                 self.election_elapsed = 0;
                 self.leader_id = m.from;
+                self.lease_suspended = false;
+                self.lease.contact();
+                self.leader_contacted(cmp::max(m.commit, m.leader_commit));
                 self.handle_heartbeat(m);
             }
             MessageType::MsgSnapshot => {
                 self.election_elapsed = 0;
                 self.leader_id = m.from;
+                self.lease_suspended = false;
+                self.lease.contact();
+                self.leader_contacted(m.get_snapshot().get_metadata().index);
                 self.handle_snapshot(m);
             }
+            MessageType::MsgHibernate => {
+                self.election_elapsed = 0;
+                self.leader_id = m.from;
+                self.lease_suspended = false;
+                self.lease.contact();
+                self.leader_contacted(m.commit);
+                self.handle_hibernate(&m);
+            }
             MessageType::MsgTransferLeader => {
                 if self.leader_id == INVALID_ID {
                     info!(
@@ -2523,6 +2945,7 @@ impl<T: Storage> Raft<T> {
         to_send.to = m.from;
         to_send.context = m.take_context();
         to_send.commit = self.raft_log.committed;
+        to_send.priority = self.priority;
         self.r.send(to_send, &mut self.msgs);
     }
 
@@ -2670,7 +3093,8 @@ impl<T: Storage> Raft<T> {
         // progress tracker is better.
         let cs = self.prs.conf().to_conf_state();
         let is_voter = self.prs.conf().voters.contains(self.id);
-        self.promotable = is_voter;
+        // A witness keeps no entry data, so it must never become leader.
+        self.promotable = is_voter && !self.prs.conf().witnesses.contains(&self.id);
         if !is_voter && self.state == StateRole::Leader {
             // This node is leader and was removed or demoted. We prevent demotions
             // at the time writing but hypothetically we handle them the same way as
@@ -2754,6 +3178,12 @@ impl<T: Storage> Raft<T> {
         self.promotable
     }
 
+    /// Checks if the node is a witness.
+    #[inline]
+    pub fn is_witness(&self) -> bool {
+        self.prs.conf().witnesses.contains(&self.id)
+    }
+
     #[doc(hidden)]
     pub fn apply_conf_change(&mut self, cc: &ConfChangeV2) -> Result<ConfState> {
         let mut changer = Changer::new(&self.prs);
@@ -20,7 +20,7 @@
 //! nodes but not the raft consensus itself. Generally, you'll interact with the
 //! RawNode first and use it to access the inner workings of the consensus protocol.
 
-use std::{collections::VecDeque, mem};
+use std::{collections::VecDeque, mem, sync::Arc};
 
 use protobuf::Message as PbMessage;
 use raft_proto::ConfChangeI;
@@ -29,7 +29,7 @@ use slog::Logger;
 use crate::eraftpb::{ConfState, Entry, EntryType, HardState, Message, MessageType, Snapshot};
 use crate::errors::{Error, Result};
 use crate::read_only::ReadState;
-use crate::{config::Config, StateRole};
+use crate::{config::Config, Clock, StateRole};
 use crate::{storage::GetEntriesFor, GetEntriesContext, Raft, SoftState, Status, Storage};
 
 use slog::info;
@@ -73,6 +73,7 @@ fn is_response_msg(t: MessageType) -> bool {
             | MessageType::MsgHeartbeatResponse
             | MessageType::MsgUnreachable
             | MessageType::MsgRequestPreVoteResponse
+            | MessageType::MsgHibernateResponse
     )
 }
 
@@ -172,6 +173,18 @@ impl Ready {
         self.light.take_committed_entries()
     }
 
+    /// Proposals made on this node whose outcome became known.
+    #[inline]
+    pub fn proposals(&self) -> &[ProposalOutcome] {
+        self.light.proposals()
+    }
+
+    /// Take the proposal outcomes.
+    #[inline]
+    pub fn take_proposals(&mut self) -> Vec<ProposalOutcome> {
+        self.light.take_proposals()
+    }
+
     /// Messages specifies outbound messages to be sent.
     /// If it contains a MsgSnap message, the application MUST report back to raft
     /// when the snapshot has been received or has failed by calling ReportSnapshot.
@@ -226,6 +239,28 @@ impl Ready {
     }
 }
 
+/// Identifies a proposal appended to the log of the leader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProposalHandle {
+    /// The index assigned to the proposal.
+    pub index: u64,
+    /// The term the proposal was appended in.
+    pub term: u64,
+}
+
+/// The outcome of a proposal, reported by [`LightReady::proposals`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProposalOutcome {
+    /// The proposal is committed and is among the committed entries.
+    Committed(ProposalHandle),
+    /// The index of the proposal was overwritten by a newer term, it will never
+    /// be committed.
+    Lost(ProposalHandle),
+    /// The index of the proposal was covered by a snapshot before its term could
+    /// be checked, so it may or may not have been committed.
+    Unknown(ProposalHandle),
+}
+
 /// ReadyRecord encapsulates some needed data from the corresponding Ready.
 #[derive(Default, Debug, PartialEq)]
 struct ReadyRecord {
@@ -243,6 +278,7 @@ pub struct LightReady {
     commit_index: Option<u64>,
     committed_entries: Vec<Entry>,
     messages: Vec<Message>,
+    proposals: Vec<ProposalOutcome>,
 }
 
 impl LightReady {
@@ -268,6 +304,20 @@ impl LightReady {
         mem::take(&mut self.committed_entries)
     }
 
+    /// Proposals made on this node whose outcome became known, in the order of
+    /// their indexes. A committed proposal is reported along with its entry in
+    /// the committed entries.
+    #[inline]
+    pub fn proposals(&self) -> &[ProposalOutcome] {
+        &self.proposals
+    }
+
+    /// Take the proposal outcomes.
+    #[inline]
+    pub fn take_proposals(&mut self) -> Vec<ProposalOutcome> {
+        mem::take(&mut self.proposals)
+    }
+
     /// Messages specifies outbound messages to be sent.
     #[inline]
     pub fn messages(&self) -> &[Message] {
@@ -294,6 +344,8 @@ pub struct RawNode<T: Storage> {
     records: VecDeque<ReadyRecord>,
     // Index which the given committed entries should start from.
     commit_since_index: u64,
+    // Proposals whose outcome is not known yet, in the order of their indexes.
+    proposals: VecDeque<ProposalHandle>,
 }
 
 impl<T: Storage> RawNode<T> {
@@ -309,6 +361,7 @@ impl<T: Storage> RawNode<T> {
             max_number: 0,
             records: VecDeque::new(),
             commit_since_index: config.applied,
+            proposals: VecDeque::new(),
         };
         rn.prev_hs = rn.raft.hard_state();
         rn.prev_ss = rn.raft.soft_state();
@@ -343,6 +396,36 @@ impl<T: Storage> RawNode<T> {
         self.raft.tick()
     }
 
+    /// Wakes the node up from hibernation, see `Config::hibernate_tick`.
+    ///
+    /// The application should call it once its liveness check of the leader fails.
+    pub fn wake(&mut self) {
+        self.raft.wake()
+    }
+
+    /// Checks whether data up to `index` has been applied, see
+    /// `Raft::can_read_applied`.
+    pub fn can_read_applied(&self, index: u64) -> bool {
+        self.raft.can_read_applied(index)
+    }
+
+    /// Checks whether the applied data is no older than `max_ticks` since the
+    /// node last heard from the leader, see `Raft::can_read_stale`.
+    pub fn can_read_stale(&self, max_ticks: usize) -> bool {
+        self.raft.can_read_stale(max_ticks)
+    }
+
+    /// Sets the clock used to measure the leader lease, see `Config::lease_duration`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>) {
+        self.raft.set_clock(clock)
+    }
+
+    /// Checks if the node is hibernating.
+    #[inline]
+    pub fn is_hibernated(&self) -> bool {
+        self.raft.hibernated
+    }
+
     /// Campaign causes this RawNode to transition to candidate state.
     pub fn campaign(&mut self) -> Result<()> {
         let mut m = Message::default();
@@ -351,7 +434,11 @@ impl<T: Storage> RawNode<T> {
     }
 
     /// Propose proposes data be appended to the raft log.
-    pub fn propose(&mut self, context: Vec<u8>, data: Vec<u8>) -> Result<()> {
+    ///
+    /// Returns the handle of the proposal if it's appended to the local log, its
+    /// outcome is reported by [`LightReady::proposals`] later. Returns `None` if
+    /// the proposal is forwarded to the leader, which is not tracked.
+    pub fn propose(&mut self, context: Vec<u8>, data: Vec<u8>) -> Result<Option<ProposalHandle>> {
         let mut m = Message::default();
         m.set_msg_type(MessageType::MsgPropose);
         m.from = self.raft.id;
@@ -359,7 +446,58 @@ impl<T: Storage> RawNode<T> {
         e.data = data.into();
         e.context = context.into();
         m.set_entries(vec![e].into());
-        self.raft.step(m)
+        self.step_proposal(m)
+    }
+
+    fn step_proposal(&mut self, m: Message) -> Result<Option<ProposalHandle>> {
+        let last_index = self.raft.raft_log.last_index();
+        let is_conf_change = m.get_entries()[0].get_entry_type() != EntryType::EntryNormal;
+        self.raft.step(m)?;
+        if self.raft.state != StateRole::Leader || self.raft.raft_log.last_index() == last_index {
+            return Ok(None);
+        }
+        // A conf change the leader refuses is replaced by an empty entry, only an
+        // accepted one becomes the pending conf change.
+        if is_conf_change && self.raft.pending_conf_index != self.raft.raft_log.last_index() {
+            return Ok(None);
+        }
+        let handle = ProposalHandle {
+            index: self.raft.raft_log.last_index(),
+            term: self.raft.term,
+        };
+        self.proposals.push_back(handle);
+        Ok(Some(handle))
+    }
+
+    // Finds out the proposals that are committed along with `committed_entries`,
+    // or overwritten by other entries.
+    fn resolve_proposals(&mut self, committed_entries: &[Entry]) -> Vec<ProposalOutcome> {
+        let mut outcomes = vec![];
+        let raft_log = &self.raft.raft_log;
+        let first = committed_entries.first().map(|e| e.index);
+        self.proposals.retain(|h| {
+            if let Some(first) = first.filter(|f| *f <= h.index) {
+                if let Some(e) = committed_entries.get((h.index - first) as usize) {
+                    outcomes.push(if e.term == h.term {
+                        ProposalOutcome::Committed(*h)
+                    } else {
+                        ProposalOutcome::Lost(*h)
+                    });
+                    return false;
+                }
+            }
+            if h.index > raft_log.last_index() {
+                outcomes.push(ProposalOutcome::Lost(*h));
+                return false;
+            }
+            match raft_log.term(h.index) {
+                Ok(t) if t != h.term => outcomes.push(ProposalOutcome::Lost(*h)),
+                Err(_) => outcomes.push(ProposalOutcome::Unknown(*h)),
+                Ok(_) => return true,
+            }
+            false
+        });
+        outcomes
     }
 
     /// Broadcast heartbeats to all the followers.
@@ -374,8 +512,16 @@ impl<T: Storage> RawNode<T> {
     /// If the node enters joint state with `auto_leave` set to true, it's
     /// caller's responsibility to propose an empty conf change again to force
     /// leaving joint state.
+    ///
+    /// Like [`RawNode::propose`], it returns the handle of the proposal, or
+    /// `None` if it's forwarded to the leader or the leader refuses it, e.g.
+    /// because another conf change is still pending.
     #[cfg_attr(feature = "cargo-clippy", allow(clippy::needless_pass_by_value))]
-    pub fn propose_conf_change(&mut self, context: Vec<u8>, cc: impl ConfChangeI) -> Result<()> {
+    pub fn propose_conf_change(
+        &mut self,
+        context: Vec<u8>,
+        cc: impl ConfChangeI,
+    ) -> Result<Option<ProposalHandle>> {
         let (data, ty) = if let Some(cc) = cc.as_v1() {
             (cc.write_to_bytes()?, EntryType::EntryConfChange)
         } else {
@@ -388,7 +534,7 @@ impl<T: Storage> RawNode<T> {
         e.data = data.into();
         e.context = context.into();
         m.set_entries(vec![e].into());
-        self.raft.step(m)
+        self.step_proposal(m)
     }
 
     /// Applies a config change to the local node. The app must call this when it
@@ -463,6 +609,9 @@ impl<T: Storage> RawNode<T> {
         if !raft.msgs.is_empty() {
             rd.messages = mem::take(&mut raft.msgs);
         }
+        if !self.proposals.is_empty() {
+            rd.proposals = self.resolve_proposals(&rd.committed_entries);
+        }
 
         rd
     }
@@ -823,6 +972,8 @@ mod test {
             (MessageType::MsgReadIndexResp, false),
             (MessageType::MsgRequestPreVote, false),
             (MessageType::MsgRequestPreVoteResponse, false),
+            (MessageType::MsgHibernate, false),
+            (MessageType::MsgHibernateResponse, false),
         ];
         for (msg_type, result) in tests {
             assert_eq!(is_local_msg(msg_type), result);
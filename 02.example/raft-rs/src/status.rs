@@ -33,6 +33,10 @@ pub struct Status<'a> {
     pub applied: u64,
     /// The progress towards catching up and applying logs.
     pub progress: Option<&'a ProgressTracker>,
+    /// The highest commit index known from a leader.
+    pub leader_commit: u64,
+    /// Number of ticks since the node last heard from a leader.
+    pub leader_contact_elapsed: Option<usize>,
 }
 
 impl<'a> Status<'a> {
@@ -45,6 +49,8 @@ impl<'a> Status<'a> {
         s.hs = raft.hard_state();
         s.ss = raft.soft_state();
         s.applied = raft.raft_log.applied;
+        s.leader_commit = raft.leader_commit();
+        s.leader_contact_elapsed = raft.leader_contact_elapsed();
         if s.ss.raft_state == StateRole::Leader {
             s.progress = Some(raft.prs());
         }
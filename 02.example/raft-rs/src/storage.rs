@@ -27,9 +27,13 @@ use crate::eraftpb::*;
 
 use crate::errors::{Error, Result, StorageError};
 use crate::util::limit_size;
+use crate::INVALID_ID;
 
 use getset::{Getters, Setters};
 
+#[cfg(any(test, feature = "conformance"))]
+pub mod conformance;
+
 /// Holds both the hard state (commit index, vote leader, term) and the configuration state
 /// (Current node IDs)
 #[derive(Debug, Clone, Default, Getters, Setters)]
@@ -216,6 +220,45 @@ impl MemStorageCore {
         self.raft_state.conf_state = cs;
     }
 
+    /// Rewrites the storage of the last surviving replica of a group, so it can
+    /// be started as a new group of `voters` after a majority of the replicas is
+    /// lost for good. Entries past the commit index are dropped, and the term is
+    /// bumped so that no vote or entry of the lost replicas carries over.
+    ///
+    /// It's unsafe in the raft sense: entries the lost replicas had committed
+    /// may be gone. Start the node with `Config::applied` at the commit index,
+    /// otherwise conf changes in the log are applied again over the new voters.
+    pub fn force_new_cluster(&mut self, voters: Vec<u64>) -> Result<()> {
+        if voters.is_empty() {
+            return Err(Error::ConfigInvalid(
+                "a group needs at least one voter".to_owned(),
+            ));
+        }
+        let commit = self.raft_state.hard_state.commit;
+        let keep = (commit + 1).saturating_sub(self.first_index());
+        self.entries.truncate(keep as usize);
+
+        let hard_state = &mut self.raft_state.hard_state;
+        hard_state.term += 1;
+        hard_state.vote = INVALID_ID;
+        self.raft_state.conf_state = ConfState::from((voters, vec![]));
+        Ok(())
+    }
+
+    /// Replaces the whole state with one recovered by a persistent storage.
+    /// `entries` must be contiguous, and may start past the snapshot if the log
+    /// was compacted.
+    pub(crate) fn restore(
+        &mut self,
+        raft_state: RaftState,
+        snapshot_metadata: SnapshotMetadata,
+        entries: Vec<Entry>,
+    ) {
+        self.raft_state = raft_state;
+        self.snapshot_metadata = snapshot_metadata;
+        self.entries = entries;
+    }
+
     #[inline]
     fn has_entry_at(&self, index: u64) -> bool {
         !self.entries.is_empty() && index >= self.first_index() && index <= self.last_index()
@@ -519,6 +562,42 @@ impl Storage for MemStorage {
     }
 }
 
+/// The writes a `Storage` is set up with, e.g. by the checks of `conformance`.
+/// They behave like the methods of the same name of `MemStorageCore`,
+/// including when they panic.
+pub trait MutableStorage: Storage {
+    /// Appends entries, overwriting the ones from the index of the first.
+    fn append(&self, ents: &[Entry]) -> Result<()>;
+
+    /// Discards the entries before `compact_index`.
+    fn compact(&self, compact_index: u64) -> Result<()>;
+
+    /// Overwrites the storage with the snapshot.
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()>;
+
+    /// Saves the hard state.
+    fn set_hardstate(&self, hs: HardState) -> Result<()>;
+}
+
+impl MutableStorage for MemStorage {
+    fn append(&self, ents: &[Entry]) -> Result<()> {
+        self.wl().append(ents)
+    }
+
+    fn compact(&self, compact_index: u64) -> Result<()> {
+        self.wl().compact(compact_index)
+    }
+
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        self.wl().apply_snapshot(snapshot)
+    }
+
+    fn set_hardstate(&self, hs: HardState) -> Result<()> {
+        self.wl().set_hardstate(hs);
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::panic::{self, AssertUnwindSafe};
@@ -528,7 +607,7 @@ mod test {
     use crate::eraftpb::{ConfState, Entry, Snapshot};
     use crate::errors::{Error as RaftError, StorageError};
 
-    use super::{GetEntriesContext, MemStorage, Storage};
+    use super::{conformance, GetEntriesContext, MemStorage, Storage};
 
     fn new_entry(index: u64, term: u64) -> Entry {
         let mut e = Entry::default();
@@ -810,4 +889,47 @@ mod test {
         let snap = new_snapshot(3, 3, nodes);
         storage.wl().apply_snapshot(snap).unwrap_err();
     }
+
+    #[test]
+    fn test_storage_force_new_cluster() {
+        let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+        let storage = MemStorage::new();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(2, 2, vec![1, 2, 3]))
+            .unwrap();
+        storage.wl().append(&ents).unwrap();
+        storage.wl().mut_hard_state().commit = 4;
+        storage.wl().mut_hard_state().term = 5;
+        storage.wl().mut_hard_state().vote = 2;
+
+        storage.wl().force_new_cluster(vec![]).unwrap_err();
+        storage.wl().force_new_cluster(vec![1]).unwrap();
+        assert_eq!(storage.last_index(), Ok(4));
+        assert_eq!(
+            storage.entries(3, 5, None, GetEntriesContext::empty(false)),
+            Ok(ents[..2].to_vec())
+        );
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state.term, 6);
+        assert_eq!(state.hard_state.vote, 0);
+        assert_eq!(state.hard_state.commit, 4);
+        assert_eq!(state.conf_state, ConfState::from((vec![1], vec![])));
+
+        // Nothing past the snapshot is committed.
+        let storage = MemStorage::new();
+        storage
+            .wl()
+            .apply_snapshot(new_snapshot(2, 2, vec![1, 2, 3]))
+            .unwrap();
+        storage.wl().append(&ents).unwrap();
+        storage.wl().force_new_cluster(vec![1]).unwrap();
+        assert_eq!(storage.first_index(), Ok(3));
+        assert_eq!(storage.last_index(), Ok(2));
+    }
+
+    #[test]
+    fn test_mem_storage_conformance() {
+        conformance::run_all(MemStorage::new);
+    }
 }
@@ -0,0 +1,402 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! Checks that a [`Storage`] behaves the way raft expects, the same way
+//! `MemStorage` does.
+//!
+//! A backend implements [`MutableStorage`], so that the checks can set up its
+//! state, and calls [`run_all`] from one of its tests with a function that
+//! returns an empty storage. A persistent backend passes a [`reopening`]
+//! backend instead, so that the checks also read back what it persisted. The
+//! checks can also be run one by one. A failed check panics.
+//!
+//! It's built for the crate's tests, and with the `conformance` feature for the
+//! tests of other crates.
+//!
+//! ```
+//! use raft::storage::{conformance, MemStorage};
+//!
+//! conformance::run_all(MemStorage::new);
+//! ```
+
+use std::panic::{self, AssertUnwindSafe};
+
+use protobuf::Message as PbMessage;
+
+use crate::eraftpb::{ConfState, Entry, HardState, Snapshot};
+use crate::errors::{Error, StorageError};
+
+use super::{GetEntriesContext, MutableStorage, Storage};
+
+/// What the checks run on. Any `FnMut() -> S` is a backend whose storages are
+/// never reopened.
+pub trait Backend {
+    /// The storage checked.
+    type Storage: MutableStorage;
+
+    /// Returns an empty storage.
+    fn create(&mut self) -> Self::Storage;
+
+    /// Returns `storage` as it is read back after a restart. The checks call
+    /// it after their writes. By default the storage is returned as is.
+    fn reopen(&mut self, storage: Self::Storage) -> Self::Storage {
+        storage
+    }
+}
+
+impl<S: MutableStorage, F: FnMut() -> S> Backend for F {
+    type Storage = S;
+
+    fn create(&mut self) -> S {
+        self()
+    }
+}
+
+/// A [`Backend`] returned by [`reopening`].
+pub struct Reopening<F, R> {
+    new_storage: F,
+    reopen: R,
+}
+
+impl<S, F, R> Backend for Reopening<F, R>
+where
+    S: MutableStorage,
+    F: FnMut() -> S,
+    R: FnMut(S) -> S,
+{
+    type Storage = S;
+
+    fn create(&mut self) -> S {
+        (self.new_storage)()
+    }
+
+    fn reopen(&mut self, storage: S) -> S {
+        (self.reopen)(storage)
+    }
+}
+
+/// Returns a backend creating storages with `new_storage`, and reopening them
+/// with `reopen`, which should drop the storage and open it again.
+pub fn reopening<S, F, R>(new_storage: F, reopen: R) -> Reopening<F, R>
+where
+    S: MutableStorage,
+    F: FnMut() -> S,
+    R: FnMut(S) -> S,
+{
+    Reopening {
+        new_storage,
+        reopen,
+    }
+}
+
+/// Runs all the checks, each on storages returned by `backend`.
+pub fn run_all<B: Backend>(mut backend: B) {
+    check_term(&mut backend);
+    check_entries(&mut backend);
+    check_first_and_last_index(&mut backend);
+    check_compact(&mut backend);
+    check_create_snapshot(&mut backend);
+    check_append(&mut backend);
+    check_apply_snapshot(&mut backend);
+    check_hard_state(&mut backend);
+}
+
+fn new_entry(index: u64, term: u64) -> Entry {
+    let mut e = Entry::default();
+    e.term = term;
+    e.index = index;
+    e
+}
+
+fn size_of<T: PbMessage>(m: &T) -> u32 {
+    m.compute_size()
+}
+
+fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
+    let mut s = Snapshot::default();
+    s.mut_metadata().index = index;
+    s.mut_metadata().term = term;
+    s.mut_metadata().mut_conf_state().voters = voters;
+    s
+}
+
+/// Returns a storage holding `ents` and nothing before them, with voters 1, 2
+/// and 3. `ents` must start at 3 or later.
+fn storage_with<B: Backend>(backend: &mut B, ents: &[Entry]) -> B::Storage {
+    let storage = backend.create();
+    let first = ents[0].index;
+    storage
+        .apply_snapshot(new_snapshot(first - 2, first - 2, vec![1, 2, 3]))
+        .unwrap();
+    storage.append(&[new_entry(first - 1, first - 1)]).unwrap();
+    storage.append(ents).unwrap();
+    storage.compact(first).unwrap();
+    backend.reopen(storage)
+}
+
+fn all_entries<S: Storage>(storage: &S) -> Vec<Entry> {
+    let (first, last) = (
+        storage.first_index().unwrap(),
+        storage.last_index().unwrap(),
+    );
+    storage
+        .entries(first, last + 1, None, GetEntriesContext::empty(false))
+        .unwrap()
+}
+
+/// Checks `Storage::term` inside and outside of the log.
+pub fn check_term<B: Backend>(backend: &mut B) {
+    let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+    let mut tests = vec![
+        (2, Err(Error::Store(StorageError::Compacted))),
+        (3, Ok(3)),
+        (4, Ok(4)),
+        (5, Ok(5)),
+        (6, Err(Error::Store(StorageError::Unavailable))),
+    ];
+
+    for (i, (idx, wterm)) in tests.drain(..).enumerate() {
+        let storage = storage_with(backend, &ents);
+
+        let t = storage.term(idx);
+        if t != wterm {
+            panic!("term #{}: expect res {:?}, got {:?}", i, wterm, t);
+        }
+    }
+}
+
+/// Checks `Storage::entries`, with and without a size limit.
+pub fn check_entries<B: Backend>(backend: &mut B) {
+    let ents = vec![
+        new_entry(3, 3),
+        new_entry(4, 4),
+        new_entry(5, 5),
+        new_entry(6, 6),
+    ];
+    let max_u64 = u64::MAX;
+    let mut tests = vec![
+        (2, 6, max_u64, Err(Error::Store(StorageError::Compacted))),
+        (3, 4, max_u64, Ok(vec![new_entry(3, 3)])),
+        (4, 5, max_u64, Ok(vec![new_entry(4, 4)])),
+        (4, 6, max_u64, Ok(vec![new_entry(4, 4), new_entry(5, 5)])),
+        (
+            4,
+            7,
+            max_u64,
+            Ok(vec![new_entry(4, 4), new_entry(5, 5), new_entry(6, 6)]),
+        ),
+        // even if maxsize is zero, the first entry should be returned
+        (4, 7, 0, Ok(vec![new_entry(4, 4)])),
+        // limit to 2
+        (
+            4,
+            7,
+            u64::from(size_of(&ents[1]) + size_of(&ents[2])),
+            Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
+        ),
+        (
+            4,
+            7,
+            u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3]) / 2),
+            Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
+        ),
+        (
+            4,
+            7,
+            u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3]) - 1),
+            Ok(vec![new_entry(4, 4), new_entry(5, 5)]),
+        ),
+        // all
+        (
+            4,
+            7,
+            u64::from(size_of(&ents[1]) + size_of(&ents[2]) + size_of(&ents[3])),
+            Ok(vec![new_entry(4, 4), new_entry(5, 5), new_entry(6, 6)]),
+        ),
+    ];
+    for (i, (lo, hi, maxsize, wentries)) in tests.drain(..).enumerate() {
+        let storage = storage_with(backend, &ents);
+        let e = storage.entries(lo, hi, maxsize, GetEntriesContext::empty(false));
+        if e != wentries {
+            panic!("entries #{}: expect entries {:?}, got {:?}", i, wentries, e);
+        }
+    }
+}
+
+/// Checks that the first and last index follow compaction and appends.
+pub fn check_first_and_last_index<B: Backend>(backend: &mut B) {
+    let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+    let storage = storage_with(backend, &ents);
+    assert_eq!(storage.first_index(), Ok(3), "first index");
+    assert_eq!(storage.last_index(), Ok(5), "last index");
+
+    storage.append(&[new_entry(6, 5)]).unwrap();
+    let storage = backend.reopen(storage);
+    assert_eq!(storage.last_index(), Ok(6), "last index after append");
+    storage.compact(4).unwrap();
+    let storage = backend.reopen(storage);
+    assert_eq!(storage.first_index(), Ok(4), "first index after compact");
+}
+
+/// Checks compaction, including to an index that is already compacted.
+pub fn check_compact<B: Backend>(backend: &mut B) {
+    let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+    let mut tests = vec![(2, 3, 3, 3), (3, 3, 3, 3), (4, 4, 4, 2), (5, 5, 5, 1)];
+    for (i, (idx, windex, wterm, wlen)) in tests.drain(..).enumerate() {
+        let storage = storage_with(backend, &ents);
+
+        storage.compact(idx).unwrap();
+        let storage = backend.reopen(storage);
+        let index = storage.first_index().unwrap();
+        if index != windex {
+            panic!("compact #{}: want {}, index {}", i, windex, index);
+        }
+        let term = storage.term(index).unwrap();
+        if term != wterm {
+            panic!("compact #{}: want {}, term {}", i, wterm, term);
+        }
+        let len = all_entries(&storage).len();
+        if len != wlen {
+            panic!("compact #{}: want {}, len {}", i, wlen, len);
+        }
+    }
+}
+
+/// Checks that snapshots are built at the commit index, or at the requested
+/// index if it's higher.
+pub fn check_create_snapshot<B: Backend>(backend: &mut B) {
+    let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+    let nodes = vec![1, 2, 3];
+
+    let mut tests = vec![
+        (4, new_snapshot(4, 4, nodes.clone()), 0),
+        (5, new_snapshot(5, 5, nodes.clone()), 5),
+        (5, new_snapshot(6, 5, nodes), 6),
+    ];
+    for (i, (idx, wsnap, windex)) in tests.drain(..).enumerate() {
+        let storage = storage_with(backend, &ents);
+        let mut hs = HardState::default();
+        hs.commit = idx;
+        hs.term = idx;
+        storage.set_hardstate(hs).unwrap();
+        let storage = backend.reopen(storage);
+
+        let snap = storage.snapshot(windex, 0).unwrap();
+        if *snap.get_metadata() != *wsnap.get_metadata() {
+            panic!("create snapshot #{}: want {:?}, got {:?}", i, wsnap, snap);
+        }
+    }
+}
+
+/// Checks appends that overwrite, extend, or reach into the compacted log.
+pub fn check_append<B: Backend>(backend: &mut B) {
+    let ents = vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)];
+    let mut tests = vec![
+        (
+            vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)],
+            Some(vec![new_entry(3, 3), new_entry(4, 4), new_entry(5, 5)]),
+        ),
+        (
+            vec![new_entry(3, 3), new_entry(4, 6), new_entry(5, 6)],
+            Some(vec![new_entry(3, 3), new_entry(4, 6), new_entry(5, 6)]),
+        ),
+        (
+            vec![
+                new_entry(3, 3),
+                new_entry(4, 4),
+                new_entry(5, 5),
+                new_entry(6, 5),
+            ],
+            Some(vec![
+                new_entry(3, 3),
+                new_entry(4, 4),
+                new_entry(5, 5),
+                new_entry(6, 5),
+            ]),
+        ),
+        // overwrite compacted raft logs is not allowed
+        (
+            vec![new_entry(2, 3), new_entry(3, 3), new_entry(4, 5)],
+            None,
+        ),
+        // truncate the existing entries and append
+        (
+            vec![new_entry(4, 5)],
+            Some(vec![new_entry(3, 3), new_entry(4, 5)]),
+        ),
+        // direct append
+        (
+            vec![new_entry(6, 6)],
+            Some(vec![
+                new_entry(3, 3),
+                new_entry(4, 4),
+                new_entry(5, 5),
+                new_entry(6, 6),
+            ]),
+        ),
+    ];
+    for (i, (entries, wentries)) in tests.drain(..).enumerate() {
+        let storage = storage_with(backend, &ents);
+        let res = panic::catch_unwind(AssertUnwindSafe(|| storage.append(&entries)));
+        if let Some(wentries) = wentries {
+            res.unwrap().unwrap();
+            let storage = backend.reopen(storage);
+            let e = all_entries(&storage);
+            if e != wentries {
+                panic!("append #{}: want {:?}, entries {:?}", i, wentries, e);
+            }
+        } else if matches!(res, Ok(Ok(_))) {
+            panic!("append #{}: overwrote compacted entries", i);
+        }
+    }
+}
+
+/// Checks that a snapshot replaces the log and the states, and that an older
+/// one is refused.
+pub fn check_apply_snapshot<B: Backend>(backend: &mut B) {
+    let nodes = vec![1, 2, 3];
+    let storage = backend.create();
+    storage
+        .append(&[new_entry(1, 1), new_entry(2, 1), new_entry(3, 2)])
+        .unwrap();
+    let storage = backend.reopen(storage);
+
+    // Apply snapshot successfully
+    let snap = new_snapshot(4, 4, nodes.clone());
+    storage.apply_snapshot(snap).unwrap();
+    let storage = backend.reopen(storage);
+    assert_eq!(storage.first_index(), Ok(5), "first index after snapshot");
+    assert_eq!(storage.last_index(), Ok(4), "last index after snapshot");
+    assert_eq!(storage.term(4), Ok(4), "snapshot term");
+    let state = storage.initial_state().unwrap();
+    assert_eq!(state.hard_state.commit, 4, "commit after snapshot");
+    assert_eq!(state.hard_state.term, 4, "term after snapshot");
+    assert_eq!(state.conf_state.voters, nodes, "voters after snapshot");
+
+    // Apply snapshot fails due to StorageError::SnapshotOutOfDate
+    let snap = new_snapshot(3, 3, nodes);
+    assert_eq!(
+        storage.apply_snapshot(snap),
+        Err(Error::Store(StorageError::SnapshotOutOfDate)),
+        "older snapshot"
+    );
+}
+
+/// Checks that the hard state is returned as it was saved, and that it
+/// doesn't touch the conf state.
+pub fn check_hard_state<B: Backend>(backend: &mut B) {
+    let storage = storage_with(backend, &[new_entry(3, 3)]);
+    let mut hs = HardState::default();
+    hs.term = 3;
+    hs.vote = 2;
+    hs.commit = 3;
+    storage.set_hardstate(hs.clone()).unwrap();
+    let storage = backend.reopen(storage);
+
+    let state = storage.initial_state().unwrap();
+    assert_eq!(state.hard_state, hs, "hard state");
+    assert_eq!(
+        state.conf_state,
+        ConfState::from((vec![1, 2, 3], vec![])),
+        "conf state"
+    );
+}
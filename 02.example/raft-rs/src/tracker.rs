@@ -23,9 +23,9 @@ pub use self::progress::Progress;
 pub use self::state::ProgressState;
 
 use crate::confchange::{MapChange, MapChangeType};
-use crate::eraftpb::ConfState;
+use crate::eraftpb::{ConfState, VoterWeight, VoterZone};
 use crate::quorum::{AckedIndexer, Index, VoteResult};
-use crate::{DefaultHashBuilder, HashMap, HashSet, JointConfig};
+use crate::{DefaultHashBuilder, HashMap, HashSet, JointConfig, MajorityConfig};
 use getset::Getters;
 use std::fmt::Debug;
 
@@ -86,6 +86,15 @@ pub struct Configuration {
     /// initiates the transition manually.
     #[get = "pub"]
     pub(crate) auto_leave: bool,
+    /// Witnesses is a set of IDs corresponding to the voters, in either half of
+    /// the joint config, that are witnesses. A witness votes and counts toward
+    /// quorum like any other voter, but it only receives entries without data and
+    /// never becomes leader.
+    ///
+    /// Invariant: Witnesses is a subset of Voters, and a peer can't be turned
+    /// into a witness or out of it without being removed first.
+    #[get = "pub"]
+    pub(crate) witnesses: HashSet<u64>,
 }
 
 // Display and crate::itertools used only for test
@@ -125,6 +134,18 @@ impl std::fmt::Display for Configuration {
                     .join(" ")
             )?
         }
+        if !self.witnesses.is_empty() {
+            write!(
+                f,
+                " witnesses=({})",
+                self.witnesses
+                    .iter()
+                    .sorted_by(|&a, &b| a.cmp(b))
+                    .map(|x| x.to_string())
+                    .collect::<Vec<String>>()
+                    .join(" ")
+            )?
+        }
         if self.auto_leave {
             write!(f, " autoleave")?
         }
@@ -143,6 +164,7 @@ impl Configuration {
             auto_leave: false,
             learners: learners.into_iter().collect(),
             learners_next: HashSet::default(),
+            witnesses: HashSet::default(),
         }
     }
 
@@ -152,6 +174,7 @@ impl Configuration {
             learners: HashSet::with_capacity_and_hasher(learners, DefaultHashBuilder::default()),
             learners_next: HashSet::default(),
             auto_leave: false,
+            witnesses: HashSet::default(),
         }
     }
 
@@ -164,6 +187,11 @@ impl Configuration {
         state.set_learners(self.learners.iter().cloned().collect());
         state.set_learners_next(self.learners_next.iter().cloned().collect());
         state.auto_leave = self.auto_leave;
+        state.set_witnesses(self.witnesses.iter().cloned().collect());
+        state.set_weights(to_voter_weights(&self.voters.incoming).into());
+        state.set_weights_outgoing(to_voter_weights(&self.voters.outgoing).into());
+        state.set_zones(to_voter_zones(&self.voters.incoming).into());
+        state.set_zones_outgoing(to_voter_zones(&self.voters.outgoing).into());
         state
     }
 
@@ -172,9 +200,36 @@ impl Configuration {
         self.learners.clear();
         self.learners_next.clear();
         self.auto_leave = false;
+        self.witnesses.clear();
     }
 }
 
+fn to_voter_weights(voters: &MajorityConfig) -> Vec<VoterWeight> {
+    voters
+        .weights()
+        .iter()
+        .map(|(id, weight)| {
+            let mut w = VoterWeight::default();
+            w.id = *id;
+            w.weight = *weight;
+            w
+        })
+        .collect()
+}
+
+fn to_voter_zones(voters: &MajorityConfig) -> Vec<VoterZone> {
+    voters
+        .zones()
+        .iter()
+        .map(|(id, zone)| {
+            let mut z = VoterZone::default();
+            z.id = *id;
+            z.zone = *zone;
+            z
+        })
+        .collect()
+}
+
 pub type ProgressMap = HashMap<u64, Progress>;
 
 impl AckedIndexer for ProgressMap {
@@ -202,6 +257,9 @@ pub struct ProgressTracker {
     max_inflight: usize,
 
     group_commit: bool,
+
+    election_quorum: u64,
+    commit_quorum: u64,
 }
 
 impl ProgressTracker {
@@ -221,6 +279,8 @@ impl ProgressTracker {
             votes: HashMap::with_capacity_and_hasher(voters, DefaultHashBuilder::default()),
             max_inflight,
             group_commit: false,
+            election_quorum: 0,
+            commit_quorum: 0,
         }
     }
 
@@ -234,6 +294,19 @@ impl ProgressTracker {
         self.group_commit
     }
 
+    /// Configures flexible quorums, see `Config::election_quorum` and
+    /// `Config::commit_quorum`. 0 for either uses majority quorums.
+    pub fn set_flexible_quorum(&mut self, election: u64, commit: u64) {
+        self.election_quorum = election;
+        self.commit_quorum = commit;
+        self.conf.voters.set_flexible_quorum(election, commit);
+    }
+
+    /// Returns the flexible quorum sizes as `(election, commit)`.
+    pub fn flexible_quorum(&self) -> (u64, u64) {
+        (self.election_quorum, self.commit_quorum)
+    }
+
     pub(crate) fn clear(&mut self) {
         self.progress.clear();
         self.conf.clear();
@@ -353,11 +426,13 @@ impl ProgressTracker {
     /// Determine if a quorum is formed from the given set of nodes.
     ///
     /// This is the only correct way to verify you have reached a quorum for the whole group.
+    /// With flexible quorums, the quorum is just large enough to keep any other node from
+    /// being elected, see `MajorityConfig::lease_result`.
     #[inline]
     pub fn has_quorum(&self, potential_quorum: &HashSet<u64>) -> bool {
         self.conf
             .voters
-            .vote_result(|id| potential_quorum.get(&id).map(|_| true))
+            .lease_result(|id| potential_quorum.get(&id).map(|_| true))
             == VoteResult::Won
     }
 
@@ -369,6 +444,9 @@ impl ProgressTracker {
     /// Applies configuration and updates progress map to match the configuration.
     pub fn apply_conf(&mut self, conf: Configuration, changes: MapChange, next_idx: u64) {
         self.conf = conf;
+        self.conf
+            .voters
+            .set_flexible_quorum(self.election_quorum, self.commit_quorum);
         for (id, change_type) in changes {
             match change_type {
                 MapChangeType::Add => {
@@ -377,6 +455,7 @@ impl ProgressTracker {
                     // Otherwise, CheckQuorum may cause us to step down if it is invoked
                     // before the added node has had a chance to communicate with us.
                     pr.recent_active = true;
+                    pr.is_witness = self.conf.witnesses.contains(&id);
                     self.progress.insert(id, pr);
                 }
                 MapChangeType::Remove => {
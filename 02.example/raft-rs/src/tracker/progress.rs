@@ -53,6 +53,12 @@ pub struct Progress {
 
     /// Committed index in raft_log
     pub committed_index: u64,
+
+    /// Whether the peer is a witness, which only receives entries without data.
+    pub is_witness: bool,
+
+    /// The election priority of the peer, as last reported in a heartbeat response.
+    pub priority: i64,
 }
 
 impl Progress {
@@ -69,6 +75,8 @@ impl Progress {
             ins: Inflights::new(ins_size),
             commit_group_id: 0,
             committed_index: 0,
+            is_witness: false,
+            priority: 0,
         }
     }
 
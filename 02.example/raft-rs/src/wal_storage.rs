@@ -0,0 +1,819 @@
+// Copyright 2022 TiKV Project Authors. Licensed under Apache-2.0.
+
+//! A [`Storage`] that keeps the raft log in a directory on the local filesystem.
+//!
+//! Entries are appended to a log split into segment files. Every entry is
+//! framed as a record of `length: u32, crc32: u32, entry`, so a write torn by a
+//! crash is detected, and cut off when the log is opened again. Overwritten
+//! entries are left in their segment: when the log is replayed, a record
+//! replaces the entries of the same or a higher index written before it.
+//! Compaction deletes the oldest segments once all of their entries are below
+//! the compact index, segments are never rewritten.
+//!
+//! The hard state, the conf state and the snapshot metadata are kept in a
+//! separate state file, which is replaced atomically by renaming a temporary
+//! file over it.
+//!
+//! The entries are also kept in memory, the segments are only read when the
+//! storage is opened.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use protobuf::Message as PbMessage;
+
+use crate::eraftpb::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
+use crate::errors::{Error, Result, StorageError};
+use crate::storage::{GetEntriesContext, MemStorage, MutableStorage, RaftState, Storage};
+
+const STATE_FILE: &str = "state";
+const STATE_TMP_FILE: &str = "state.tmp";
+const SEGMENT_SUFFIX: &str = ".log";
+
+/// The configuration of a [`WalStorage`].
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    /// A new segment is started once the active one has grown to this size, in
+    /// bytes. Compaction frees space in steps of a segment.
+    pub segment_size: u64,
+    /// Whether appended entries are synced to disk before `append` returns.
+    /// Otherwise they are only handed to the OS, and [`WalStorage::sync`] has to
+    /// be called to make them durable. The state file is always synced.
+    pub sync: bool,
+}
+
+impl Default for WalConfig {
+    fn default() -> WalConfig {
+        WalConfig {
+            segment_size: 64 * 1024 * 1024,
+            sync: true,
+        }
+    }
+}
+
+/// What the state file holds.
+#[derive(Clone)]
+struct DurableState {
+    hard_state: HardState,
+    conf_state: ConfState,
+    snapshot_metadata: SnapshotMetadata,
+    // Entries below it are compacted, even if a segment still holds them.
+    first_index: u64,
+    // Segments with a lower sequence number were written before the last
+    // snapshot was applied.
+    min_seq: u64,
+}
+
+impl Default for DurableState {
+    fn default() -> DurableState {
+        DurableState {
+            hard_state: HardState::default(),
+            conf_state: ConfState::default(),
+            snapshot_metadata: SnapshotMetadata::default(),
+            first_index: 1,
+            min_seq: 0,
+        }
+    }
+}
+
+impl DurableState {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut body = vec![];
+        body.extend_from_slice(&self.first_index.to_le_bytes());
+        body.extend_from_slice(&self.min_seq.to_le_bytes());
+        for msg in [
+            self.hard_state.write_to_bytes()?,
+            self.conf_state.write_to_bytes()?,
+            self.snapshot_metadata.write_to_bytes()?,
+        ] {
+            body.extend_from_slice(&(msg.len() as u32).to_le_bytes());
+            body.extend_from_slice(&msg);
+        }
+        let mut buf = crc32fast::hash(&body).to_le_bytes().to_vec();
+        buf.extend_from_slice(&body);
+        Ok(buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<DurableState> {
+        let mut reader = Reader(buf);
+        let crc = reader.u32();
+        if crc != Some(crc32fast::hash(reader.0)) {
+            return Err(corrupted("the state file is damaged".to_owned()));
+        }
+        let fields = (|| {
+            Some((
+                reader.u64()?,
+                reader.u64()?,
+                reader.bytes()?,
+                reader.bytes()?,
+                reader.bytes()?,
+            ))
+        })();
+        let (first_index, min_seq, hs, cs, meta) =
+            fields.ok_or_else(|| corrupted("the state file is truncated".to_owned()))?;
+        let mut state = DurableState {
+            first_index,
+            min_seq,
+            ..Default::default()
+        };
+        state.hard_state.merge_from_bytes(hs)?;
+        state.conf_state.merge_from_bytes(cs)?;
+        state.snapshot_metadata.merge_from_bytes(meta)?;
+        Ok(state)
+    }
+}
+
+/// Reads little-endian fields, `None` once the buffer is too short.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (head, tail) = self.0.split_at(n);
+        self.0 = tail;
+        Some(head)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        self.take(4)
+            .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        self.take(8)
+            .map(|b| u64::from_le_bytes(b.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        self.take(len as usize)
+    }
+
+    /// Reads a record, `None` if it's cut short or its checksum doesn't match.
+    fn record(&mut self) -> Option<&'a [u8]> {
+        let len = self.u32()?;
+        let crc = self.u32()?;
+        let data = self.take(len as usize)?;
+        if crc32fast::hash(data) != crc {
+            return None;
+        }
+        Some(data)
+    }
+}
+
+fn corrupted(msg: String) -> Error {
+    Error::Io(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+// Segments are named after their sequence number and the index of their first
+// entry, in fixed-width hex so that they sort by name.
+fn segment_name(seq: u64, first_index: u64) -> String {
+    format!("{:016x}-{:016x}{}", seq, first_index, SEGMENT_SUFFIX)
+}
+
+fn parse_segment_name(name: &str) -> Option<u64> {
+    let (seq, first_index) = name.strip_suffix(SEGMENT_SUFFIX)?.split_once('-')?;
+    if seq.len() != 16 || first_index.len() != 16 {
+        return None;
+    }
+    u64::from_str_radix(first_index, 16).ok()?;
+    u64::from_str_radix(seq, 16).ok()
+}
+
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    // Directories can't be opened as files on Windows, where renames are
+    // durable once they return.
+    #[cfg(unix)]
+    File::open(dir)?.sync_all()?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+struct Segment {
+    seq: u64,
+    path: PathBuf,
+    // The highest index of the entries written to it.
+    max_index: u64,
+}
+
+/// The files of a `WalStorage`.
+struct Wal {
+    dir: PathBuf,
+    config: WalConfig,
+    state: DurableState,
+    // Ordered by sequence number, entries are appended to the last one.
+    segments: Vec<Segment>,
+    active: Option<File>,
+    active_size: u64,
+    next_seq: u64,
+}
+
+impl Wal {
+    /// Opens the files in `dir`, and returns the entries replayed from them.
+    fn open(dir: &Path, config: WalConfig) -> Result<(Wal, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+        let state = match fs::read(dir.join(STATE_FILE)) {
+            Ok(buf) => DurableState::decode(&buf)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => DurableState::default(),
+            Err(e) => return Err(e.into()),
+        };
+        // A write of the state file cut short by a crash.
+        if let Err(e) = fs::remove_file(dir.join(STATE_TMP_FILE)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e.into());
+            }
+        }
+
+        let mut segments = vec![];
+        for dirent in fs::read_dir(dir)? {
+            let dirent = dirent?;
+            if let Some(seq) = dirent.file_name().to_str().and_then(parse_segment_name) {
+                segments.push(Segment {
+                    seq,
+                    path: dirent.path(),
+                    max_index: 0,
+                });
+            }
+        }
+        segments.sort_by_key(|s| s.seq);
+        // Applying a snapshot deletes all segments once the state file is
+        // written, a crash may have stopped it halfway.
+        for segment in segments.iter().filter(|s| s.seq < state.min_seq) {
+            fs::remove_file(&segment.path)?;
+        }
+        segments.retain(|s| s.seq >= state.min_seq);
+
+        let mut entries: Vec<Entry> = vec![];
+        let mut active_size = 0;
+        let count = segments.len();
+        for (i, segment) in segments.iter_mut().enumerate() {
+            let buf = fs::read(&segment.path)?;
+            let mut reader = Reader(&buf);
+            let mut valid = 0;
+            while let Some(data) = reader.record() {
+                let mut entry = Entry::default();
+                entry.merge_from_bytes(data)?;
+                valid = buf.len() - reader.0.len();
+
+                segment.max_index = cmp::max(segment.max_index, entry.index);
+                if entry.index < state.first_index {
+                    // Overwrites every entry past the compact index as well.
+                    entries.clear();
+                    continue;
+                }
+                let next_index = state.first_index + entries.len() as u64;
+                if entry.index > next_index {
+                    return Err(corrupted(format!(
+                        "entry {} follows entry {} in {}",
+                        entry.index,
+                        next_index - 1,
+                        segment.path.display()
+                    )));
+                }
+                entries.truncate((entry.index - state.first_index) as usize);
+                entries.push(entry);
+            }
+            if valid < buf.len() {
+                if i + 1 < count {
+                    return Err(corrupted(format!(
+                        "{} is damaged at offset {}",
+                        segment.path.display(),
+                        valid
+                    )));
+                }
+                // Only the last record written before a crash can be torn.
+                let file = OpenOptions::new().write(true).open(&segment.path)?;
+                file.set_len(valid as u64)?;
+                file.sync_all()?;
+            }
+            active_size = valid as u64;
+        }
+
+        let active = match segments.last() {
+            Some(segment) => Some(OpenOptions::new().append(true).open(&segment.path)?),
+            None => None,
+        };
+        let next_seq = segments.last().map_or(state.min_seq, |s| s.seq + 1);
+        let wal = Wal {
+            dir: dir.to_owned(),
+            config,
+            state,
+            segments,
+            active,
+            active_size,
+            next_seq,
+        };
+        Ok((wal, entries))
+    }
+
+    /// Replaces the state file with `state`.
+    fn save_state(&mut self, state: DurableState) -> Result<()> {
+        let tmp = self.dir.join(STATE_TMP_FILE);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&state.encode()?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, self.dir.join(STATE_FILE))?;
+        sync_dir(&self.dir)?;
+        self.state = state;
+        Ok(())
+    }
+
+    fn append(&mut self, ents: &[Entry]) -> Result<()> {
+        let mut buf = vec![];
+        for entry in ents {
+            let data = entry.write_to_bytes()?;
+            buf.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buf.extend_from_slice(&crc32fast::hash(&data).to_le_bytes());
+            buf.extend_from_slice(&data);
+        }
+        if self.active.is_none() || self.active_size >= self.config.segment_size {
+            self.roll(ents[0].index)?;
+        }
+
+        let file = self.active.as_mut().unwrap();
+        if let Err(e) = file.write_all(&buf) {
+            // Don't leave a partial record for the next append to follow.
+            let _ = file.set_len(self.active_size);
+            return Err(e.into());
+        }
+        self.active_size += buf.len() as u64;
+        let segment = self.segments.last_mut().unwrap();
+        segment.max_index = cmp::max(segment.max_index, ents.last().unwrap().index);
+        if self.config.sync {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Starts a new segment, whose first entry is `first_index`.
+    fn roll(&mut self, first_index: u64) -> Result<()> {
+        if let Some(file) = self.active.take() {
+            file.sync_data()?;
+        }
+        let path = self.dir.join(segment_name(self.next_seq, first_index));
+        let file = OpenOptions::new()
+            .append(true)
+            .create_new(true)
+            .open(&path)?;
+        sync_dir(&self.dir)?;
+        self.segments.push(Segment {
+            seq: self.next_seq,
+            path,
+            max_index: 0,
+        });
+        self.next_seq += 1;
+        self.active = Some(file);
+        self.active_size = 0;
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if let Some(file) = &self.active {
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    fn compact(&mut self, compact_index: u64) -> Result<()> {
+        let mut state = self.state.clone();
+        state.first_index = compact_index;
+        self.save_state(state)?;
+
+        // Only whole segments from the front can go, a later record may rely on
+        // an earlier segment to overwrite its stale entries.
+        let n = self
+            .segments
+            .iter()
+            .take_while(|s| s.max_index < compact_index)
+            .count();
+        if n == self.segments.len() {
+            self.active = None;
+        }
+        for segment in self.segments.drain(..n) {
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+
+    /// Saves `state` and deletes all segments.
+    fn reset(&mut self, mut state: DurableState) -> Result<()> {
+        state.min_seq = self.next_seq;
+        self.save_state(state)?;
+        self.active = None;
+        for segment in self.segments.drain(..) {
+            fs::remove_file(&segment.path)?;
+        }
+        Ok(())
+    }
+}
+
+/// `WalStorage` is a `Storage` that persists the raft log to a directory, see
+/// the module docs for the layout.
+///
+/// Like `MemStorage`, it doesn't store applied data, so the snapshots it
+/// returns carry no data.
+#[derive(Clone)]
+pub struct WalStorage {
+    mem: MemStorage,
+    wal: Arc<Mutex<Wal>>,
+}
+
+impl WalStorage {
+    /// Opens the log in `dir`, or creates an empty one if there is none.
+    ///
+    /// A record torn by a crash at the end of the log is cut off. Damage
+    /// anywhere else is returned as an `InvalidData` IO error.
+    pub fn open<P: AsRef<Path>>(dir: P, config: WalConfig) -> Result<WalStorage> {
+        let (wal, entries) = Wal::open(dir.as_ref(), config)?;
+        let mem = MemStorage::new();
+        let raft_state = RaftState::new(wal.state.hard_state.clone(), wal.state.conf_state.clone());
+        mem.wl()
+            .restore(raft_state, wal.state.snapshot_metadata.clone(), entries);
+        Ok(WalStorage {
+            mem,
+            wal: Arc::new(Mutex::new(wal)),
+        })
+    }
+
+    fn wal(&self) -> MutexGuard<'_, Wal> {
+        self.wal.lock().unwrap()
+    }
+
+    /// Saves the current HardState.
+    pub fn set_hardstate(&self, hs: HardState) -> Result<()> {
+        let mut wal = self.wal();
+        let mut state = wal.state.clone();
+        state.hard_state = hs.clone();
+        wal.save_state(state)?;
+        self.mem.wl().set_hardstate(hs);
+        Ok(())
+    }
+
+    /// Saves the current conf state.
+    pub fn set_conf_state(&self, cs: ConfState) -> Result<()> {
+        let mut wal = self.wal();
+        let mut state = wal.state.clone();
+        state.conf_state = cs.clone();
+        wal.save_state(state)?;
+        self.mem.wl().set_conf_state(cs);
+        Ok(())
+    }
+
+    /// Overwrites the log with the given snapshot, see
+    /// `MemStorageCore::apply_snapshot`. All segments are deleted.
+    pub fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        let mut wal = self.wal();
+        let meta = snapshot.get_metadata();
+        if self.mem.first_index()? > meta.index {
+            return Err(Error::Store(StorageError::SnapshotOutOfDate));
+        }
+
+        let mut state = wal.state.clone();
+        state.hard_state.term = cmp::max(state.hard_state.term, meta.term);
+        state.hard_state.commit = meta.index;
+        state.conf_state = meta.get_conf_state().clone();
+        state.snapshot_metadata = meta.clone();
+        state.first_index = meta.index + 1;
+        wal.reset(state)?;
+        self.mem.wl().apply_snapshot(snapshot)
+    }
+
+    /// Discards all log entries prior to compact_index, see
+    /// `MemStorageCore::compact`. Segments are deleted once all of their
+    /// entries are discarded.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `compact_index` is higher than `Storage::last_index(&self) + 1`.
+    pub fn compact(&self, compact_index: u64) -> Result<()> {
+        let mut wal = self.wal();
+        if compact_index <= self.mem.first_index()? {
+            return Ok(());
+        }
+        let last_index = self.mem.last_index()?;
+        if compact_index > last_index + 1 {
+            panic!(
+                "compact not received raft logs: {}, last index: {}",
+                compact_index, last_index
+            );
+        }
+        wal.compact(compact_index)?;
+        self.mem.wl().compact(compact_index)
+    }
+
+    /// Append the new entries to storage.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ents` contains compacted entries, or there's a gap between `ents` and the last
+    /// received entry in the storage.
+    pub fn append(&self, ents: &[Entry]) -> Result<()> {
+        if ents.is_empty() {
+            return Ok(());
+        }
+        let mut wal = self.wal();
+        let (first_index, last_index) = (self.mem.first_index()?, self.mem.last_index()?);
+        if first_index > ents[0].index {
+            panic!(
+                "overwrite compacted raft logs, compacted: {}, append: {}",
+                first_index - 1,
+                ents[0].index,
+            );
+        }
+        if last_index + 1 < ents[0].index {
+            panic!(
+                "raft logs should be continuous, last index: {}, new appended: {}",
+                last_index, ents[0].index,
+            );
+        }
+
+        // Compacting the whole log moves the first index back to the snapshot,
+        // entries may be appended from there again. The segments only hold
+        // compacted entries then, which must not be replayed over the new ones.
+        if ents[0].index < wal.state.first_index {
+            let mut state = wal.state.clone();
+            state.first_index = ents[0].index;
+            wal.reset(state)?;
+        }
+        wal.append(ents)?;
+        self.mem.wl().append(ents)
+    }
+
+    /// Syncs the appended entries to disk, only needed if `WalConfig::sync` is
+    /// off.
+    pub fn sync(&self) -> Result<()> {
+        self.wal().sync()
+    }
+}
+
+impl MutableStorage for WalStorage {
+    fn append(&self, ents: &[Entry]) -> Result<()> {
+        WalStorage::append(self, ents)
+    }
+
+    fn compact(&self, compact_index: u64) -> Result<()> {
+        WalStorage::compact(self, compact_index)
+    }
+
+    fn apply_snapshot(&self, snapshot: Snapshot) -> Result<()> {
+        WalStorage::apply_snapshot(self, snapshot)
+    }
+
+    fn set_hardstate(&self, hs: HardState) -> Result<()> {
+        WalStorage::set_hardstate(self, hs)
+    }
+}
+
+impl Storage for WalStorage {
+    /// Implements the Storage trait.
+    fn initial_state(&self) -> Result<RaftState> {
+        self.mem.initial_state()
+    }
+
+    /// Implements the Storage trait.
+    fn entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+        context: GetEntriesContext,
+    ) -> Result<Vec<Entry>> {
+        self.mem.entries(low, high, max_size, context)
+    }
+
+    /// Implements the Storage trait.
+    fn term(&self, idx: u64) -> Result<u64> {
+        self.mem.term(idx)
+    }
+
+    /// Implements the Storage trait.
+    fn first_index(&self) -> Result<u64> {
+        self.mem.first_index()
+    }
+
+    /// Implements the Storage trait.
+    fn last_index(&self) -> Result<u64> {
+        self.mem.last_index()
+    }
+
+    /// Implements the Storage trait.
+    fn snapshot(&self, request_index: u64, to: u64) -> Result<Snapshot> {
+        self.mem.snapshot(request_index, to)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, OpenOptions};
+    use std::path::{Path, PathBuf};
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+    use tempfile::TempDir;
+
+    use crate::eraftpb::{Entry, HardState, Snapshot};
+    use crate::storage::{conformance, GetEntriesContext, Storage};
+
+    use super::{WalConfig, WalStorage, SEGMENT_SUFFIX, STATE_FILE};
+
+    fn new_entry(index: u64, term: u64) -> Entry {
+        let mut e = Entry::default();
+        e.term = term;
+        e.index = index;
+        e
+    }
+
+    fn new_snapshot(index: u64, term: u64, voters: Vec<u64>) -> Snapshot {
+        let mut s = Snapshot::default();
+        s.mut_metadata().index = index;
+        s.mut_metadata().term = term;
+        s.mut_metadata().mut_conf_state().voters = voters;
+        s
+    }
+
+    fn config(segment_size: u64) -> WalConfig {
+        WalConfig {
+            segment_size,
+            sync: false,
+        }
+    }
+
+    fn all_entries(storage: &WalStorage) -> Vec<Entry> {
+        let (first, last) = (
+            storage.first_index().unwrap(),
+            storage.last_index().unwrap(),
+        );
+        storage
+            .entries(first, last + 1, None, GetEntriesContext::empty(false))
+            .unwrap()
+    }
+
+    fn segments(dir: &Path) -> Vec<PathBuf> {
+        let mut paths: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|d| d.unwrap().path())
+            .filter(|p| p.to_str().unwrap().ends_with(SEGMENT_SUFFIX))
+            .collect();
+        paths.sort();
+        paths
+    }
+
+    fn truncate(path: &Path, len: u64) {
+        let file = OpenOptions::new().write(true).open(path).unwrap();
+        file.set_len(len).unwrap();
+    }
+
+    #[test]
+    fn test_wal_storage_conformance() {
+        let mut dirs = vec![];
+        let new_storage = || {
+            let dir = TempDir::new().unwrap();
+            let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+            dirs.push(dir);
+            storage
+        };
+        let reopen = |storage: WalStorage| {
+            let dir = storage.wal().dir.clone();
+            drop(storage);
+            WalStorage::open(dir, config(64)).unwrap()
+        };
+        conformance::run_all(conformance::reopening(new_storage, reopen));
+    }
+
+    #[test]
+    fn test_wal_storage_apply_snapshot() {
+        let nodes = vec![1, 2, 3];
+        let dir = TempDir::new().unwrap();
+        let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+        for index in 1..=20 {
+            storage.append(&[new_entry(index, 1)]).unwrap();
+        }
+        assert!(segments(dir.path()).len() > 1);
+
+        // Apply snapshot successfully
+        let snap = new_snapshot(4, 4, nodes.clone());
+        storage.apply_snapshot(snap).unwrap();
+        assert!(segments(dir.path()).is_empty());
+
+        // Apply snapshot fails due to StorageError::SnapshotOutOfDate
+        let snap = new_snapshot(3, 3, nodes.clone());
+        storage.apply_snapshot(snap).unwrap_err();
+
+        storage.append(&[new_entry(5, 4)]).unwrap();
+        let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+        assert_eq!(storage.first_index(), Ok(5));
+        assert_eq!(storage.last_index(), Ok(5));
+        assert_eq!(storage.term(4), Ok(4));
+        let state = storage.initial_state().unwrap();
+        assert_eq!(state.hard_state.commit, 4);
+        assert_eq!(state.conf_state.voters, nodes);
+    }
+
+    #[test]
+    fn test_wal_storage_compact_deletes_segments() {
+        let dir = TempDir::new().unwrap();
+        let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+        let ents: Vec<_> = (1..=100).map(|i| new_entry(i, 1)).collect();
+        for e in &ents {
+            storage.append(&[e.clone()]).unwrap();
+        }
+        // Overwrite a suffix, the stale entries stay in their segments.
+        let overwrite: Vec<_> = (80..=90).map(|i| new_entry(i, 2)).collect();
+        storage.append(&overwrite).unwrap();
+        let before = segments(dir.path()).len();
+
+        storage.compact(60).unwrap();
+        assert!(segments(dir.path()).len() < before);
+        let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+        assert_eq!(storage.first_index(), Ok(60));
+        let mut wents = ents[59..79].to_vec();
+        wents.extend(overwrite);
+        assert_eq!(all_entries(&storage), wents);
+
+        // Compacting everything moves the first index back, the segments
+        // with the stale entries must not be replayed over new ones.
+        storage.compact(91).unwrap();
+        assert_eq!(storage.first_index(), Ok(1));
+        storage.append(&[new_entry(1, 3)]).unwrap();
+        let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+        assert_eq!(all_entries(&storage), vec![new_entry(1, 3)]);
+    }
+
+    #[test]
+    fn test_wal_storage_recover_torn_tail() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for i in 0..50 {
+            let dir = TempDir::new().unwrap();
+            let storage = WalStorage::open(dir.path(), config(256)).unwrap();
+            let mut ents = vec![];
+            for index in 1..=40 {
+                let mut e = new_entry(index, 1);
+                e.data = vec![index as u8; rng.gen_range(0..32)].into();
+                storage.append(&[e.clone()]).unwrap();
+                ents.push(e);
+            }
+            let mut hs = HardState::default();
+            hs.commit = 10;
+            storage.set_hardstate(hs.clone()).unwrap();
+            drop(storage);
+
+            // A crash cuts the last segment anywhere in its last records.
+            let last = segments(dir.path()).pop().unwrap();
+            let name = last.file_name().unwrap().to_str().unwrap().to_owned();
+            let first = u64::from_str_radix(&name[17..33], 16).unwrap();
+            let len = fs::metadata(&last).unwrap().len();
+            truncate(&last, rng.gen_range(0..len));
+
+            let storage = WalStorage::open(dir.path(), config(256)).unwrap();
+            let last_index = storage.last_index().unwrap();
+            assert!(
+                last_index + 1 >= first && last_index < 40,
+                "#{}: last index {} of segment from {}",
+                i,
+                last_index,
+                first
+            );
+            assert_eq!(all_entries(&storage), ents[..last_index as usize], "#{}", i);
+            assert_eq!(storage.initial_state().unwrap().hard_state, hs);
+
+            // New entries follow the cut, not the torn record.
+            storage.append(&[new_entry(last_index + 1, 2)]).unwrap();
+            let storage = WalStorage::open(dir.path(), config(256)).unwrap();
+            assert_eq!(storage.last_index(), Ok(last_index + 1), "#{}", i);
+            assert_eq!(storage.term(last_index + 1), Ok(2), "#{}", i);
+        }
+    }
+
+    #[test]
+    fn test_wal_storage_detect_damage() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for _ in 0..20 {
+            let dir = TempDir::new().unwrap();
+            let storage = WalStorage::open(dir.path(), config(64)).unwrap();
+            for index in 1..=20 {
+                storage.append(&[new_entry(index, 1)]).unwrap();
+            }
+            storage.set_hardstate(HardState::default()).unwrap();
+            drop(storage);
+
+            // Only the last segment can be torn by a crash.
+            let first = segments(dir.path()).remove(0);
+            let len = fs::metadata(&first).unwrap().len();
+            truncate(&first, rng.gen_range(0..len));
+            assert!(WalStorage::open(dir.path(), config(64)).is_err());
+            fs::remove_file(&first).unwrap();
+
+            // The state file is replaced atomically, it's never torn.
+            let state = dir.path().join(STATE_FILE);
+            let len = fs::metadata(&state).unwrap().len();
+            truncate(&state, rng.gen_range(0..len));
+            assert!(WalStorage::open(dir.path(), config(64)).is_err());
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+//! Stand-in for the arcgraph logging crate, which isn't part of this tree. It
+//! only carries the macro sled_storage.rs logs with.
+
+pub use log;
+
+/// Logs a debug message of the raft module.
+#[macro_export]
+macro_rules! raft_debug {
+    ($($arg:tt)+) => {
+        $crate::log::debug!(target: "raft", $($arg)+)
+    };
+}
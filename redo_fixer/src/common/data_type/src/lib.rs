@@ -0,0 +1,48 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+//! Stand-in for the arcgraph data_type crate, which isn't part of this tree.
+//! It only carries the names sled_storage.rs uses, and the shapes of
+//! `ActionTypeLog` and `LogEntry` aren't the upstream ones: don't decode real
+//! entries with them.
+
+use serde::{Deserialize, Serialize};
+
+pub mod types {
+    pub type GraphId = u32;
+    pub type PartitionId = u32;
+    pub type TransactionId = u64;
+    pub type RaftPeerId = u64;
+}
+
+/// The kind of a record in the data of a raft entry. `Default` only exists so
+/// tests can build records that are neither.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActionTypeLog {
+    #[default]
+    Default,
+    StartTxn,
+    CommitTxn,
+    RollbackTxn,
+    SyncLSN,
+}
+
+/// A record in the data of a raft entry, with the fields sled_storage.rs reads.
+#[derive(Debug, Default, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub action_type: ActionTypeLog,
+    pub txn_id: types::TransactionId,
+    pub lsn: u64,
+}
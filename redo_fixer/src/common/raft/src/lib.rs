@@ -0,0 +1,26 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+#[cfg(feature = "stubs")]
+mod stubs;
+#[cfg(feature = "stubs")]
+pub use stubs::{error, storage, utils};
+
+#[cfg(not(feature = "stubs"))]
+compile_error!("the error, storage and utils modules aren't vendored here, build with the `stubs` feature");
+
+pub mod sled_storage;
+
+pub use error::Error;
@@ -23,62 +23,587 @@ use data_type::types::TransactionId;
 use data_type::types::RaftPeerId;
 use data_type::ActionTypeLog;
 use protobuf::Message;
-use sled::{Batch, Db};
-use std::cell::RefCell;
+use sled::transaction::Transactional;
+use sled::{Batch, Db, Tree};
 use std::cmp;
 use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard};
 use tikv_raft::prelude::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
 use tikv_raft::RaftState;
 use tikv_raft::Result as TiKvResult;
 use tikv_raft::Storage;
 
-const HARD_STATE: [u8; 8] = u64::MAX.to_le_bytes();
-const CONF_STATE: [u8; 8] = (u64::MAX - 1).to_le_bytes();
-const FIRST_INDEX: [u8; 8] = (u64::MAX - 2).to_le_bytes();
-const LAST_INDEX: [u8; 8] = (u64::MAX - 3).to_le_bytes();
+// Sled orders keys as raw bytes, so every key starts with a type prefix and
+// entry indexes are big-endian to keep entries in numeric order.
+const META_PREFIX: u8 = 0x00;
+const ENTRY_PREFIX: u8 = 0x01;
+
+const HARD_STATE: [u8; 2] = [META_PREFIX, 0x01];
+const CONF_STATE: [u8; 2] = [META_PREFIX, 0x02];
+const FIRST_INDEX: [u8; 2] = [META_PREFIX, 0x03];
+const LAST_INDEX: [u8; 2] = [META_PREFIX, 0x04];
+const SNAPSHOT_METADATA: [u8; 2] = [META_PREFIX, 0x05];
+const SNAPSHOT: [u8; 2] = [META_PREFIX, 0x06];
+const KEY_FORMAT: [u8; 2] = [META_PREFIX, 0xff];
+
+const KEY_FORMAT_VERSION: u8 = 1;
+
+// Keys written before KEY_FORMAT existed, all of them are little-endian u64.
+const LEGACY_HARD_STATE: u64 = u64::MAX;
+const LEGACY_CONF_STATE: u64 = u64::MAX - 1;
+const LEGACY_FIRST_INDEX: u64 = u64::MAX - 2;
+const LEGACY_LAST_INDEX: u64 = u64::MAX - 3;
+
+/// Decodes the entry stored under the key of `index`.
+pub fn parse_entry(index: u64, bytes: &[u8]) -> Result<Entry> {
+    let entry = Entry::parse_from_bytes(bytes).map_err(|e| SledStorageError::Corruption {
+        index,
+        reason: e.to_string(),
+    })?;
+    if entry.index != index {
+        return Err(SledStorageError::Corruption {
+            index,
+            reason: format!("entry has index {}", entry.index),
+        }
+        .into());
+    }
+    Ok(entry)
+}
+
+/// Decodes the value of `RawKey::FirstIndex` or `RawKey::LastIndex`.
+pub fn parse_index_marker(bytes: &[u8]) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.try_into().ok()?))
+}
+
+/// Encodes the value of `RawKey::FirstIndex` or `RawKey::LastIndex`.
+pub fn encode_index_marker(index: u64) -> [u8; 8] {
+    index.to_le_bytes()
+}
+
+/// The keys of a store, for offline tools that check or repair it.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RawKey {
+    HardState,
+    ConfState,
+    FirstIndex,
+    LastIndex,
+    SnapshotMetadata,
+    Snapshot,
+    KeyFormat,
+    Entry(u64),
+    /// A key no version of `SledStorage` writes.
+    Unknown(Vec<u8>),
+}
+
+impl RawKey {
+    pub fn decode(key: &[u8]) -> RawKey {
+        if key.len() == 9 && key[0] == ENTRY_PREFIX {
+            return RawKey::Entry(u64::from_be_bytes(*array_ref![key, 1, 8]));
+        }
+        match key {
+            k if k == HARD_STATE => RawKey::HardState,
+            k if k == CONF_STATE => RawKey::ConfState,
+            k if k == FIRST_INDEX => RawKey::FirstIndex,
+            k if k == LAST_INDEX => RawKey::LastIndex,
+            k if k == SNAPSHOT_METADATA => RawKey::SnapshotMetadata,
+            k if k == SNAPSHOT => RawKey::Snapshot,
+            k if k == KEY_FORMAT => RawKey::KeyFormat,
+            k => RawKey::Unknown(k.to_vec()),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            RawKey::HardState => HARD_STATE.to_vec(),
+            RawKey::ConfState => CONF_STATE.to_vec(),
+            RawKey::FirstIndex => FIRST_INDEX.to_vec(),
+            RawKey::LastIndex => LAST_INDEX.to_vec(),
+            RawKey::SnapshotMetadata => SNAPSHOT_METADATA.to_vec(),
+            RawKey::Snapshot => SNAPSHOT.to_vec(),
+            RawKey::KeyFormat => KEY_FORMAT.to_vec(),
+            RawKey::Entry(index) => entry_key(*index).to_vec(),
+            RawKey::Unknown(key) => key.clone(),
+        }
+    }
+}
+
+fn entry_key(index: u64) -> [u8; 9] {
+    let mut key = [ENTRY_PREFIX; 9];
+    key[1..].copy_from_slice(&index.to_be_bytes());
+    key
+}
+
+/// Tracks the transactions started and finished by `entry`.
+fn track_active_txns(txns: &mut HashMap<TransactionId, u64>, entry: &Entry) {
+    let log_entries = convert_to_log_entries(entry);
+    for log_entry in log_entries.into_iter() {
+        if matches!(log_entry.action_type, ActionTypeLog::StartTxn) {
+            txns.insert(log_entry.txn_id, entry.index);
+        } else if matches!(
+            log_entry.action_type,
+            ActionTypeLog::CommitTxn | ActionTypeLog::RollbackTxn
+        ) {
+            txns.remove(&log_entry.txn_id);
+        }
+        // else if matches!(log_entry.action_type, ActionTypeLog::SyncLSN){
+        //     let sled_util = SledUtil::instance();
+        //     let mut key = vec![];
+        //     key.extend_from_slice(self.graph_id.to_le_bytes().as_ref());
+        //     key.extend_from_slice(self.partition_id.to_le_bytes().as_ref());
+        //     let value = log_entry.lsn.to_be_bytes();
+        //     let _ = sled_util.insert(&key[..], sled::IVec::from(&value));
+        //     let _ = sled_util.flush();
+        // }
+    }
+}
+
+/// Rewrites a store in the legacy little-endian key layout to the current one.
+/// It is a no-op for stores that are already migrated.
+fn migrate_key_layout(db: &Tree) -> Result<()> {
+    if db.contains_key(KEY_FORMAT)? {
+        return Ok(());
+    }
+    let mut batch = Batch::default();
+    let mut migrated = 0;
+    for kv in db.iter() {
+        let (key, value) = kv?;
+        if key.len() != 8 {
+            continue;
+        }
+        let new_key = match u64::from_le_bytes(*array_ref![key, 0, 8]) {
+            LEGACY_HARD_STATE => HARD_STATE.to_vec(),
+            LEGACY_CONF_STATE => CONF_STATE.to_vec(),
+            LEGACY_FIRST_INDEX => FIRST_INDEX.to_vec(),
+            LEGACY_LAST_INDEX => LAST_INDEX.to_vec(),
+            index => entry_key(index).to_vec(),
+        };
+        batch.remove(key);
+        batch.insert(new_key, value);
+        migrated += 1;
+    }
+    batch.insert(&KEY_FORMAT, &[KEY_FORMAT_VERSION]);
+    db.apply_batch(batch)?;
+    db.flush()?;
+    if migrated > 0 {
+        arcgraph_log::raft_debug!("migrated {} keys to the big-endian key layout", migrated);
+    }
+    Ok(())
+}
+
+/// Migrates every store under `log_folder`, or the default sled folder, to the
+/// current key layout. Stores are also migrated when they are opened, this is
+/// for migrating all of them at once, e.g. before an upgrade is rolled out.
+pub fn migrate_stores(log_folder: Option<String>) -> Result<usize> {
+    let folder = log_folder.unwrap_or_else(|| String::from("/tmp/raft_log/sled"));
+    let mut count = 0;
+    for dir in std::fs::read_dir(folder).map_err(|e| Error::Other(Box::new(e)))? {
+        let path = dir.map_err(|e| Error::Other(Box::new(e)))?.path();
+        if path.is_dir() {
+            let db = sled::open(path)?;
+            migrate_key_layout(&db)?;
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Errors of `SledStorage`. They are carried by `Error::Other`, and mapped to
+/// the matching `tikv_raft::StorageError` when raft asks for them.
+#[derive(Debug, PartialEq, thiserror::Error)]
+pub enum SledStorageError {
+    #[error("index {index} is compacted, first index is {first_index}")]
+    Compacted { index: u64, first_index: u64 },
+    #[error("index {index} is unavailable, last index is {last_index}")]
+    Unavailable { index: u64, last_index: u64 },
+    #[error("snapshot at {index} is out of date, first index is {first_index}")]
+    SnapshotOutOfDate { index: u64, first_index: u64 },
+    #[error("raft logs should be continuous, last index: {last_index}, new appended: {index}")]
+    Discontinuous { index: u64, last_index: u64 },
+    #[error("can't compact to {index}, past the checkpoint index {checkpoint}")]
+    PastCheckpoint { index: u64, checkpoint: u64 },
+    /// The stored data is broken, retrying won't help.
+    #[error("raft log is corrupted at {index}: {reason}")]
+    Corruption { index: u64, reason: String },
+}
+
+impl From<SledStorageError> for Error {
+    fn from(e: SledStorageError) -> Self {
+        Error::Other(Box::new(e))
+    }
+}
+
+/// Packs and restores the application state carried by snapshots.
+pub trait SnapshotProvider: Send + Sync {
+    /// Returns the application state at `index`, or a reference to chunks the
+    /// receiver can fetch it from. It fails if `index` isn't applied yet.
+    fn pack(&self, index: u64) -> Result<Vec<u8>>;
+
+    /// Replaces the application state with the data packed by `pack`.
+    fn restore(&self, meta: &SnapshotMetadata, data: &[u8]) -> Result<()>;
+}
 
 #[allow(dead_code)]
 pub struct SledStorage {
     graph_id: u32,
     partition_id: u32,
-    core: Db,
-    active_txns: RefCell<HashMap<TransactionId, u64>>,
-    snapshot_metadata: SnapshotMetadata,
+    core: Tree,
+    // Start indexes of the transactions not committed or rolled back yet. The
+    // lock is held while entries are written, so a checkpoint never sees the
+    // entries without the transactions they start.
+    active_txns: Mutex<HashMap<TransactionId, u64>>,
+    snapshot_metadata: RwLock<SnapshotMetadata>,
+    snapshot_provider: Option<Arc<dyn SnapshotProvider>>,
+}
+
+/// Keeps the raft logs of many groups in one sled database, so they share the
+/// page cache, the flusher thread and the file handles. The log of each group
+/// lives in its own tree.
+#[derive(Clone)]
+pub struct SledEngine {
+    db: Db,
+}
+
+impl SledEngine {
+    pub fn open(path: &str) -> Result<Self> {
+        Ok(Self {
+            db: sled::open(path)?,
+        })
+    }
+
+    /// Opens the log of a group, creating it if it doesn't exist.
+    pub fn storage(
+        &self,
+        graph_id: GraphId,
+        partition_id: PartitionId,
+        peer_id: RaftPeerId,
+    ) -> Result<SledStorage> {
+        let tree = self
+            .db
+            .open_tree(format!("{graph_id}_{partition_id}_{peer_id}"))?;
+        SledStorage::with_tree(graph_id, partition_id, tree)
+    }
+
+    /// Writes `batch` atomically, either all groups in it are updated or none.
+    pub fn write(&self, batch: SledWriteBatch<'_>) -> Result<()> {
+        if batch.writes.is_empty() {
+            return Ok(());
+        }
+        let trees: Vec<Tree> = batch.writes.iter().map(|w| w.storage.core.clone()).collect();
+        // Lock the groups in address order, so concurrent writes can't deadlock.
+        let mut writes: Vec<&GroupWrite<'_>> = batch.writes.iter().collect();
+        writes.sort_by_key(|w| w.storage as *const SledStorage as usize);
+        let mut locked: Vec<_> = writes
+            .iter()
+            .map(|w| (w, w.storage.active_txns()))
+            .collect();
+        trees.as_slice().transaction(|txs| {
+            for (tx, w) in txs.iter().zip(batch.writes.iter()) {
+                tx.apply_batch(&w.batch)?;
+            }
+            Ok(())
+        })?;
+        for (w, txns) in locked.iter_mut() {
+            for entry in w.entries.iter() {
+                track_active_txns(txns, entry);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+struct GroupWrite<'a> {
+    storage: &'a SledStorage,
+    batch: Batch,
+    entries: &'a [Entry],
 }
 
-unsafe impl Sync for SledStorage {}
-unsafe impl Send for SledStorage {}
+/// Writes to the logs of several groups of one `SledEngine`, applied atomically
+/// by `SledEngine::write`. It's meant for persisting the readies of all groups
+/// handled in one round of a multi-raft driver.
+#[derive(Default)]
+pub struct SledWriteBatch<'a> {
+    writes: Vec<GroupWrite<'a>>,
+}
+
+impl<'a> SledWriteBatch<'a> {
+    fn group(&mut self, storage: &'a SledStorage) -> &mut GroupWrite<'a> {
+        let pos = self
+            .writes
+            .iter()
+            .position(|w| std::ptr::eq(w.storage, storage));
+        let pos = pos.unwrap_or_else(|| {
+            self.writes.push(GroupWrite {
+                storage,
+                batch: Batch::default(),
+                entries: &[],
+            });
+            self.writes.len() - 1
+        });
+        &mut self.writes[pos]
+    }
+
+    /// Appends `entries` to the log of `storage`. A batch can append to the
+    /// same group only once, as a ready has at most one run of entries, a
+    /// second append fails.
+    pub fn append(&mut self, storage: &'a SledStorage, entries: &'a [Entry]) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        let write = self.group(storage);
+        if !write.entries.is_empty() {
+            return Err(Error::Msg(format!(
+                "entries of group {}_{} are appended twice in one batch",
+                storage.graph_id, storage.partition_id
+            )));
+        }
+        storage.append_batch(entries, &mut write.batch)?;
+        write.entries = entries;
+        Ok(())
+    }
+
+    pub fn set_hard_state(&mut self, storage: &'a SledStorage, hard_state: &HardState) -> Result<()> {
+        let bytes = hard_state.write_to_bytes()?;
+        self.group(storage).batch.insert(&HARD_STATE, bytes);
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.writes.is_empty()
+    }
+}
 
 impl SledStorage {
-    pub fn new(graph_id: GraphId, partition_id: PartitionId, peer_id: RaftPeerId, log_folder: Option<String>) -> Self {
+    pub fn new(graph_id: GraphId, partition_id: PartitionId, peer_id: RaftPeerId, log_folder: Option<String>) -> Result<Self> {
         let file_path = if let Some(folder) = log_folder {
             format!("{folder}/{graph_id}_{partition_id}_{peer_id}")
         } else {
             format!("/tmp/raft_log/sled/{graph_id}_{partition_id}_{peer_id}")
         };
-        Self {
+        let db = sled::open(file_path)?;
+        Self::with_tree(graph_id, partition_id, Tree::clone(&db))
+    }
+
+    /// Opens the log at `path` for offline tools. Unlike `new`, opening never
+    /// changes the log: a missing store or one in the legacy key layout is
+    /// refused instead of being created or migrated. Active transactions aren't
+    /// replayed until `rebuild_active_txns` is called, so a damaged log can
+    /// still be opened. Sled has no read-only mode though, it may still rewrite
+    /// its own files, e.g. when recovering them, so copy the directory first if
+    /// it has to stay untouched.
+    pub fn open_offline(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if !path.is_dir() {
+            return Err(Error::Msg(format!("{} is not a raft log", path.display())));
+        }
+        let db = sled::Config::new().path(path).open()?;
+        if !db.contains_key(KEY_FORMAT)? && !db.is_empty() {
+            return Err(Error::Msg(format!(
+                "{} is in the legacy key layout, migrate it first",
+                path.display()
+            )));
+        }
+        // Stores opened by `new` are named after their group.
+        let name = path.file_name().map(|n| n.to_string_lossy().into_owned());
+        let mut ids = name.iter().flat_map(|n| n.split('_')).map(|id| id.parse().unwrap_or(0));
+        let graph_id = ids.next().unwrap_or(0);
+        let partition_id = ids.next().unwrap_or(0);
+        Self::from_tree(graph_id, partition_id, Tree::clone(&db))
+    }
+
+    /// Creates an empty log at `path` for offline tools, e.g. to import a log
+    /// into. It fails if `path` already exists.
+    pub fn create_offline(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(Error::Msg(format!("{} already exists", path.display())));
+        }
+        let db = sled::Config::new().path(path).open()?;
+        migrate_key_layout(&db)?;
+        Self::from_tree(0, 0, Tree::clone(&db))
+    }
+
+    /// Creates an empty log in a temporary directory, which is removed when the
+    /// store is dropped. It's for offline tools, e.g. to check a log that isn't
+    /// kept.
+    pub fn create_temporary() -> Result<Self> {
+        let db = sled::Config::new().temporary(true).open()?;
+        migrate_key_layout(&db)?;
+        Self::from_tree(0, 0, Tree::clone(&db))
+    }
+
+    fn with_tree(graph_id: GraphId, partition_id: PartitionId, core: Tree) -> Result<Self> {
+        migrate_key_layout(&core)?;
+        let storage = Self::from_tree(graph_id, partition_id, core)?;
+        storage.rebuild_active_txns()?;
+        Ok(storage)
+    }
+
+    fn from_tree(graph_id: GraphId, partition_id: PartitionId, core: Tree) -> Result<Self> {
+        let mut storage = Self {
             graph_id,
             partition_id,
-            core: sled::open(file_path).unwrap(),
-            active_txns: RefCell::default(),
-            snapshot_metadata: SnapshotMetadata::default(),
+            core,
+            active_txns: Mutex::default(),
+            snapshot_metadata: RwLock::default(),
+            snapshot_provider: None,
+        };
+        storage.load_snapshot_metadata()?;
+        Ok(storage)
+    }
+
+    /// Iterates over every key of the store in key order, values undecoded.
+    pub fn raw_scan(&self) -> impl Iterator<Item = Result<(RawKey, sled::IVec)>> + '_ {
+        self.core.iter().map(|kv| {
+            let (key, value) = kv?;
+            Ok((RawKey::decode(&key), value))
+        })
+    }
+
+    pub fn raw_get(&self, key: &RawKey) -> Result<Option<sled::IVec>> {
+        Ok(self.core.get(key.encode())?)
+    }
+
+    /// Sets or, for `None` values, removes the keys atomically, and flushes.
+    /// Nothing is validated, it's meant for repairing damaged stores.
+    pub fn raw_write(&self, writes: &[(RawKey, Option<Vec<u8>>)]) -> Result<()> {
+        let mut batch = Batch::default();
+        for (key, value) in writes {
+            match value {
+                Some(value) => batch.insert(key.encode(), value.as_slice()),
+                None => batch.remove(key.encode()),
+            }
         }
+        self.core.apply_batch(batch)?;
+        self.core.flush()?;
+        Ok(())
+    }
+
+    /// Rewrites the log of the last surviving replica of a group, so it can be
+    /// started as a new group of `voters` after a majority of the replicas is
+    /// lost for good. Same as `MemStorageCore::force_new_cluster`: entries past
+    /// the commit index are dropped and the term is bumped. Entries the lost
+    /// replicas had committed may be gone, and the node must be started with
+    /// `Config::applied` at the commit index.
+    pub fn force_new_cluster(&mut self, voters: Vec<u64>) -> Result<()> {
+        if voters.is_empty() {
+            return Err(Error::Msg(String::from("a group needs at least one voter")));
+        }
+        let mut hard_state = self.get_hard_state()?.unwrap_or_default();
+        let first_index = self.get_first_index()?;
+        let last_index = self.get_last_index()?;
+        let commit = hard_state.commit;
+        hard_state.term += 1;
+        hard_state.vote = 0;
+        let conf_state = ConfState {
+            voters,
+            ..Default::default()
+        };
+
+        let mut batch = Batch::default();
+        for index in cmp::max(commit + 1, first_index)..=last_index {
+            batch.remove(&entry_key(index));
+        }
+        if last_index > commit {
+            batch.insert(&LAST_INDEX, &commit.to_le_bytes());
+        }
+        batch.insert(&HARD_STATE, hard_state.write_to_bytes()?);
+        batch.insert(&CONF_STATE, conf_state.write_to_bytes()?);
+        // `snapshot` would hand out the old membership to new peers otherwise.
+        // The stored snapshot is dropped, the next one is packed with the new
+        // conf state.
+        let mut snapshot_metadata = self.get_snapshot_metadata();
+        snapshot_metadata.set_conf_state(conf_state.clone());
+        batch.insert(&SNAPSHOT_METADATA, snapshot_metadata.write_to_bytes()?);
+        batch.remove(&SNAPSHOT);
+        self.core.apply_batch(batch)?;
+        self.core.flush()?;
+        *self
+            .snapshot_metadata
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = snapshot_metadata;
+        arcgraph_log::raft_debug!(
+            "forced a new cluster, hard_state={:?}, conf_state={:?}",
+            hard_state,
+            conf_state
+        );
+        // Transactions started by the dropped entries are gone.
+        self.rebuild_active_txns()
+    }
+
+    /// Returns the metadata of the last snapshot applied to the log.
+    pub fn get_snapshot_metadata(&self) -> SnapshotMetadata {
+        self.snapshot_metadata().clone()
+    }
+
+    /// Returns the active transactions with the indexes they started at, in
+    /// index order.
+    pub fn get_active_txns(&self) -> Vec<(TransactionId, u64)> {
+        let mut txns: Vec<_> = self.active_txns().iter().map(|(t, i)| (*t, *i)).collect();
+        txns.sort_by_key(|(_, index)| *index);
+        txns
+    }
+
+    /// Sets the provider packing the application state into snapshots. Without
+    /// one, snapshots only carry the data given to `create_snapshot`.
+    pub fn set_snapshot_provider(&mut self, provider: Arc<dyn SnapshotProvider>) {
+        self.snapshot_provider = Some(provider);
+    }
+
+    fn active_txns(&self) -> MutexGuard<'_, HashMap<TransactionId, u64>> {
+        // Every update leaves the map consistent, so a panic elsewhere can't
+        // break it.
+        self.active_txns.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn snapshot_metadata(&self) -> RwLockReadGuard<'_, SnapshotMetadata> {
+        self.snapshot_metadata
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
     }
 
     fn build_tikv_error(&self, e: Error) -> tikv_raft::Error {
-        tikv_raft::Error::Store(tikv_raft::StorageError::Other(Box::new(e)))
+        use tikv_raft::StorageError;
+        let e = match e {
+            Error::Other(e) => match e.downcast::<SledStorageError>() {
+                Ok(e) => match *e {
+                    SledStorageError::Compacted { .. } => StorageError::Compacted,
+                    SledStorageError::Unavailable { .. } => StorageError::Unavailable,
+                    SledStorageError::SnapshotOutOfDate { .. } => StorageError::SnapshotOutOfDate,
+                    e => StorageError::Other(Box::new(e)),
+                },
+                Err(e) => StorageError::Other(Box::new(Error::Other(e))),
+            },
+            e => StorageError::Other(Box::new(e)),
+        };
+        tikv_raft::Error::Store(e)
     }
 
     fn get_entry(&self, index: u64) -> Result<Option<Entry>> {
-        let bytes_entry = self.core.get(index.to_le_bytes())?;
+        let bytes_entry = self.core.get(entry_key(index))?;
         if let Some(bytes) = bytes_entry {
-            let entry = Entry::parse_from_bytes(&bytes)?;
-            Ok(Some(entry))
+            Ok(Some(parse_entry(index, &bytes)?))
         } else {
             Ok(None)
         }
     }
 
+    fn get_index_marker(&self, key: [u8; 2], name: &str) -> Result<Option<u64>> {
+        let Some(bytes) = self.core.get(key)? else {
+            return Ok(None);
+        };
+        let index = parse_index_marker(&bytes).ok_or_else(|| SledStorageError::Corruption {
+            index: self.snapshot_metadata().index,
+            reason: format!("{name} marker has {} bytes", bytes.len()),
+        })?;
+        Ok(Some(index))
+    }
+
     fn insert_hard_state(&self, hard_state: &HardState) -> Result<()> {
         let bytes = hard_state.write_to_bytes()?;
         self.core.insert(HARD_STATE, bytes)?;
@@ -91,31 +616,140 @@ impl SledStorage {
         Ok(())
     }
 
-    fn get_minimum_index_active_txn(&self) -> Option<u64> {
-        if self.active_txns.borrow().is_empty() {
-            None
+    fn get_snapshot(&self) -> Result<Option<Snapshot>> {
+        if let Some(bytes) = self.core.get(SNAPSHOT)? {
+            Ok(Some(Snapshot::parse_from_bytes(&bytes)?))
         } else {
-            let mut r = u64::MAX;
-            for entry_idx in self.active_txns.borrow().values() {
-                if *entry_idx < r {
-                    r = *entry_idx;
-                }
+            Ok(None)
+        }
+    }
+
+    fn insert_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+        let bytes = snapshot.write_to_bytes()?;
+        self.core.insert(SNAPSHOT, bytes)?;
+        Ok(())
+    }
+
+    /// Builds the metadata of a snapshot at the commit index.
+    fn build_snapshot_metadata(&self) -> Result<SnapshotMetadata> {
+        let missing = |what: &str| SledStorageError::Corruption {
+            index: self.snapshot_metadata().index,
+            reason: format!("{what} is missing"),
+        };
+        let hard_state = self.get_hard_state()?.ok_or_else(|| missing("hard state"))?;
+        let conf_state = self.get_conf_state()?.ok_or_else(|| missing("conf state"))?;
+
+        let mut meta = SnapshotMetadata::default();
+        meta.index = hard_state.get_commit();
+        meta.term = self.get_term(meta.index)?;
+        meta.set_conf_state(conf_state);
+        Ok(meta)
+    }
+
+    fn load_snapshot_metadata(&mut self) -> Result<()> {
+        if let Some(bytes) = self.core.get(SNAPSHOT_METADATA)? {
+            *self
+                .snapshot_metadata
+                .get_mut()
+                .unwrap_or_else(PoisonError::into_inner) =
+                SnapshotMetadata::parse_from_bytes(&bytes)?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the active transactions by replaying the log from the first
+    /// retained entry. `compact` refuses to compact past the checkpoint index,
+    /// so every transaction still active at the checkpoint is seen again.
+    pub fn rebuild_active_txns(&self) -> Result<()> {
+        let mut txns = self.active_txns();
+        txns.clear();
+        let first_index = self.get_first_index()?;
+        let last_index = self.get_last_index()?;
+        if first_index > last_index {
+            return Ok(());
+        }
+        for kv in self.core.range(entry_key(first_index)..=entry_key(last_index)) {
+            let (_, bytes) = kv?;
+            let entry = Entry::parse_from_bytes(&bytes)?;
+            track_active_txns(&mut txns, &entry);
+        }
+        arcgraph_log::raft_debug!(
+            "rebuilt active txns from index {}: {:?}",
+            first_index,
+            *txns
+        );
+        Ok(())
+    }
+
+    /// Puts the writes appending `entries` into `batch`, including removing the
+    /// conflicting entries after them.
+    fn append_batch(&self, entries: &[Entry], batch: &mut Batch) -> Result<()> {
+        let first_index = self.get_first_index()?;
+        let last_index = self.get_last_index()?;
+        arcgraph_log::raft_debug!(
+            "append first_index={}, last_index={}",
+            first_index,
+            last_index
+        );
+        if first_index > entries[0].index {
+            return Err(SledStorageError::Compacted {
+                index: entries[0].index,
+                first_index,
+            }
+            .into());
+        }
+
+        if last_index + 1 < entries[0].index {
+            return Err(SledStorageError::Discontinuous {
+                index: entries[0].index,
+                last_index,
             }
-            Some(r)
+            .into());
+        }
+
+        for entry in entries.iter() {
+            batch.insert(
+                &entry_key(entry.index),
+                entry.write_to_bytes()?,
+            );
         }
+
+        let del_last_idx = last_index;
+        let del_start_idx = entries.last().unwrap().index + 1;
+        arcgraph_log::raft_debug!(
+            "append del start={}, end={}",
+            del_start_idx,
+            del_last_idx
+        );
+        for key in del_start_idx..=del_last_idx {
+            batch.remove(&entry_key(key));
+        }
+
+        let new_last_idx = del_start_idx - 1;
+        batch.insert(&LAST_INDEX, &new_last_idx.to_le_bytes());
+        Ok(())
     }
 }
 
 impl LogStore for SledStorage {
     fn reset(&self) -> Result<()> {
         self.core.clear()?;
+        self.core.insert(KEY_FORMAT, &[KEY_FORMAT_VERSION])?;
+        self.active_txns().clear();
+        *self
+            .snapshot_metadata
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = SnapshotMetadata::default();
         Ok(())
     }
 
     fn get_checkpoint_idx(&self) -> Option<u64> {
+        // Read the commit index under the lock, so it can't cover entries whose
+        // transactions aren't tracked yet.
+        let txns = self.active_txns();
         let hard_state = self.get_hard_state();
         if let Ok(Some(hs)) = hard_state {
-            let active_txn_idx = self.get_minimum_index_active_txn();
+            let active_txn_idx = txns.values().min().copied();
             let commit_idx = hs.get_commit();
             if let Some(idx) = active_txn_idx {
                 Some(cmp::min(idx, commit_idx))
@@ -128,35 +762,53 @@ impl LogStore for SledStorage {
     }
 
     fn get_first_index(&self) -> Result<u64> {
-        let bytes_first_index = self.core.get(&FIRST_INDEX)?;
-        if let Some(bytes) = bytes_first_index {
-            let tmp = bytes.to_vec();
-            Ok(u64::from_le_bytes(*array_ref![tmp.as_slice(), 0, 8]))
-        } else {
-            Ok(self.snapshot_metadata.index + 1)
+        match self.get_index_marker(FIRST_INDEX, "first index")? {
+            Some(index) => Ok(index),
+            None => Ok(self.snapshot_metadata().index + 1),
         }
     }
 
     fn get_last_index(&self) -> Result<u64> {
-        let bytes_last_index = self.core.get(&LAST_INDEX)?;
-        if let Some(bytes) = bytes_last_index {
-            let tmp = bytes.to_vec();
-            Ok(u64::from_le_bytes(*array_ref![tmp.as_slice(), 0, 8]))
-        } else {
-            Ok(self.snapshot_metadata.index)
+        match self.get_index_marker(LAST_INDEX, "last index")? {
+            Some(index) => Ok(index),
+            None => Ok(self.snapshot_metadata().index),
         }
     }
 
     fn get_term(&self, idx: u64) -> Result<u64> {
+        let (snapshot_index, snapshot_term) = {
+            let meta = self.snapshot_metadata();
+            (meta.index, meta.term)
+        };
         let term = if let Some(entry) = self.get_entry(idx)? {
             entry.term
+        } else if idx == snapshot_index {
+            snapshot_term
         } else {
-            self.snapshot_metadata.term
+            let first_index = self.get_first_index()?;
+            let last_index = self.get_last_index()?;
+            let e = if idx < first_index {
+                SledStorageError::Compacted {
+                    index: idx,
+                    first_index,
+                }
+            } else if idx > last_index {
+                SledStorageError::Unavailable {
+                    index: idx,
+                    last_index,
+                }
+            } else {
+                SledStorageError::Corruption {
+                    index: idx,
+                    reason: String::from("entry is missing"),
+                }
+            };
+            return Err(e.into());
         };
         arcgraph_log::raft_debug!(
             "get_term, idx={}, metadata.index={}, term={}",
             idx,
-            self.snapshot_metadata.index,
+            snapshot_index,
             term
         );
         Ok(term)
@@ -176,43 +828,54 @@ impl LogStore for SledStorage {
             first_index
         );
         if low < first_index {
-            return Err(Error::Msg(String::from("Compacted")));
+            return Err(SledStorageError::Compacted {
+                index: low,
+                first_index,
+            }
+            .into());
         }
         let last_index = self.get_last_index()?;
         if high > last_index + 1 {
-            panic!(
-                "index out of bound (last: {}, high: {})",
-                last_index + 1,
-                high
-            );
-        }
-
-        let real_high = if let Some(size) = max_size.into() {
-            if size < high - low {
-                low + size + 1
-            } else {
-                high
+            return Err(SledStorageError::Unavailable {
+                index: high - 1,
+                last_index,
             }
-        } else {
-            high
-        };
-        arcgraph_log::raft_debug!("get_entries, start={}, end={}", low, real_high);
+            .into());
+        }
 
-        let start: &[u8] = &low.to_le_bytes();
-        let end: &[u8] = &real_high.to_le_bytes();
-        let r = self.core.range(start..end);
+        // Like `MemStorage`, `max_size` is a budget in bytes, and at least one
+        // entry is returned even if it's larger than the budget.
+        let max_size = max_size.into();
+        let r = self.core.range(entry_key(low)..entry_key(high));
         let mut entries = vec![];
+        let mut size = 0;
+        let mut limited = false;
         for bytes_entry in r {
             let bytes = bytes_entry?;
-            // entries.push(Entry::parse_from_bytes(&bytes.1).unwrap());
-            if let Ok(entry) = Entry::parse_from_bytes(&bytes.1) {
-                entries.push(entry);
-            } else {
-                panic!(
-                    "entry parse from bytes error, bytes content is {:?}",
-                    &bytes.1
-                );
+            let expected = low + entries.len() as u64;
+            let index = u64::from_be_bytes(*array_ref![bytes.0, 1, 8]);
+            if index != expected {
+                return Err(SledStorageError::Corruption {
+                    index: expected,
+                    reason: String::from("entry is missing"),
+                }
+                .into());
+            }
+            let entry = parse_entry(index, &bytes.1)?;
+            size += u64::from(entry.compute_size());
+            if !entries.is_empty() && max_size.is_some_and(|max| size > max) {
+                limited = true;
+                break;
             }
+            entries.push(entry);
+        }
+        let real_high = low + entries.len() as u64;
+        if real_high < high && !limited {
+            return Err(SledStorageError::Corruption {
+                index: real_high,
+                reason: String::from("entry is missing"),
+            }
+            .into());
         }
         arcgraph_log::raft_debug!(
             "get entries={:?}, low={:?}, high={:?}",
@@ -228,92 +891,22 @@ impl LogStore for SledStorage {
         if entries.is_empty() {
             Ok(())
         } else {
-            let first_index = self.get_first_index()?;
-            let last_index = self.get_last_index()?;
-            arcgraph_log::raft_debug!(
-                "append first_index={}, last_index={}",
-                first_index,
-                last_index
-            );
-            arcgraph_log::raft_debug!("append entries={:?}", entries);
-            if first_index > entries[0].index {
-                panic!(
-                    "overwrite compacted raft logs, compacted: {}, append: {}",
-                    first_index - 1,
-                    entries[0].index,
-                );
+            let mut batch = Batch::default();
+            self.append_batch(entries, &mut batch)?;
+            let mut txns = self.active_txns();
+            self.core.apply_batch(batch)?;
+            for entry in entries.iter() {
+                track_active_txns(&mut txns, entry);
             }
-
-            if last_index + 1 < entries[0].index {
-                panic!(
-                    "raft logs should be continuous, last index: {}, new appended: {}",
-                    last_index, entries[0].index,
-                );
-            }
-
-            self.core.transaction(|tx| {
-                let mut insert_batch = Batch::default();
-                for entry in entries.iter() {
-                    let log_entries = convert_to_log_entries(entry);
-                    for log_entry in log_entries.into_iter() {
-                        if matches!(log_entry.action_type, ActionTypeLog::StartTxn) {
-                            self.active_txns
-                                .borrow_mut()
-                                .insert(log_entry.txn_id, entry.index);
-                        } else if matches!(
-                            log_entry.action_type,
-                            ActionTypeLog::CommitTxn | ActionTypeLog::RollbackTxn
-                        ) {
-                            self.active_txns.borrow_mut().remove(&log_entry.txn_id);
-                        }
-                        // else if matches!(log_entry.action_type, ActionTypeLog::SyncLSN){
-                        //     let sled_util = SledUtil::instance();
-                        //     let mut key = vec![];
-                        //     key.extend_from_slice(self.graph_id.to_le_bytes().as_ref());
-                        //     key.extend_from_slice(self.partition_id.to_le_bytes().as_ref());
-                        //     let value = log_entry.lsn.to_be_bytes();
-                        //     let _ = sled_util.insert(&key[..], sled::IVec::from(&value));
-                        //     let _ = sled_util.flush();
-                        // }
-                    }
-                    insert_batch.insert(
-                        &entry.index.to_le_bytes(),
-                        entry
-                            .write_to_bytes()
-                            .expect("entry should serialize to bytes"),
-                    );
-                }
-                tx.apply_batch(&insert_batch)?;
-
-                let mut del_batch = Batch::default();
-                let del_last_idx = last_index;
-                let del_start_idx = entries.last().unwrap().index + 1;
-                arcgraph_log::raft_debug!(
-                    "append del start={}, end={}",
-                    del_start_idx,
-                    del_last_idx
-                );
-                for key in del_start_idx..=del_last_idx {
-                    del_batch.remove(&key.to_le_bytes());
-                }
-                tx.apply_batch(&del_batch)?;
-
-                let new_last_idx = del_start_idx - 1;
-                tx.insert(&LAST_INDEX, &new_last_idx.to_le_bytes())?;
-                Ok(())
-            })?;
+            drop(txns);
             let last_index = self.get_last_index()?;
-            arcgraph_log::raft_debug!(
-                "first_index, last_index after append: {}, {}",
-                first_index,
-                last_index
-            );
+            arcgraph_log::raft_debug!("last_index after append: {}", last_index);
             Ok(())
         }
     }
 
     fn get_hard_state(&self) -> Result<Option<HardState>> {
-        let bytes_hard_state = self.core.get(&HARD_STATE)?;
+        let bytes_hard_state = self.core.get(HARD_STATE)?;
         if let Some(bytes) = bytes_hard_state {
             let hard_state = HardState::parse_from_bytes(&bytes)?;
             Ok(Some(hard_state))
@@ -323,7 +916,7 @@ impl LogStore for SledStorage {
     }
 
     fn get_conf_state(&self) -> Result<Option<ConfState>> {
-        let bytes_conf_state = self.core.get(&CONF_STATE)?;
+        let bytes_conf_state = self.core.get(CONF_STATE)?;
         if let Some(bytes) = bytes_conf_state {
             let conf_state = ConfState::parse_from_bytes(&bytes)?;
             Ok(Some(conf_state))
@@ -337,7 +930,10 @@ impl LogStore for SledStorage {
     }
 
     fn set_hard_state_commit(&mut self, commit: u64) -> Result<()> {
-        let mut hard_state = self.get_hard_state()?.expect("hard state should exist");
+        let mut hard_state = self.get_hard_state()?.ok_or(SledStorageError::Corruption {
+            index: commit,
+            reason: String::from("hard state is missing"),
+        })?;
         hard_state.set_commit(commit);
         self.insert_hard_state(&hard_state)
     }
@@ -346,65 +942,77 @@ impl LogStore for SledStorage {
         self.insert_conf_state(conf_state)
     }
 
-    fn create_snapshot(&mut self, _data: prost::bytes::Bytes) -> Result<Snapshot> {
-        self.snapshot(0).map_err(|e| Error::Other(Box::new(e)))
+    fn create_snapshot(&mut self, data: prost::bytes::Bytes) -> Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        snapshot.set_metadata(self.build_snapshot_metadata()?);
+        if data.is_empty() {
+            if let Some(provider) = &self.snapshot_provider {
+                snapshot.set_data(provider.pack(snapshot.get_metadata().index)?.into());
+            }
+        } else {
+            snapshot.set_data(data.to_vec().into());
+        }
+        self.insert_snapshot(&snapshot)?;
+        Ok(snapshot)
     }
 
     fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()> {
         let meta = snapshot.get_metadata();
-        let first_index = self
-            .get_first_index()
-            .expect("storage should have first index");
+        let first_index = self.get_first_index()?;
         arcgraph_log::raft_debug!(
             "apply_snapshot, first_index={}, meta.index={}",
             first_index,
             meta.index
         );
         if first_index > meta.index {
-            return Err(Error::Msg(String::from("Snapshot out of date")));
+            return Err(SledStorageError::SnapshotOutOfDate {
+                index: meta.index,
+                first_index,
+            }
+            .into());
         }
 
-        self.snapshot_metadata = meta.clone();
-        let r = self.get_hard_state()?;
-        let mut hard_state = if let Some(hs) = r {
-            hs
-        } else {
-            HardState::default()
-        };
+        // Restore the application first, the log is kept if it fails.
+        if let Some(provider) = &self.snapshot_provider {
+            provider.restore(meta, snapshot.get_data())?;
+        }
+
+        let mut hard_state = self.get_hard_state()?.unwrap_or_default();
         hard_state.term = cmp::max(meta.term, hard_state.term);
         hard_state.commit = cmp::max(meta.index, hard_state.commit);
         let conf_state = meta.get_conf_state();
         // arcgraph_log::raft_debug!("hard_state={:?}, conf_state={:?}", hard_state, conf_state);
-        let last_index = self
-            .get_last_index()
-            .expect("storage should have last index");
+        let last_index = self.get_last_index()?;
+        let hard_state_bytes = hard_state.write_to_bytes()?;
+        let conf_state_bytes = conf_state.write_to_bytes()?;
+        let meta_bytes = meta.write_to_bytes()?;
+        let snapshot_bytes = snapshot.write_to_bytes()?;
 
+        let mut txns = self.active_txns();
         self.core.transaction(|tx| {
             let mut del_batch = Batch::default();
             for key in first_index..=last_index {
-                del_batch.remove(&key.to_le_bytes());
+                del_batch.remove(&entry_key(key));
             }
             tx.apply_batch(&del_batch)?;
 
             tx.insert(&FIRST_INDEX, &(meta.index + 1).to_le_bytes())?;
             tx.insert(&LAST_INDEX, &meta.index.to_le_bytes())?;
 
-            tx.insert(
-                &HARD_STATE,
-                hard_state
-                    .write_to_bytes()
-                    .expect("hard state should be able to bytes"),
-            )?;
-
-            tx.insert(
-                &CONF_STATE,
-                conf_state
-                    .write_to_bytes()
-                    .expect("conf state should be able to bytes"),
-            )?;
+            tx.insert(&HARD_STATE, hard_state_bytes.as_slice())?;
+            tx.insert(&CONF_STATE, conf_state_bytes.as_slice())?;
+            tx.insert(&SNAPSHOT_METADATA, meta_bytes.as_slice())?;
+            tx.insert(&SNAPSHOT, snapshot_bytes.as_slice())?;
             Ok(())
         })?;
-        self.active_txns.borrow_mut().clear();
+        txns.clear();
+        drop(txns);
+        // Cache the metadata once the snapshot is stored, so a failed write
+        // leaves the old one.
+        *self
+            .snapshot_metadata
+            .get_mut()
+            .unwrap_or_else(PoisonError::into_inner) = meta.clone();
         Ok(())
     }
 
@@ -416,16 +1024,32 @@ impl LogStore for SledStorage {
 
         let last_index = self.get_last_index()?;
         if compact_idx > last_index + 1 {
-            panic!(
-                "compact not received raft logs: {}, last index: {}",
-                compact_idx, last_index
-            );
+            return Err(SledStorageError::Unavailable {
+                index: compact_idx - 1,
+                last_index,
+            }
+            .into());
+        }
+        // The active transactions are rebuilt from the retained entries on open,
+        // so the entries starting them must be kept. Before anything is
+        // committed, only the active transactions bound the checkpoint.
+        let checkpoint = self
+            .get_checkpoint_idx()
+            .or_else(|| self.active_txns().values().min().copied());
+        if let Some(checkpoint) = checkpoint {
+            if compact_idx > checkpoint {
+                return Err(SledStorageError::PastCheckpoint {
+                    index: compact_idx,
+                    checkpoint,
+                }
+                .into());
+            }
         }
 
         self.core.transaction(|tx| {
             let mut del_batch = Batch::default();
             for key in first_index..compact_idx {
-                del_batch.remove(&key.to_le_bytes());
+                del_batch.remove(&entry_key(key));
             }
             tx.apply_batch(&del_batch)?;
             tx.insert(&FIRST_INDEX, &compact_idx.to_le_bytes())?;
@@ -490,33 +1114,36 @@ impl Storage for SledStorage {
     }
 
     fn snapshot(&self, request_index: u64) -> TiKvResult<Snapshot> {
-        let mut snapshot = Snapshot::default();
-        let meta = snapshot.mut_metadata();
-
-        let hard_state = self
-            .get_hard_state()
-            .map_err(|e| self.build_tikv_error(e))?
-            .expect("hard state should exist");
+        // A stored snapshot is reused as long as the log continues from it.
+        let first_index = self
+            .get_first_index()
+            .map_err(|e| self.build_tikv_error(e))?;
+        if let Some(snapshot) = self.get_snapshot().map_err(|e| self.build_tikv_error(e))? {
+            let index = snapshot.get_metadata().index;
+            if index >= request_index && index + 1 >= first_index {
+                arcgraph_log::raft_debug!("snapshot={:?}", snapshot.get_metadata());
+                return Ok(snapshot);
+            }
+        }
 
-        meta.index = hard_state.get_commit();
-        meta.term = if meta.index == self.snapshot_metadata.get_index() {
-            self.snapshot_metadata.get_term()
-        } else {
-            let entry = self
-                .get_entry(meta.index)
-                .map_err(|e| self.build_tikv_error(e))?
-                .unwrap_or_else(|| panic!("entry with {} should exists", meta.index));
-            entry.term
-        };
-        let conf_state = self
-            .get_conf_state()
-            .map_err(|e| self.build_tikv_error(e))?
-            .expect("conf state should exist");
-        meta.set_conf_state(conf_state);
+        let mut snapshot = Snapshot::default();
+        snapshot.set_metadata(
+            self.build_snapshot_metadata()
+                .map_err(|e| self.build_tikv_error(e))?,
+        );
+        if let Some(provider) = &self.snapshot_provider {
+            let data = provider.pack(snapshot.get_metadata().index).map_err(|e| {
+                arcgraph_log::raft_debug!("failed to pack snapshot: {:?}", e);
+                tikv_raft::Error::Store(tikv_raft::StorageError::SnapshotTemporarilyUnavailable)
+            })?;
+            snapshot.set_data(data.into());
+            self.insert_snapshot(&snapshot)
+                .map_err(|e| self.build_tikv_error(e))?;
+        }
         if snapshot.get_metadata().index < request_index {
             snapshot.mut_metadata().index = request_index;
         }
-        arcgraph_log::raft_debug!("snapshot={:?}", snapshot);
+        arcgraph_log::raft_debug!("snapshot={:?}", snapshot.get_metadata());
         // arcgraph_log::raft_debug!("snapshot bt={:?}", backtrace::Backtrace::new());
         Ok(snapshot)
     }
@@ -525,26 +1152,68 @@ impl Storage for SledStorage {
 #[cfg(test)]
 mod tests {
 
-    use std::os::raw;
-
-    use data_type::LogEntry;
+    use data_type::{ActionTypeLog, LogEntry};
     use protobuf::Message;
     use tikv_raft::{
         prelude::{ConfState, HardState, Snapshot, SnapshotMetadata},
         Storage,
     };
 
-    use super::SledStorage;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use super::{SledEngine, SledStorage, SledStorageError, SledWriteBatch, SnapshotProvider};
     use crate::storage::LogStore;
     use prost::bytes::Bytes;
     use tikv_raft::prelude::Entry;
 
-    fn new_store() -> SledStorage {
+    // Tests run in parallel, so each one has a store of its own. It's removed
+    // first, a store left corrupted by the last run can't be opened.
+    fn new_store(peer_id: u64) -> SledStorage {
         let graph_id = 1;
         let partition_id = 2;
-        let peer_id = 3;
+        let _ = std::fs::remove_dir_all(format!("/tmp/raft_log/{graph_id}_{partition_id}_{peer_id}"));
+        let folder = Some(String::from("/tmp/raft_log"));
+        SledStorage::new(graph_id, partition_id, peer_id, folder).unwrap()
+    }
+
+    // Sled releases the lock of a dropped store from its flusher thread, so
+    // reopening it right away may find it still locked.
+    fn reopen(peer_id: u64) -> crate::error::Result<SledStorage> {
         let folder = Some(String::from("/tmp/raft_log"));
-        SledStorage::new(graph_id, partition_id, peer_id, folder)
+        for _ in 0..100 {
+            match SledStorage::new(1, 2, peer_id, folder.clone()) {
+                Err(crate::Error::Sled(sled::Error::Io(e)))
+                    if e.to_string().contains("could not acquire lock") =>
+                {
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                r => return r,
+            }
+        }
+        SledStorage::new(1, 2, peer_id, folder)
+    }
+
+    // An application whose state is the index it last applied.
+    #[derive(Default)]
+    struct IndexState {
+        applied: Mutex<u64>,
+    }
+
+    impl SnapshotProvider for IndexState {
+        fn pack(&self, index: u64) -> crate::error::Result<Vec<u8>> {
+            let applied = *self.applied.lock().unwrap();
+            if applied < index {
+                return Err(crate::Error::Msg(format!("{index} isn't applied yet")));
+            }
+            Ok(index.to_be_bytes().to_vec())
+        }
+
+        fn restore(&self, meta: &SnapshotMetadata, data: &[u8]) -> crate::error::Result<()> {
+            assert_eq!(data, meta.index.to_be_bytes());
+            *self.applied.lock().unwrap() = meta.index;
+            Ok(())
+        }
     }
 
     fn build_entries(idx_list: Vec<u64>) -> Vec<Entry> {
@@ -562,12 +1231,12 @@ mod tests {
 
     #[test]
     fn test_store_new() {
-        new_store();
+        new_store(3);
     }
 
     #[test]
     fn test_init_state() {
-        let store = new_store();
+        let store = new_store(15);
         if let Ok(rs) = store.initial_state() {
             println!(
                 "hard_state={:?}, conf_state={:?}",
@@ -576,18 +1245,18 @@ mod tests {
         }
 
         if let Ok(cs) = store.get_conf_state() {
-            println!("conf_state={:?}", cs.unwrap());
+            println!("conf_state={:?}", cs);
         }
 
         if let Ok(hs) = store.get_hard_state() {
-            println!("hard_state={:?}", hs.unwrap());
+            println!("hard_state={:?}", hs);
         }
     }
 
     #[test]
     fn test_set_hard_state() {
-        let mut store = new_store();
-        let old_hs = store.get_hard_state().unwrap().unwrap();
+        let mut store = new_store(16);
+        let old_hs = store.get_hard_state().unwrap();
         println!("old hard state={:?}", old_hs);
         let hs = HardState {
             commit: 9,
@@ -595,31 +1264,29 @@ mod tests {
             vote: 200,
             ..Default::default()
         };
-        if store.set_hard_state(&hs).is_ok() {
-            let new_hs = store.get_hard_state().unwrap().unwrap();
-            println!("new hard state={:?}", new_hs);
-        }
+        store.set_hard_state(&hs).unwrap();
+        let new_hs = store.get_hard_state().unwrap().unwrap();
+        assert_eq!(new_hs, hs);
     }
 
     #[test]
     fn test_set_conf_state() {
-        let mut store = new_store();
-        let old_cs = store.get_conf_state().unwrap().unwrap();
+        let mut store = new_store(17);
+        let old_cs = store.get_conf_state().unwrap();
         println!("old conf state={:?}", old_cs);
         let cs = ConfState {
             voters: vec![1, 2, 3],
             learners: vec![4, 5, 6],
             ..Default::default()
         };
-        if store.set_conf_state(&cs).is_ok() {
-            let new_cs = store.get_conf_state().unwrap().unwrap();
-            println!("new conf state={:?}", new_cs);
-        }
+        store.set_conf_state(&cs).unwrap();
+        let new_cs = store.get_conf_state().unwrap().unwrap();
+        assert_eq!(new_cs, cs);
     }
 
     #[test]
     fn test_append_entries() {
-        let mut store = new_store();
+        let mut store = new_store(18);
         // test wrong first_index
         // let entry_idx_list = vec![0, 1, 2];
         // let entries = build_entries(entry_idx_list);
@@ -665,7 +1332,7 @@ mod tests {
 
     #[test]
     fn test_create_snapshot() {
-        let store = new_store();
+        let store = new_store(19);
         let snapshot = store.snapshot(0);
         println!("snapshot={:?}", snapshot);
     }
@@ -673,7 +1340,7 @@ mod tests {
     #[test]
     fn test_apply_snapshot() {
         test_init_state();
-        let mut store = new_store();
+        let mut store = new_store(20);
         let entry_idx_list = vec![1, 2, 3, 4, 5];
         let entries = build_entries(entry_idx_list);
         store.append(&entries).unwrap();
@@ -734,7 +1401,7 @@ mod tests {
 
     #[test]
     fn test_compact() {
-        let mut store = new_store();
+        let mut store = new_store(21);
         let entry_idx_list = vec![1, 2, 3, 4, 5];
         let entries = build_entries(entry_idx_list);
         store.append(&entries).unwrap();
@@ -749,7 +1416,7 @@ mod tests {
 
     #[test]
     fn test_get_term() {
-        let mut store = new_store();
+        let mut store = new_store(22);
         let entry_idx_list = vec![1, 2, 3, 4, 5];
         let entries = build_entries(entry_idx_list);
         store.append(&entries).unwrap();
@@ -767,7 +1434,7 @@ mod tests {
             7048549560733540346,
         ];
         for peer_id in peer_ids.into_iter() {
-            let store = SledStorage::new(graph_id, partition_id, peer_id, None);
+            let store = SledStorage::new(graph_id, partition_id, peer_id, None).unwrap();
             let first_index = store.get_first_index().unwrap();
             let last_index = store.get_last_index().unwrap();
             let raw_entries = store
@@ -785,4 +1452,553 @@ mod tests {
             println!("{} cs={:?}", peer_id, cs);
         }
     }
+
+    #[test]
+    fn test_entries_in_index_order() {
+        let mut store = SledStorage::new(1, 2, 4, Some(String::from("/tmp/raft_log"))).unwrap();
+        store.reset().unwrap();
+        let entries = build_entries((1..=300).collect());
+        store.append(&entries).unwrap();
+        let got = store.get_entries(1, 301, None).unwrap();
+        assert_eq!(got, entries);
+        let got = store.get_entries(250, 260, None).unwrap();
+        assert_eq!(got, entries[249..259].to_vec());
+    }
+
+    #[test]
+    fn test_migrate_legacy_key_layout() {
+        let path = "/tmp/raft_log/1_2_5";
+        let _ = std::fs::remove_dir_all(path);
+        let entries = build_entries((1..=300).collect());
+        {
+            let db = sled::open(path).unwrap();
+            for entry in entries.iter() {
+                db.insert(entry.index.to_le_bytes(), entry.write_to_bytes().unwrap())
+                    .unwrap();
+            }
+            let hs = HardState {
+                commit: 300,
+                term: 300,
+                ..Default::default()
+            };
+            db.insert(u64::MAX.to_le_bytes(), hs.write_to_bytes().unwrap())
+                .unwrap();
+            db.insert((u64::MAX - 2).to_le_bytes(), &1u64.to_le_bytes())
+                .unwrap();
+            db.insert((u64::MAX - 3).to_le_bytes(), &300u64.to_le_bytes())
+                .unwrap();
+            db.flush().unwrap();
+        }
+
+        let store = reopen(5).unwrap();
+        assert_eq!(store.get_first_index().unwrap(), 1);
+        assert_eq!(store.get_last_index().unwrap(), 300);
+        assert_eq!(store.get_hard_state().unwrap().unwrap().commit, 300);
+        assert_eq!(store.get_entries(1, 301, None).unwrap(), entries);
+        drop(store);
+
+        // Migrating again changes nothing.
+        let store = reopen(5).unwrap();
+        assert_eq!(store.get_entries(1, 301, None).unwrap(), entries);
+    }
+
+    #[test]
+    fn test_snapshot_metadata_survives_restart() {
+        let folder = Some(String::from("/tmp/raft_log"));
+        let mut store = SledStorage::new(1, 2, 6, folder.clone()).unwrap();
+        store.reset().unwrap();
+        store.append(&build_entries(vec![1, 2, 3, 4, 5])).unwrap();
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 5;
+        snapshot.mut_metadata().term = 100;
+        store.apply_snapshot(snapshot).unwrap();
+        drop(store);
+
+        let store = reopen(6).unwrap();
+        assert_eq!(store.get_first_index().unwrap(), 6);
+        assert_eq!(store.get_last_index().unwrap(), 5);
+        assert_eq!(store.get_term(5).unwrap(), 100);
+    }
+
+    #[test]
+    fn test_active_txns_survive_restart() {
+        let folder = Some(String::from("/tmp/raft_log"));
+        let mut store = SledStorage::new(1, 2, 7, folder.clone()).unwrap();
+        store.reset().unwrap();
+        let mut entries = build_entries(vec![1, 2, 3]);
+        let start = LogEntry {
+            action_type: ActionTypeLog::StartTxn,
+            txn_id: 7,
+            ..Default::default()
+        };
+        entries[1].data = Bytes::from(bincode::serialize(&start).unwrap());
+        store.append(&entries).unwrap();
+        store
+            .set_hard_state(&HardState {
+                commit: 3,
+                ..Default::default()
+            })
+            .unwrap();
+        assert_eq!(store.get_checkpoint_idx(), Some(2));
+        // The entry starting the transaction is needed to rebuild it.
+        assert!(store.compact(3).is_err());
+        store.compact(2).unwrap();
+        drop(store);
+
+        let store = reopen(7).unwrap();
+        assert_eq!(store.get_checkpoint_idx(), Some(2));
+    }
+
+    #[test]
+    fn test_engine_write_batch() {
+        let _ = std::fs::remove_dir_all("/tmp/raft_log/engine");
+        let engine = SledEngine::open("/tmp/raft_log/engine").unwrap();
+        let store1 = engine.storage(1, 1, 1).unwrap();
+        let store2 = engine.storage(1, 2, 1).unwrap();
+        let entries1 = build_entries(vec![1, 2, 3]);
+        let entries2 = build_entries(vec![1, 2]);
+        let hs = HardState {
+            commit: 2,
+            term: 2,
+            ..Default::default()
+        };
+
+        let mut batch = SledWriteBatch::default();
+        batch.append(&store1, &entries1).unwrap();
+        batch.append(&store2, &entries2).unwrap();
+        batch.set_hard_state(&store2, &hs).unwrap();
+        engine.write(batch).unwrap();
+
+        assert_eq!(store1.get_last_index().unwrap(), 3);
+        assert_eq!(store1.get_hard_state().unwrap(), None);
+        assert_eq!(store2.get_entries(1, 3, None).unwrap(), entries2);
+        assert_eq!(store2.get_hard_state().unwrap(), Some(hs));
+
+        // A batch appends to a group only once.
+        let mut batch = SledWriteBatch::default();
+        batch.append(&store1, &entries1).unwrap();
+        assert!(batch.append(&store1, &entries1).is_err());
+
+        // Groups don't see each other's entries.
+        drop((store1, store2));
+        let store3 = engine.storage(1, 3, 1).unwrap();
+        assert_eq!(store3.get_last_index().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_engine_storage_errors() {
+        let _ = std::fs::remove_dir_all("/tmp/raft_log/engine_errors");
+        let engine = SledEngine::open("/tmp/raft_log/engine_errors").unwrap();
+        let mut store = engine.storage(1, 1, 1).unwrap();
+        store.append(&build_entries(vec![1, 2, 3])).unwrap();
+        store.core.insert(super::entry_key(2), &[0xff; 4]).unwrap();
+        drop(store);
+
+        // A damaged group fails to open, without taking the others down.
+        assert!(engine.storage(1, 1, 1).is_err());
+        assert!(engine.storage(1, 2, 1).is_ok());
+    }
+
+    #[test]
+    fn test_typed_errors() {
+        let mut store = SledStorage::new(1, 2, 8, Some(String::from("/tmp/raft_log"))).unwrap();
+        store.reset().unwrap();
+        store.append(&build_entries(vec![1, 2, 3, 4, 5])).unwrap();
+        store.compact(3).unwrap();
+
+        assert_eq!(
+            store.entries(1, 4, None),
+            Err(tikv_raft::Error::Store(tikv_raft::StorageError::Compacted))
+        );
+        assert_eq!(
+            store.entries(3, 7, None),
+            Err(tikv_raft::Error::Store(tikv_raft::StorageError::Unavailable))
+        );
+        assert_eq!(
+            store.term(1),
+            Err(tikv_raft::Error::Store(tikv_raft::StorageError::Compacted))
+        );
+        assert_eq!(
+            store.term(6),
+            Err(tikv_raft::Error::Store(tikv_raft::StorageError::Unavailable))
+        );
+        assert!(store.append(&build_entries(vec![1])).is_err());
+        assert!(store.append(&build_entries(vec![7])).is_err());
+        assert!(store.compact(7).is_err());
+
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 2;
+        assert!(store.apply_snapshot(snapshot).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_entry() {
+        // The store is left corrupted, which fails the next open.
+        let _ = std::fs::remove_dir_all("/tmp/raft_log/1_2_9");
+        let mut store = SledStorage::new(1, 2, 9, Some(String::from("/tmp/raft_log"))).unwrap();
+        store.reset().unwrap();
+        store.append(&build_entries(vec![1, 2, 3])).unwrap();
+        store.core.insert(super::entry_key(2), &[0xff; 4]).unwrap();
+
+        match store.entries(1, 4, None) {
+            Err(tikv_raft::Error::Store(tikv_raft::StorageError::Other(e))) => {
+                let e = e.downcast_ref::<SledStorageError>().unwrap();
+                assert!(matches!(e, SledStorageError::Corruption { index: 2, .. }));
+            }
+            r => panic!("expected corruption, got {:?}", r),
+        }
+        assert_eq!(store.get_entries(3, 4, None).unwrap().len(), 1);
+
+        // Active transactions can't be rebuilt on open.
+        drop(store);
+        assert!(reopen(9).is_err());
+    }
+
+    #[test]
+    fn test_corrupted_index_marker() {
+        let mut store = new_store(23);
+        store.append(&build_entries(vec![1, 2, 3])).unwrap();
+        store.core.insert(super::LAST_INDEX, &[3]).unwrap();
+
+        match store.get_last_index() {
+            Err(crate::Error::Other(e)) => {
+                let e = e.downcast_ref::<SledStorageError>().unwrap();
+                assert!(matches!(e, SledStorageError::Corruption { .. }));
+            }
+            r => panic!("expected corruption, got {:?}", r),
+        }
+        assert!(store.last_index().is_err());
+    }
+
+    // A `SledStorage` as raft-rs sees it. The store speaks the raft 0.6 types
+    // and the checks the raft-rs ones, both are converted through protobuf.
+    struct Conformance {
+        peer_id: u64,
+        store: Mutex<SledStorage>,
+    }
+
+    fn convert<A: Message, B: Message>(m: &A) -> B {
+        B::parse_from_bytes(&m.write_to_bytes().unwrap()).unwrap()
+    }
+
+    fn convert_error(e: tikv_raft::Error) -> raft_rs::Error {
+        use raft_rs::StorageError;
+        let e = match e {
+            tikv_raft::Error::Store(tikv_raft::StorageError::Compacted) => StorageError::Compacted,
+            tikv_raft::Error::Store(tikv_raft::StorageError::Unavailable) => {
+                StorageError::Unavailable
+            }
+            tikv_raft::Error::Store(tikv_raft::StorageError::SnapshotOutOfDate) => {
+                StorageError::SnapshotOutOfDate
+            }
+            tikv_raft::Error::Store(tikv_raft::StorageError::SnapshotTemporarilyUnavailable) => {
+                StorageError::SnapshotTemporarilyUnavailable
+            }
+            e => StorageError::Other(Box::new(e)),
+        };
+        raft_rs::Error::Store(e)
+    }
+
+    impl Conformance {
+        fn store(&self) -> std::sync::MutexGuard<'_, SledStorage> {
+            self.store.lock().unwrap()
+        }
+
+        fn write(
+            &self,
+            f: impl FnOnce(&mut SledStorage) -> crate::error::Result<()>,
+        ) -> raft_rs::Result<()> {
+            let mut store = self.store();
+            f(&mut store).map_err(|e| convert_error(store.build_tikv_error(e)))
+        }
+    }
+
+    impl raft_rs::Storage for Conformance {
+        fn initial_state(&self) -> raft_rs::Result<raft_rs::RaftState> {
+            let state = self.store().initial_state().map_err(convert_error)?;
+            Ok(raft_rs::RaftState::new(
+                convert(&state.hard_state),
+                convert(&state.conf_state),
+            ))
+        }
+
+        fn entries(
+            &self,
+            low: u64,
+            high: u64,
+            max_size: impl Into<Option<u64>>,
+            _: raft_rs::GetEntriesContext,
+        ) -> raft_rs::Result<Vec<raft_rs::eraftpb::Entry>> {
+            let entries = self
+                .store()
+                .entries(low, high, max_size)
+                .map_err(convert_error)?;
+            Ok(entries.iter().map(convert).collect())
+        }
+
+        fn term(&self, idx: u64) -> raft_rs::Result<u64> {
+            self.store().term(idx).map_err(convert_error)
+        }
+
+        fn first_index(&self) -> raft_rs::Result<u64> {
+            self.store().first_index().map_err(convert_error)
+        }
+
+        fn last_index(&self) -> raft_rs::Result<u64> {
+            self.store().last_index().map_err(convert_error)
+        }
+
+        fn snapshot(
+            &self,
+            request_index: u64,
+            _: u64,
+        ) -> raft_rs::Result<raft_rs::eraftpb::Snapshot> {
+            let snapshot = self
+                .store()
+                .snapshot(request_index)
+                .map_err(convert_error)?;
+            Ok(convert(&snapshot))
+        }
+    }
+
+    impl raft_rs::storage::MutableStorage for Conformance {
+        fn append(&self, ents: &[raft_rs::eraftpb::Entry]) -> raft_rs::Result<()> {
+            let ents: Vec<Entry> = ents.iter().map(convert).collect();
+            self.write(|store| store.append(&ents))
+        }
+
+        // Raft only compacts applied entries, and the store refuses to compact
+        // past them. The checks don't commit first, so it's done here.
+        fn compact(&self, compact_index: u64) -> raft_rs::Result<()> {
+            self.write(|store| {
+                let commit = store.get_hard_state()?.map_or(0, |hs| hs.commit);
+                if commit < compact_index {
+                    store.set_hard_state_commit(compact_index)?;
+                }
+                store.compact(compact_index)
+            })
+        }
+
+        fn apply_snapshot(&self, snapshot: raft_rs::eraftpb::Snapshot) -> raft_rs::Result<()> {
+            self.write(|store| store.apply_snapshot(convert(&snapshot)))
+        }
+
+        fn set_hardstate(&self, hs: raft_rs::eraftpb::HardState) -> raft_rs::Result<()> {
+            self.write(|store| store.set_hard_state(&convert(&hs)))
+        }
+    }
+
+    #[test]
+    fn test_sled_storage_conformance() {
+        let mut peer_id = 100;
+        let new_storage = || {
+            peer_id += 1;
+            Conformance {
+                peer_id,
+                store: Mutex::new(new_store(peer_id)),
+            }
+        };
+        let reopen = |storage: Conformance| {
+            let peer_id = storage.peer_id;
+            drop(storage);
+            Conformance {
+                peer_id,
+                store: Mutex::new(reopen(peer_id).unwrap()),
+            }
+        };
+        raft_rs::storage::conformance::run_all(raft_rs::storage::conformance::reopening(
+            new_storage,
+            reopen,
+        ));
+    }
+
+    #[test]
+    fn test_snapshot_provider() {
+        let folder = Some(String::from("/tmp/raft_log"));
+        let leader_state = Arc::new(IndexState::default());
+        let mut leader = SledStorage::new(1, 2, 11, folder.clone()).unwrap();
+        leader.reset().unwrap();
+        leader.set_snapshot_provider(leader_state.clone());
+        leader.append(&build_entries(vec![1, 2, 3])).unwrap();
+        leader.set_conf_state(&ConfState::default()).unwrap();
+        leader
+            .set_hard_state(&HardState {
+                commit: 3,
+                ..Default::default()
+            })
+            .unwrap();
+
+        // The state at the commit index isn't there yet.
+        assert_eq!(
+            leader.snapshot(0),
+            Err(tikv_raft::Error::Store(
+                tikv_raft::StorageError::SnapshotTemporarilyUnavailable
+            ))
+        );
+        *leader_state.applied.lock().unwrap() = 3;
+        let snapshot = leader.snapshot(0).unwrap();
+        assert_eq!(snapshot.get_metadata().index, 3);
+        assert_eq!(snapshot.get_data(), 3u64.to_be_bytes());
+
+        // The snapshot is persisted with its metadata and reused after restart.
+        drop(leader);
+        let leader = reopen(11).unwrap();
+        assert_eq!(leader.snapshot(0).unwrap(), snapshot);
+
+        let follower_state = Arc::new(IndexState::default());
+        let mut follower = SledStorage::new(1, 2, 12, folder).unwrap();
+        follower.reset().unwrap();
+        follower.set_snapshot_provider(follower_state.clone());
+        follower.apply_snapshot(snapshot.clone()).unwrap();
+        assert_eq!(*follower_state.applied.lock().unwrap(), 3);
+        assert_eq!(follower.get_first_index().unwrap(), 4);
+        assert_eq!(follower.snapshot(0).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_create_snapshot_with_data() {
+        let mut store = SledStorage::new(1, 2, 13, Some(String::from("/tmp/raft_log"))).unwrap();
+        store.reset().unwrap();
+        store.append(&build_entries(vec![1, 2])).unwrap();
+        store.set_conf_state(&ConfState::default()).unwrap();
+        store
+            .set_hard_state(&HardState {
+                commit: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        let snapshot = store.create_snapshot(Bytes::from_static(b"state")).unwrap();
+        assert_eq!(snapshot.get_metadata().index, 2);
+        assert_eq!(snapshot.get_metadata().term, 2);
+        assert_eq!(store.snapshot(1).unwrap().get_data(), b"state");
+    }
+
+    #[test]
+    fn test_concurrent_append_and_checkpoint() {
+        fn is_send_sync<T: Send + Sync>() {}
+        is_send_sync::<SledStorage>();
+
+        let _ = std::fs::remove_dir_all("/tmp/raft_log/concurrent");
+        let engine = SledEngine::open("/tmp/raft_log/concurrent").unwrap();
+        let store = Arc::new(engine.storage(1, 1, 1).unwrap());
+        let done = Arc::new(AtomicBool::new(false));
+
+        let entry = |index: u64, action_type: ActionTypeLog, txn_id: u64| Entry {
+            index,
+            term: 1,
+            data: Bytes::from(
+                bincode::serialize(&LogEntry {
+                    action_type,
+                    txn_id: txn_id as _,
+                    ..Default::default()
+                })
+                .unwrap(),
+            ),
+            ..Default::default()
+        };
+        // Round k finishes transaction k, starts transaction k + 1 at 3k + 2, and
+        // commits up to 3k + 3. So the checkpoint is always at a start index.
+        let driver = {
+            let (engine, store, done) = (engine.clone(), store.clone(), done.clone());
+            std::thread::spawn(move || {
+                for k in 0..300u64 {
+                    let entries = vec![
+                        entry(3 * k + 1, ActionTypeLog::CommitTxn, k),
+                        entry(3 * k + 2, ActionTypeLog::StartTxn, k + 1),
+                        entry(3 * k + 3, ActionTypeLog::default(), 0),
+                    ];
+                    let hs = HardState {
+                        commit: 3 * k + 3,
+                        term: 1,
+                        ..Default::default()
+                    };
+                    let mut batch = SledWriteBatch::default();
+                    batch.append(&store, &entries).unwrap();
+                    batch.set_hard_state(&store, &hs).unwrap();
+                    engine.write(batch).unwrap();
+                }
+                done.store(true, Ordering::SeqCst);
+            })
+        };
+
+        let mut checked = 0;
+        while !done.load(Ordering::SeqCst) {
+            if let Some(idx) = store.get_checkpoint_idx() {
+                assert_eq!(idx % 3, 2, "checkpoint {} is past an active transaction", idx);
+                checked += 1;
+            }
+        }
+        driver.join().unwrap();
+        assert_eq!(store.get_checkpoint_idx(), Some(3 * 299 + 2));
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_force_new_cluster() {
+        let mut store = SledStorage::new(1, 2, 14, Some(String::from("/tmp/raft_log"))).unwrap();
+        store.reset().unwrap();
+        store.append(&build_entries(vec![1, 2, 3, 4])).unwrap();
+        store
+            .set_conf_state(&ConfState {
+                voters: vec![1, 2, 3],
+                ..Default::default()
+            })
+            .unwrap();
+        store
+            .set_hard_state(&HardState {
+                term: 4,
+                vote: 2,
+                commit: 2,
+                ..Default::default()
+            })
+            .unwrap();
+
+        assert!(store.force_new_cluster(vec![]).is_err());
+        store.force_new_cluster(vec![1]).unwrap();
+        assert_eq!(store.get_last_index().unwrap(), 2);
+        assert_eq!(store.get_entry(3).unwrap(), None);
+        let state = store.initial_state().unwrap();
+        assert_eq!((state.hard_state.term, state.hard_state.vote, state.hard_state.commit), (5, 0, 2));
+        assert_eq!(state.conf_state.voters, vec![1]);
+        assert!(state.conf_state.learners.is_empty());
+    }
+
+    #[test]
+    fn test_force_new_cluster_snapshot() {
+        let mut store = new_store(24);
+        store.set_snapshot_provider(Arc::new(IndexState::default()));
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 2;
+        snapshot.mut_metadata().term = 2;
+        snapshot.mut_metadata().mut_conf_state().voters = vec![1, 2, 3];
+        snapshot.set_data(Bytes::from(2u64.to_be_bytes().to_vec()));
+        store.apply_snapshot(snapshot).unwrap();
+        store.append(&build_entries(vec![3, 4])).unwrap();
+        store
+            .set_conf_state(&ConfState {
+                voters: vec![1, 2, 3],
+                ..Default::default()
+            })
+            .unwrap();
+        store
+            .set_hard_state(&HardState {
+                term: 4,
+                commit: 2,
+                ..Default::default()
+            })
+            .unwrap();
+        let snapshot = store.snapshot(2).unwrap();
+        assert_eq!(snapshot.get_metadata().get_conf_state().voters, vec![1, 2, 3]);
+
+        store.force_new_cluster(vec![1]).unwrap();
+        let snapshot = store.snapshot(2).unwrap();
+        assert_eq!(snapshot.get_metadata().index, 2);
+        assert_eq!(snapshot.get_metadata().get_conf_state().voters, vec![1]);
+        assert_eq!(store.get_snapshot_metadata().get_conf_state().voters, vec![1]);
+        drop(store);
+
+        let store = reopen(24).unwrap();
+        assert_eq!(store.get_snapshot_metadata().get_conf_state().voters, vec![1]);
+        assert_eq!(store.get_first_index().unwrap(), 3);
+    }
 }
@@ -0,0 +1,22 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+//! Stand-ins for the modules of the arcgraph raft crate that sled_storage.rs
+//! is built against. They aren't the upstream code: they only carry what this
+//! tree uses, with the same paths, so the store and redo-fix build and test.
+
+pub mod error;
+pub mod storage;
+pub mod utils;
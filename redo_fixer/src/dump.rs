@@ -0,0 +1,605 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! A portable dump of a raft log, for moving a partition between machines or
+//! storage engines.
+//!
+//! A dump is a header, the hard state, the conf state, the snapshot metadata,
+//! the first index, the entries in index order, and a footer with the number of records and a
+//! CRC32 over them. Every record is the protobuf encoding of its message, in
+//! one of two formats:
+//!
+//! - `proto`: `RDFX`, the version as a u32, then for each record a tag byte, the
+//!   length as a u32 and the bytes. Integers are little-endian.
+//! - `json`: a JSON object per line. Records carry the protobuf bytes in hex,
+//!   next to a few decoded fields for reading.
+//!
+//! The checksum covers the tag and the bytes of every record, so it's the same
+//! in both formats.
+//!
+//! The first index is a u64 record since version 2. A log compacted without a
+//! snapshot starts past the snapshot index, version 1 dumps can't carry it.
+
+use protobuf::Message;
+use raft::error::Result;
+use raft::sled_storage::{encode_index_marker, parse_index_marker, RawKey, SledStorage};
+use raft::storage::LogStore;
+use raft::Error;
+use serde::{Deserialize, Serialize};
+use std::convert::TryInto;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use tikv_raft::prelude::{ConfState, Entry, HardState, Snapshot, SnapshotMetadata};
+
+const MAGIC: &[u8; 4] = b"RDFX";
+const VERSION: u32 = 2;
+// The oldest version that is still read.
+const MIN_VERSION: u32 = 1;
+
+const TAG_HARD_STATE: u8 = 1;
+const TAG_CONF_STATE: u8 = 2;
+const TAG_SNAPSHOT: u8 = 3;
+const TAG_ENTRY: u8 = 4;
+const TAG_FIRST_INDEX: u8 = 5;
+const TAG_FOOTER: u8 = 0xff;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Proto,
+}
+
+impl std::str::FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Format, String> {
+        match s {
+            "json" => Ok(Format::Json),
+            "proto" => Ok(Format::Proto),
+            s => Err(format!("unknown format {}, expect json or proto", s)),
+        }
+    }
+}
+
+/// Everything of a log that a dump carries.
+#[derive(Debug, Default, PartialEq)]
+pub struct LogDump {
+    pub hard_state: HardState,
+    pub conf_state: ConfState,
+    pub snapshot: SnapshotMetadata,
+    /// The index of the first entry. It's past the snapshot index if the log is
+    /// compacted further than its snapshot.
+    pub first_index: u64,
+    pub entries: Vec<Entry>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+enum JsonRecord {
+    Header {
+        version: u32,
+    },
+    HardState {
+        term: u64,
+        vote: u64,
+        commit: u64,
+        proto: String,
+    },
+    ConfState {
+        voters: Vec<u64>,
+        learners: Vec<u64>,
+        proto: String,
+    },
+    Snapshot {
+        index: u64,
+        term: u64,
+        proto: String,
+    },
+    FirstIndex {
+        index: u64,
+        proto: String,
+    },
+    Entry {
+        index: u64,
+        term: u64,
+        entry_type: String,
+        proto: String,
+    },
+    Footer {
+        records: u64,
+        crc32: u32,
+    },
+}
+
+fn io_error(e: impl std::error::Error + Send + Sync + 'static) -> Error {
+    Error::Other(Box::new(e))
+}
+
+fn corrupted(reason: String) -> Error {
+    Error::Msg(format!("dump is corrupted: {}", reason))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Result<Vec<u8>> {
+    if s.len() % 2 == 1 {
+        return Err(corrupted(format!("odd hex length {}", s.len())));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|e| corrupted(e.to_string())))
+        .collect()
+}
+
+/// Counts records and checksums them as they are written or read.
+#[derive(Default)]
+struct Checksum {
+    records: u64,
+    hasher: crc32fast::Hasher,
+}
+
+impl Checksum {
+    fn update(&mut self, tag: u8, bytes: &[u8]) {
+        self.records += 1;
+        self.hasher.update(&[tag]);
+        self.hasher.update(bytes);
+    }
+
+    fn check(self, records: u64, crc32: u32) -> Result<()> {
+        let actual = self.hasher.finalize();
+        if self.records != records || actual != crc32 {
+            return Err(corrupted(format!(
+                "footer has {} records with crc32 {:08x}, read {} with crc32 {:08x}",
+                records, crc32, self.records, actual
+            )));
+        }
+        Ok(())
+    }
+}
+
+impl LogDump {
+    /// Reads the log of `storage` without writing to it.
+    pub fn from_sled(storage: &SledStorage) -> Result<LogDump> {
+        let first_index = storage.get_first_index()?;
+        let last_index = storage.get_last_index()?;
+        let entries = if first_index <= last_index {
+            storage.get_entries(first_index, last_index + 1, None)?
+        } else {
+            vec![]
+        };
+        Ok(LogDump {
+            hard_state: storage.get_hard_state()?.unwrap_or_default(),
+            conf_state: storage.get_conf_state()?.unwrap_or_default(),
+            snapshot: storage.get_snapshot_metadata(),
+            first_index,
+            entries,
+        })
+    }
+
+    fn records(&self) -> Result<Vec<(u8, Vec<u8>)>> {
+        let mut records = vec![
+            (TAG_HARD_STATE, self.hard_state.write_to_bytes()?),
+            (TAG_CONF_STATE, self.conf_state.write_to_bytes()?),
+            (TAG_SNAPSHOT, self.snapshot.write_to_bytes()?),
+            (TAG_FIRST_INDEX, encode_index_marker(self.first_index).to_vec()),
+        ];
+        for entry in &self.entries {
+            records.push((TAG_ENTRY, entry.write_to_bytes()?));
+        }
+        Ok(records)
+    }
+
+    fn push_record(&mut self, tag: u8, bytes: &[u8]) -> Result<()> {
+        match tag {
+            TAG_HARD_STATE => self.hard_state = HardState::parse_from_bytes(bytes)?,
+            TAG_CONF_STATE => self.conf_state = ConfState::parse_from_bytes(bytes)?,
+            TAG_SNAPSHOT => self.snapshot = SnapshotMetadata::parse_from_bytes(bytes)?,
+            TAG_FIRST_INDEX => {
+                self.first_index = parse_index_marker(bytes)
+                    .ok_or_else(|| corrupted(format!("first index has {} bytes", bytes.len())))?
+            }
+            TAG_ENTRY => self.entries.push(Entry::parse_from_bytes(bytes)?),
+            tag => return Err(corrupted(format!("unknown record tag {}", tag))),
+        }
+        Ok(())
+    }
+
+    /// Checks that the entries start at the first index without gaps, so the
+    /// dump can be imported.
+    fn check_continuity(&self) -> Result<()> {
+        if self.first_index <= self.snapshot.index {
+            return Err(corrupted(format!(
+                "first index {} isn't past the snapshot index {}",
+                self.first_index, self.snapshot.index
+            )));
+        }
+        for (expected, entry) in (self.first_index..).zip(&self.entries) {
+            if entry.index != expected {
+                return Err(corrupted(format!(
+                    "expect entry {}, got {}",
+                    expected, entry.index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_to(&self, path: &Path, format: Format) -> Result<()> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(io_error)?;
+        let mut w = BufWriter::new(file);
+        let mut checksum = Checksum::default();
+        match format {
+            Format::Proto => {
+                w.write_all(MAGIC).map_err(io_error)?;
+                w.write_all(&VERSION.to_le_bytes()).map_err(io_error)?;
+                for (tag, bytes) in self.records()? {
+                    checksum.update(tag, &bytes);
+                    write_frame(&mut w, tag, &bytes)?;
+                }
+                let mut footer = checksum.records.to_le_bytes().to_vec();
+                footer.extend_from_slice(&checksum.hasher.finalize().to_le_bytes());
+                write_frame(&mut w, TAG_FOOTER, &footer)?;
+            }
+            Format::Json => {
+                write_json(&mut w, &JsonRecord::Header { version: VERSION })?;
+                let mut entries = self.entries.iter();
+                for (tag, bytes) in self.records()? {
+                    checksum.update(tag, &bytes);
+                    let proto = to_hex(&bytes);
+                    let record = match tag {
+                        TAG_HARD_STATE => JsonRecord::HardState {
+                            term: self.hard_state.term,
+                            vote: self.hard_state.vote,
+                            commit: self.hard_state.commit,
+                            proto,
+                        },
+                        TAG_CONF_STATE => JsonRecord::ConfState {
+                            voters: self.conf_state.voters.clone(),
+                            learners: self.conf_state.learners.clone(),
+                            proto,
+                        },
+                        TAG_SNAPSHOT => JsonRecord::Snapshot {
+                            index: self.snapshot.index,
+                            term: self.snapshot.term,
+                            proto,
+                        },
+                        TAG_FIRST_INDEX => JsonRecord::FirstIndex {
+                            index: self.first_index,
+                            proto,
+                        },
+                        _ => {
+                            let entry = entries.next().unwrap();
+                            JsonRecord::Entry {
+                                index: entry.index,
+                                term: entry.term,
+                                entry_type: format!("{:?}", entry.get_entry_type()),
+                                proto,
+                            }
+                        }
+                    };
+                    write_json(&mut w, &record)?;
+                }
+                write_json(
+                    &mut w,
+                    &JsonRecord::Footer {
+                        records: checksum.records,
+                        crc32: checksum.hasher.finalize(),
+                    },
+                )?;
+            }
+        }
+        let file = w.into_inner().map_err(|e| io_error(e.into_error()))?;
+        file.sync_all().map_err(io_error)?;
+        Ok(())
+    }
+
+    /// Reads a dump in either format, checking the version and the checksum.
+    pub fn read_from(path: &Path) -> Result<LogDump> {
+        let mut r = BufReader::new(File::open(path).map_err(io_error)?);
+        let is_proto = r.fill_buf().map_err(io_error)?.starts_with(MAGIC);
+        let mut dump = LogDump::default();
+        let mut checksum = Checksum::default();
+        if is_proto {
+            let mut header = [0; 8];
+            r.read_exact(&mut header).map_err(io_error)?;
+            check_version(u32::from_le_bytes([header[4], header[5], header[6], header[7]]))?;
+            loop {
+                let (tag, bytes) = read_frame(&mut r)?;
+                if tag == TAG_FOOTER {
+                    if bytes.len() != 12 {
+                        return Err(corrupted(format!("footer has {} bytes", bytes.len())));
+                    }
+                    let records = u64::from_le_bytes(bytes[..8].try_into().unwrap());
+                    let crc32 = u32::from_le_bytes(bytes[8..].try_into().unwrap());
+                    checksum.check(records, crc32)?;
+                    break;
+                }
+                checksum.update(tag, &bytes);
+                dump.push_record(tag, &bytes)?;
+            }
+        } else {
+            let mut lines = r.lines();
+            let mut next = || -> Result<JsonRecord> {
+                let line = lines
+                    .next()
+                    .ok_or_else(|| corrupted(String::from("footer is missing")))?
+                    .map_err(io_error)?;
+                serde_json::from_str(&line).map_err(|e| corrupted(e.to_string()))
+            };
+            match next()? {
+                JsonRecord::Header { version } => check_version(version)?,
+                _ => return Err(corrupted(String::from("header is missing"))),
+            }
+            loop {
+                let (tag, proto) = match next()? {
+                    JsonRecord::HardState { proto, .. } => (TAG_HARD_STATE, proto),
+                    JsonRecord::ConfState { proto, .. } => (TAG_CONF_STATE, proto),
+                    JsonRecord::Snapshot { proto, .. } => (TAG_SNAPSHOT, proto),
+                    JsonRecord::FirstIndex { proto, .. } => (TAG_FIRST_INDEX, proto),
+                    JsonRecord::Entry { proto, .. } => (TAG_ENTRY, proto),
+                    JsonRecord::Footer { records, crc32 } => {
+                        checksum.check(records, crc32)?;
+                        break;
+                    }
+                    JsonRecord::Header { .. } => {
+                        return Err(corrupted(String::from("header is repeated")))
+                    }
+                };
+                let bytes = from_hex(&proto)?;
+                checksum.update(tag, &bytes);
+                dump.push_record(tag, &bytes)?;
+            }
+        }
+        if dump.first_index == 0 {
+            // Written before the first index was recorded.
+            dump.first_index = dump.snapshot.index + 1;
+        }
+        Ok(dump)
+    }
+
+    /// Rebuilds the log in a new sled store at `path`.
+    pub fn import_sled(&self, path: &Path) -> Result<SledStorage> {
+        self.check_continuity()?;
+        self.rebuild(SledStorage::create_offline(path)?)
+    }
+
+    /// Rebuilds the log in a temporary sled store, removed when it's dropped.
+    pub fn import_temporary(&self) -> Result<SledStorage> {
+        self.check_continuity()?;
+        self.rebuild(SledStorage::create_temporary()?)
+    }
+
+    fn rebuild(&self, mut storage: SledStorage) -> Result<SledStorage> {
+        if self.snapshot.index > 0 {
+            let mut snapshot = Snapshot::default();
+            snapshot.set_metadata(self.snapshot.clone());
+            storage.apply_snapshot(snapshot)?;
+        }
+        if self.first_index > self.snapshot.index + 1 {
+            // The log was compacted past its snapshot, so it starts where the
+            // compaction left it. The entries are appended after the markers.
+            storage.raw_write(&[
+                (RawKey::FirstIndex, Some(encode_index_marker(self.first_index).to_vec())),
+                (RawKey::LastIndex, Some(encode_index_marker(self.first_index - 1).to_vec())),
+            ])?;
+        }
+        if !self.entries.is_empty() {
+            storage.append(&self.entries)?;
+        }
+        storage.set_hard_state(&self.hard_state)?;
+        storage.set_conf_state(&self.conf_state)?;
+        Ok(storage)
+    }
+
+    /// Checks that `imported`, read back from an import, carries this log.
+    pub fn check_round_trip(&self, imported: &LogDump) -> Result<()> {
+        let mismatch = if self.hard_state != imported.hard_state {
+            "hard state"
+        } else if self.conf_state != imported.conf_state {
+            "conf state"
+        } else if (self.snapshot.index, self.snapshot.term)
+            != (imported.snapshot.index, imported.snapshot.term)
+        {
+            "snapshot"
+        } else if self.first_index != imported.first_index {
+            "first index"
+        } else if self.entries != imported.entries {
+            "entries"
+        } else {
+            return Ok(());
+        };
+        Err(Error::Msg(format!("imported {} differ from the dump", mismatch)))
+    }
+}
+
+fn check_version(version: u32) -> Result<()> {
+    if !(MIN_VERSION..=VERSION).contains(&version) {
+        return Err(corrupted(format!(
+            "version {} isn't supported, expect {} to {}",
+            version, MIN_VERSION, VERSION
+        )));
+    }
+    Ok(())
+}
+
+fn write_frame(w: &mut impl Write, tag: u8, bytes: &[u8]) -> Result<()> {
+    w.write_all(&[tag]).map_err(io_error)?;
+    w.write_all(&(bytes.len() as u32).to_le_bytes())
+        .map_err(io_error)?;
+    w.write_all(bytes).map_err(io_error)
+}
+
+fn read_frame(r: &mut impl Read) -> Result<(u8, Vec<u8>)> {
+    let mut head = [0; 5];
+    r.read_exact(&mut head)
+        .map_err(|e| corrupted(format!("truncated record: {}", e)))?;
+    let len = u32::from_le_bytes([head[1], head[2], head[3], head[4]]);
+    // The length comes from the file, so the buffer only grows with the bytes
+    // that are actually there.
+    let mut bytes = vec![];
+    r.take(u64::from(len))
+        .read_to_end(&mut bytes)
+        .map_err(|e| corrupted(format!("truncated record: {}", e)))?;
+    if bytes.len() as u64 != u64::from(len) {
+        return Err(corrupted(format!(
+            "truncated record: {} of {} bytes",
+            bytes.len(),
+            len
+        )));
+    }
+    Ok((head[0], bytes))
+}
+
+fn write_json(w: &mut impl Write, record: &JsonRecord) -> Result<()> {
+    serde_json::to_writer(&mut *w, record).map_err(io_error)?;
+    w.write_all(b"\n").map_err(io_error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_frame, Format, LogDump};
+    use raft::sled_storage::SledStorage;
+    use raft::storage::LogStore;
+    use std::path::{Path, PathBuf};
+    use tikv_raft::prelude::{ConfState, Entry, HardState, Snapshot};
+
+    fn test_path(name: &str) -> PathBuf {
+        let path = Path::new("/tmp/raft_log/redo_fix").join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        let _ = std::fs::remove_file(&path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        path
+    }
+
+    // A log with a snapshot at 2, entries [3, 10] and 8 committed.
+    fn new_log(name: &str) -> SledStorage {
+        let mut storage = SledStorage::create_offline(test_path(name)).unwrap();
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 2;
+        snapshot.mut_metadata().term = 1;
+        storage.apply_snapshot(snapshot).unwrap();
+        let entries: Vec<_> = (3..=10)
+            .map(|index| Entry {
+                index,
+                term: 2,
+                data: vec![index as u8; 3].into(),
+                ..Default::default()
+            })
+            .collect();
+        storage.append(&entries).unwrap();
+        storage
+            .set_hard_state(&HardState {
+                term: 2,
+                vote: 1,
+                commit: 8,
+                ..Default::default()
+            })
+            .unwrap();
+        storage
+            .set_conf_state(&ConfState {
+                voters: vec![1, 2, 3],
+                ..Default::default()
+            })
+            .unwrap();
+        storage
+    }
+
+    // Exports `storage`, then imports the dump both into memory and into sled.
+    fn round_trip(storage: &SledStorage, name: &str, format: Format) -> LogDump {
+        let dump = LogDump::from_sled(storage).unwrap();
+        let file = test_path(&format!("{}.dump", name));
+        dump.write_to(&file, format).unwrap();
+        let read = LogDump::read_from(&file).unwrap();
+        assert_eq!(read, dump);
+
+        let imported = LogDump::from_sled(&read.import_temporary().unwrap()).unwrap();
+        read.check_round_trip(&imported).unwrap();
+        let sled = read.import_sled(&test_path(&format!("{}.sled", name))).unwrap();
+        read.check_round_trip(&LogDump::from_sled(&sled).unwrap()).unwrap();
+        read
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let storage = new_log("dump_round_trip");
+        for (format, name) in [(Format::Json, "json"), (Format::Proto, "proto")] {
+            let dump = round_trip(&storage, &format!("dump_round_trip_{}", name), format);
+            assert_eq!(dump.first_index, 3);
+            assert_eq!(dump.entries.len(), 8);
+        }
+    }
+
+    #[test]
+    fn test_round_trip_compacted() {
+        // Compacting moves the first index past the snapshot.
+        let mut storage = new_log("dump_compacted");
+        storage.compact(6).unwrap();
+        assert_eq!(storage.get_snapshot_metadata().index, 2);
+        for (format, name) in [(Format::Json, "json"), (Format::Proto, "proto")] {
+            let dump = round_trip(&storage, &format!("dump_compacted_{}", name), format);
+            assert_eq!(dump.first_index, 6);
+            let imported = dump.import_temporary().unwrap();
+            assert_eq!(imported.get_first_index().unwrap(), 6);
+            assert_eq!(imported.get_last_index().unwrap(), 10);
+            assert_eq!(imported.get_term(6).unwrap(), 2);
+        }
+    }
+
+    #[test]
+    fn test_checksum_mismatch() {
+        let storage = new_log("dump_checksum");
+        let dump = LogDump::from_sled(&storage).unwrap();
+
+        let file = test_path("dump_checksum.proto");
+        dump.write_to(&file, Format::Proto).unwrap();
+        let mut bytes = std::fs::read(&file).unwrap();
+        // The last byte of the footer is in the crc32.
+        *bytes.last_mut().unwrap() ^= 0xff;
+        std::fs::write(&file, bytes).unwrap();
+        assert!(LogDump::read_from(&file).is_err());
+
+        // The data of entry 5 changes, it still decodes.
+        let file = test_path("dump_checksum.json");
+        dump.write_to(&file, Format::Json).unwrap();
+        let text = std::fs::read_to_string(&file).unwrap();
+        assert_eq!(text.matches("050505").count(), 1);
+        std::fs::write(&file, text.replace("050505", "060606")).unwrap();
+        assert!(LogDump::read_from(&file).is_err());
+    }
+
+    #[test]
+    fn test_frame_longer_than_the_file() {
+        // A length of 4 GiB with a few bytes behind it.
+        let mut frame: &[u8] = &[4, 0xff, 0xff, 0xff, 0xff, 1, 2, 3];
+        assert!(read_frame(&mut frame).is_err());
+        let mut frame: &[u8] = &[4, 3, 0, 0, 0, 1, 2, 3];
+        assert_eq!(read_frame(&mut frame).unwrap(), (4, vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_export_keeps_existing_file() {
+        let path = Path::new("/tmp/raft_log/redo_fix/export_existing");
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"keep").unwrap();
+        assert!(LogDump::default().write_to(path, Format::Proto).is_err());
+        assert_eq!(std::fs::read(path).unwrap(), b"keep");
+    }
+}
@@ -0,0 +1,79 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+use raft::error::Result;
+use raft::sled_storage::SledStorage;
+use raft::storage::LogStore;
+use raft::utils::convert_to_log_entries;
+use tikv_raft::prelude::EntryType;
+
+pub fn print_state(storage: &SledStorage) -> Result<()> {
+    println!("hard state: {:?}", storage.get_hard_state()?);
+    println!("conf state: {:?}", storage.get_conf_state()?);
+    println!("snapshot:   {:?}", storage.get_snapshot_metadata());
+    Ok(())
+}
+
+pub fn print_range(storage: &SledStorage) -> Result<()> {
+    let first_index = storage.get_first_index()?;
+    let last_index = storage.get_last_index()?;
+    if first_index > last_index {
+        println!("no entries, next index is {}", first_index);
+    } else {
+        println!(
+            "entries [{}, {}], {} in total",
+            first_index,
+            last_index,
+            last_index - first_index + 1
+        );
+    }
+    Ok(())
+}
+
+pub fn print_entries(storage: &SledStorage, from: Option<u64>, to: Option<u64>) -> Result<()> {
+    let from = from.unwrap_or(storage.get_first_index()?);
+    let to = to.unwrap_or(storage.get_last_index()?);
+    if from > to {
+        return Ok(());
+    }
+    for entry in storage.get_entries(from, to + 1, None)? {
+        println!(
+            "{} term={} type={:?} size={}",
+            entry.index,
+            entry.term,
+            entry.get_entry_type(),
+            entry.data.len()
+        );
+        // Conf changes carry no records.
+        if entry.get_entry_type() == EntryType::EntryNormal && !entry.data.is_empty() {
+            for log_entry in convert_to_log_entries(&entry) {
+                println!("    {:?}", log_entry);
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_txns(storage: &SledStorage) -> Result<()> {
+    storage.rebuild_active_txns()?;
+    let txns = storage.get_active_txns();
+    if txns.is_empty() {
+        println!("no active transactions");
+    }
+    for (txn_id, index) in txns {
+        println!("txn {} started at {}", txn_id, index);
+    }
+    println!("checkpoint index: {:?}", storage.get_checkpoint_idx());
+    Ok(())
+}
@@ -1,153 +1,216 @@
-use serde::{Deserialize, Serialize};
-use serde_json;
-use sled::{self, Db, Result};
-use std::str;
-
-#[derive(Debug, Serialize, Deserialize)]
-pub struct Todo {
-    pub id: String,
-    pub title: String,
-    pub is_done: bool,
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! `redo-fix` inspects the raft log of a partition offline, i.e. while the
+//! node that owns it is stopped. Even the commands that only read the log open
+//! it with sled, which may rewrite its files, so run them on a copy of the
+//! directory when it has to stay untouched.
+
+mod diff;
+mod dump;
+mod inspect;
+mod repair;
+mod verify;
+
+use clap::{Parser, Subcommand};
+use raft::error::Result;
+use raft::sled_storage::SledStorage;
+use raft::storage::LogStore;
+use raft::Error;
+use std::path::PathBuf;
+
+#[derive(Parser)]
+#[clap(
+    name = "redo-fix",
+    about = "Inspects, checks, repairs, compares and moves raft log stores offline",
+    after_help = "Sled may rewrite the files of a log even when it's only read, \
+                  run the commands on a copy of the directory to keep it untouched."
+)]
+struct Opt {
+    #[clap(subcommand)]
+    cmd: Command,
 }
 
-pub struct TodoList {
-    db: Db,
-    latest_id: String,
+/// The sled directory of a log, e.g. /tmp/raft_log/sled/1_2_3.
+type Dir = PathBuf;
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the hard state, the conf state and the snapshot metadata.
+    State { dir: Dir },
+    /// Prints the index range of the entries.
+    Range { dir: Dir },
+    /// Dumps the entries in [from, to], with the records they carry.
+    Entries {
+        dir: Dir,
+        #[clap(long)]
+        from: Option<u64>,
+        #[clap(long)]
+        to: Option<u64>,
+    },
+    /// Prints the active transactions and the checkpoint index.
+    Txns { dir: Dir },
+    /// Checks the log for damage and prints a JSON report. Exits with 2 if the
+    /// log is damaged.
+    Verify { dir: Dir },
+    /// Repairs damage reported by verify. The old value of every key it changes
+    /// is saved to the backup file first.
+    Repair {
+        dir: Dir,
+        #[clap(subcommand)]
+        repair: repair::Repair,
+        /// Prints the changes without applying them.
+        #[clap(long)]
+        dry_run: bool,
+        /// Where to save the old values, it must not exist yet.
+        #[clap(long)]
+        backup: Option<PathBuf>,
+        /// Allows dropping committed entries.
+        #[clap(long)]
+        force: bool,
+    },
+    /// Puts back the keys saved by a repair.
+    Restore { dir: Dir, backup: PathBuf },
+    /// Compares the logs of two replicas of a partition and locates where they
+    /// diverge.
+    Diff { dir_a: Dir, dir_b: Dir },
+    /// Writes the entries, the hard state, the conf state and the snapshot
+    /// metadata to a dump file.
+    Export {
+        dir: Dir,
+        /// The dump file, it must not exist yet.
+        file: PathBuf,
+        /// json or proto.
+        #[clap(long, default_value = "proto")]
+        format: dump::Format,
+    },
+    /// Rebuilds a log from a dump file and checks it against the dump. Without
+    /// --sled, the log is rebuilt in a temporary store, only to check the dump.
+    Import {
+        file: PathBuf,
+        /// The sled directory to create the log in, it must not exist yet.
+        #[clap(long)]
+        sled: Option<Dir>,
+    },
+    /// Unsafe: restarts the last surviving replica of a partition as a new
+    /// group of --voters, dropping the entries past its commit index. Entries
+    /// the lost replicas had committed may be gone. The log is exported to the
+    /// backup file first.
+    ForceNewCluster {
+        dir: Dir,
+        /// The voters of the new group, e.g. the id of the survivor.
+        #[clap(long, value_delimiter = ',', required = true)]
+        voters: Vec<u64>,
+        /// Where to export the log to first, it's written as a proto dump.
+        #[clap(long)]
+        backup: PathBuf,
+        /// Confirms that the other replicas are lost for good.
+        #[clap(long)]
+        force: bool,
+    },
 }
 
-impl TodoList {
-    pub fn new(path: String) -> Self {
-        let db = sled::open(path.as_str()).unwrap();
-        let latest_id = match db.get("latest_id".as_bytes()) {
-            Ok(id) => match id {
-                Some(id) => str::from_utf8(&id).unwrap().to_string(),
-                None => {
-                    format!("0")
-                }
-            },
-            _ => panic!("erro"),
-        };
-        TodoList { db, latest_id }
-    }
-
-    pub fn list(&self) -> Vec<Todo> {
-        let mut list = Vec::new();
-        for v in self.db.iter() {
-            if "latest_id" == str::from_utf8(&v.as_ref().unwrap().0).unwrap() {
-                continue;
+fn run(opt: Opt) -> Result<()> {
+    match opt.cmd {
+        Command::State { dir } => inspect::print_state(&SledStorage::open_offline(dir)?),
+        Command::Range { dir } => inspect::print_range(&SledStorage::open_offline(dir)?),
+        Command::Entries { dir, from, to } => {
+            inspect::print_entries(&SledStorage::open_offline(dir)?, from, to)
+        }
+        Command::Txns { dir } => inspect::print_txns(&SledStorage::open_offline(dir)?),
+        Command::Verify { dir } => {
+            let report = verify::verify(&SledStorage::open_offline(dir)?)?;
+            println!("{}", serde_json::to_string_pretty(&report).unwrap());
+            if !report.ok {
+                std::process::exit(2);
             }
-
-            let stodo = str::from_utf8(&v.as_ref().unwrap().1).unwrap();
-            let todo: Todo = serde_json::from_str(stodo).unwrap();
-            list.push(todo);
+            Ok(())
         }
-        list
-    }
-
-    pub fn add(&mut self, title: String) -> Result<()> {
-        let id = format!("{}", self.latest_id.parse::<usize>().unwrap() + 1);
-        self.latest_id = id.clone();
-        let todo = Todo {
-            id: id.clone(),
-            title,
-            is_done: false,
-        };
-        let r = serde_json::to_string(&todo).unwrap();
-        self.db.insert(id.as_bytes(), r.as_bytes())?;
-        self.db.insert("latest_id".as_bytes(), id.as_bytes())?;
-        Ok(())
-    }
-
-    pub fn remove(&mut self, id: String) {
-        self.db.remove(id.as_bytes()).unwrap();
-    }
-
-    pub fn toggle_done(&mut self, id: String) {
-        let todo = self.db.get(id.as_bytes()).unwrap();
-        match todo {
-            Some(todo) => {
-                let s = str::from_utf8(&todo).unwrap();
-                let mut todo: Todo = serde_json::from_str(s).unwrap();
-                todo.is_done = !todo.is_done;
-                self.db
-                    .insert(
-                        id.as_bytes(),
-                        serde_json::to_string(&todo).unwrap().as_bytes(),
-                    )
-                    .unwrap();
+        Command::Repair {
+            dir,
+            repair,
+            dry_run,
+            backup,
+            force,
+        } => {
+            let storage = SledStorage::open_offline(dir)?;
+            let writes = repair::plan(&storage, &repair, force)?;
+            repair::print_plan(&writes);
+            if dry_run || writes.is_empty() {
+                return Ok(());
             }
-            None => {}
+            let backup = backup.ok_or_else(|| Error::Msg(String::from("--backup is required")))?;
+            repair::apply(&storage, &writes, &backup)?;
+            println!("repaired, old values are saved to {}", backup.display());
+            Ok(())
         }
-    }
-
-    pub fn get(&self, id: String) -> Option<Todo> {
-        let todo = self.db.get(id.as_bytes()).unwrap();
-        match todo {
-            Some(todo) => {
-                let s = str::from_utf8(&todo).unwrap();
-                let todo: Todo = serde_json::from_str(s).unwrap();
-                Some(todo)
+        Command::Restore { dir, backup } => {
+            let writes = repair::restore(&SledStorage::open_offline(dir)?, &backup)?;
+            println!("restored {} keys", writes.len());
+            Ok(())
+        }
+        Command::Diff { dir_a, dir_b } => diff::print_diff(
+            &SledStorage::open_offline(dir_a)?,
+            &SledStorage::open_offline(dir_b)?,
+        ),
+        Command::Export { dir, file, format } => {
+            let dump = dump::LogDump::from_sled(&SledStorage::open_offline(dir)?)?;
+            dump.write_to(&file, format)?;
+            println!("exported {} entries to {}", dump.entries.len(), file.display());
+            Ok(())
+        }
+        Command::Import { file, sled } => {
+            let dump = dump::LogDump::read_from(&file)?;
+            let imported = match &sled {
+                Some(dir) => dump::LogDump::from_sled(&dump.import_sled(dir)?)?,
+                None => dump::LogDump::from_sled(&dump.import_temporary()?)?,
+            };
+            dump.check_round_trip(&imported)?;
+            println!("imported {} entries, round trip is checked", dump.entries.len());
+            Ok(())
+        }
+        Command::ForceNewCluster {
+            dir,
+            voters,
+            backup,
+            force,
+        } => {
+            if !force {
+                return Err(Error::Msg(String::from(
+                    "entries may be lost, use --force if the other replicas are gone for good",
+                )));
+            }
+            let mut storage = SledStorage::open_offline(dir)?;
+            if backup.exists() {
+                return Err(Error::Msg(format!("{} already exists", backup.display())));
             }
-            None => None,
+            dump::LogDump::from_sled(&storage)?.write_to(&backup, dump::Format::Proto)?;
+            storage.force_new_cluster(voters)?;
+            let commit = storage.get_hard_state()?.map_or(0, |hs| hs.commit);
+            println!(
+                "log is rewritten, start the node with applied index {}, the old log is exported to {}",
+                commit,
+                backup.display()
+            );
+            Ok(())
         }
     }
-
-    pub fn edit(&mut self, id: String, title: String) {
-        let todo = self.db.get(id.as_bytes()).unwrap().unwrap();
-        let mut todo: Todo = serde_json::from_str(str::from_utf8(&todo).unwrap()).unwrap();
-        todo.title = title;
-        let todo = serde_json::to_string(&todo).unwrap();
-        self.db.insert(&id.as_bytes(), todo.as_bytes()).unwrap();
-    }
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    //打开数据库
-    //let tree = sled::open("/tmp/welcome-to-sled").expect("open");
-    let storage = SledStorage::new(graph_id, *partition_id, *peer_id, None);
-    //let tree = sled::open("/tmp/raft_log.bak/sled/1125_9_7066373972136435680.2").expect("open");
-
-    // 插入KV，读取Key对应的值
-    //tree.insert("KEY1", "VAL1");
-    //assert_eq!(tree.get(&"KEY1"), Ok(Some(sled::IVec::from("VAL1"))));
-
-    // 范围查询
-    /* 
-    for kv in tree.range(..) {
-        //println!("key:{}, value:{}", key.unw);
-        //println!("key :{:?}, value:{:?}", kv.unwrap().0, kv.unwrap().1);
-        println!("key value :{:?}", kv.unwrap());
+fn main() {
+    if let Err(e) = run(Opt::parse()) {
+        eprintln!("redo-fix: {:?}", e);
+        std::process::exit(1);
     }
-    
-
-    // 删除
-    tree.remove(&"KEY1");
-
-    // atomic compare and swap，可以用在并发编程中
-    tree.compare_and_swap("KEY1", Some("VAL1"), Some("VAL2"));
-
-    // 阻塞直到所有修改都写入硬盘
-    tree.flush();
-    */
-
-
-    /* 
-    let mut todo_list = TodoList::new("my_db".to_string());
-    todo_list.add(format!("ok"))?;
-    todo_list.add(format!("thisis good"))?;
-    let todo = todo_list.get(format!("1"));
-    println!("{:?}", todo);
-    */
-    // let todo = todo_list.get(format!("2"));
-    // println!("{:?}", todo);
-    // todo_list.toggle_done(format!("2"));
-    // let todo = todo_list.get(format!("2"));
-    // println!("{:?}", todo);
-    
-    /* 
-    let list = todo_list.list();
-    println!("{:?}", list);
-    */
-    Ok(())
 }
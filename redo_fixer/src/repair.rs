@@ -0,0 +1,389 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Repairs for the damage `verify` finds. A repair is planned as a list of raw
+//! writes first, so it can be printed for a dry run, and the old value of every
+//! key it touches is backed up before it's applied.
+
+use clap::Subcommand;
+use protobuf::Message;
+use raft::error::Result;
+use raft::sled_storage::{encode_index_marker, parse_entry, parse_index_marker, RawKey, SledStorage};
+use raft::storage::LogStore;
+use raft::Error;
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+#[derive(Subcommand)]
+pub enum Repair {
+    /// Removes the entries after --after.
+    Truncate {
+        #[clap(long)]
+        after: u64,
+    },
+    /// Rewrites the index markers from the entries that exist.
+    Markers,
+    /// Lowers the commit index to the last valid entry.
+    ClampCommit,
+    /// Removes unknown keys and entries left below the snapshot.
+    DropOrphans,
+}
+
+/// Sets a key, or removes it if the value is `None`.
+pub type RawWrite = (RawKey, Option<Vec<u8>>);
+
+#[derive(Serialize, Deserialize)]
+struct BackupRecord {
+    key: Vec<u8>,
+    value: Option<Vec<u8>>,
+}
+
+/// Plans the writes of `repair`. Repairs that would drop committed entries are
+/// refused unless `force` is set.
+pub fn plan(storage: &SledStorage, repair: &Repair, force: bool) -> Result<Vec<RawWrite>> {
+    let snapshot_index = storage.get_snapshot_metadata().index;
+    let mut writes = vec![];
+    match repair {
+        Repair::Truncate { after: index } => {
+            let first_index = first_index(storage)?;
+            if index.checked_add(1).is_some_and(|next| next < first_index) {
+                return Err(Error::Msg(format!(
+                    "can't truncate after {}, entries before {} are compacted",
+                    index, first_index
+                )));
+            }
+            let entries = entries(storage)?;
+            let highest = entries
+                .iter()
+                .filter_map(|(key, _)| match key {
+                    RawKey::Entry(i) => Some(*i),
+                    _ => None,
+                })
+                .max()
+                .map_or(first_index - 1, |i| i.max(first_index - 1));
+            if *index > highest {
+                return Err(Error::Msg(format!(
+                    "can't truncate after {}, the last entry is {}",
+                    index, highest
+                )));
+            }
+            let hard_state = storage.get_hard_state()?;
+            let commit = hard_state.as_ref().map_or(0, |hs| hs.commit);
+            if *index < commit && !force {
+                return Err(Error::Msg(format!(
+                    "entries up to {} are committed, use --force to truncate after {}",
+                    commit, index
+                )));
+            }
+            for (key, _) in entries {
+                if let RawKey::Entry(i) = key {
+                    if i > *index {
+                        writes.push((key, None));
+                    }
+                }
+            }
+            writes.push((RawKey::LastIndex, Some(encode_index_marker(*index).to_vec())));
+            // The commit index can't point past the log.
+            if let Some(mut hard_state) = hard_state {
+                if hard_state.commit > *index {
+                    hard_state.commit = *index;
+                    writes.push((RawKey::HardState, Some(hard_state.write_to_bytes()?)));
+                }
+            }
+        }
+        Repair::Markers => {
+            let indexes: Vec<u64> = entries(storage)?
+                .into_iter()
+                .filter_map(|(key, _)| match key {
+                    RawKey::Entry(i) if i > snapshot_index => Some(i),
+                    _ => None,
+                })
+                .collect();
+            let (first, last) = match (indexes.first(), indexes.last()) {
+                (Some(first), Some(last)) => (*first, *last),
+                _ => (snapshot_index + 1, snapshot_index),
+            };
+            writes.push((RawKey::FirstIndex, Some(encode_index_marker(first).to_vec())));
+            writes.push((RawKey::LastIndex, Some(encode_index_marker(last).to_vec())));
+        }
+        Repair::ClampCommit => {
+            let mut hard_state = match storage.get_hard_state()? {
+                Some(hs) => hs,
+                None => return Ok(writes),
+            };
+            let last_valid = last_valid_index(storage)?;
+            if hard_state.commit > last_valid {
+                hard_state.commit = last_valid;
+                writes.push((RawKey::HardState, Some(hard_state.write_to_bytes()?)));
+            }
+        }
+        Repair::DropOrphans => {
+            for kv in storage.raw_scan() {
+                match kv? {
+                    (key @ RawKey::Unknown(_), _) => writes.push((key, None)),
+                    (RawKey::Entry(i), _) if i <= snapshot_index => {
+                        writes.push((RawKey::Entry(i), None))
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    // Writes that don't change anything aren't worth a backup.
+    let mut changed = vec![];
+    for (key, value) in writes {
+        let old = storage.raw_get(&key)?;
+        if old.as_deref() != value.as_deref() {
+            changed.push((key, value));
+        }
+    }
+    Ok(changed)
+}
+
+pub fn print_plan(writes: &[RawWrite]) {
+    if writes.is_empty() {
+        println!("nothing to repair");
+    }
+    for (key, value) in writes {
+        match value {
+            Some(value) => println!("set {:?} = {:02x?}", key, value),
+            None => println!("remove {:?}", key),
+        }
+    }
+}
+
+/// Backs up the keys `writes` touch to `backup`, which must not exist yet, then
+/// applies them atomically.
+pub fn apply(storage: &SledStorage, writes: &[RawWrite], backup: &Path) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(backup)
+        .map_err(|e| Error::Other(Box::new(e)))?;
+    for (key, _) in writes {
+        let record = BackupRecord {
+            key: key.encode(),
+            value: storage.raw_get(key)?.map(|v| v.to_vec()),
+        };
+        let line = serde_json::to_string(&record).map_err(|e| Error::Other(Box::new(e)))?;
+        writeln!(file, "{}", line).map_err(|e| Error::Other(Box::new(e)))?;
+    }
+    file.sync_all().map_err(|e| Error::Other(Box::new(e)))?;
+    storage.raw_write(writes)
+}
+
+/// Puts back the keys saved in `backup` by `apply`.
+pub fn restore(storage: &SledStorage, backup: &Path) -> Result<Vec<RawWrite>> {
+    let file = File::open(backup).map_err(|e| Error::Other(Box::new(e)))?;
+    let mut writes = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line.map_err(|e| Error::Other(Box::new(e)))?;
+        let record: BackupRecord =
+            serde_json::from_str(&line).map_err(|e| Error::Other(Box::new(e)))?;
+        writes.push((RawKey::decode(&record.key), record.value));
+    }
+    storage.raw_write(&writes)?;
+    Ok(writes)
+}
+
+fn entries(storage: &SledStorage) -> Result<Vec<(RawKey, sled::IVec)>> {
+    let mut entries = vec![];
+    for kv in storage.raw_scan() {
+        let (key, value) = kv?;
+        if let RawKey::Entry(_) = key {
+            entries.push((key, value));
+        }
+    }
+    Ok(entries)
+}
+
+// The first index by the marker, falling back to the entries if it's broken.
+fn first_index(storage: &SledStorage) -> Result<u64> {
+    if let Some(marker) = storage.raw_get(&RawKey::FirstIndex)? {
+        if let Some(index) = parse_index_marker(&marker) {
+            return Ok(index);
+        }
+    }
+    let snapshot_index = storage.get_snapshot_metadata().index;
+    for (key, _) in entries(storage)? {
+        if let RawKey::Entry(i) = key {
+            if i > snapshot_index {
+                return Ok(i);
+            }
+        }
+    }
+    Ok(snapshot_index + 1)
+}
+
+/// The end of the run of decodable entries starting at the first index, or the
+/// snapshot index if there is none.
+fn last_valid_index(storage: &SledStorage) -> Result<u64> {
+    let first_index = first_index(storage)?;
+    let mut last_valid = first_index
+        .saturating_sub(1)
+        .max(storage.get_snapshot_metadata().index);
+    for (key, value) in entries(storage)? {
+        if let RawKey::Entry(i) = key {
+            if i < first_index {
+                continue;
+            }
+            if i != last_valid + 1 || parse_entry(i, &value).is_err() {
+                break;
+            }
+            last_valid = i;
+        }
+    }
+    Ok(last_valid)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{apply, plan, restore, Repair};
+    use protobuf::Message;
+    use raft::sled_storage::{encode_index_marker, RawKey, SledStorage};
+    use raft::storage::LogStore;
+    use std::path::PathBuf;
+    use tikv_raft::prelude::{Entry, HardState, Snapshot};
+
+    fn store_with_entries(name: &str, last: u64, commit: u64) -> SledStorage {
+        let path = format!("/tmp/raft_log/redo_fix/{}", name);
+        let _ = std::fs::remove_dir_all(&path);
+        let mut storage = SledStorage::create_offline(path).unwrap();
+        let entries: Vec<_> = (1..=last)
+            .map(|index| Entry {
+                index,
+                term: 1,
+                ..Default::default()
+            })
+            .collect();
+        storage.append(&entries).unwrap();
+        storage
+            .set_hard_state(&HardState {
+                term: 1,
+                commit,
+                ..Default::default()
+            })
+            .unwrap();
+        storage
+    }
+
+    #[test]
+    fn test_truncate_past_last_entry() {
+        let storage = store_with_entries("repair_truncate_past_last", 5, 3);
+        assert!(plan(&storage, &Repair::Truncate { after: 6 }, false).is_err());
+        let writes = plan(&storage, &Repair::Truncate { after: 4 }, false).unwrap();
+        assert_eq!(
+            writes,
+            vec![
+                (RawKey::Entry(5), None),
+                (RawKey::LastIndex, Some(encode_index_marker(4).to_vec())),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_forced_truncate_lowers_commit() {
+        let storage = store_with_entries("repair_forced_truncate", 5, 4);
+        assert!(plan(&storage, &Repair::Truncate { after: 2 }, false).is_err());
+        let writes = plan(&storage, &Repair::Truncate { after: 2 }, true).unwrap();
+        storage.raw_write(&writes).unwrap();
+
+        assert_eq!(storage.get_last_index().unwrap(), 2);
+        assert_eq!(storage.get_hard_state().unwrap().unwrap().commit, 2);
+        assert_eq!(storage.get_hard_state().unwrap().unwrap().term, 1);
+    }
+
+    #[test]
+    fn test_truncate_after_max_index() {
+        let storage = store_with_entries("repair_truncate_max", 5, 3);
+        assert!(plan(&storage, &Repair::Truncate { after: u64::MAX }, true).is_err());
+    }
+
+    #[test]
+    fn test_markers() {
+        let storage = store_with_entries("repair_markers", 5, 3);
+        storage
+            .raw_write(&[
+                (RawKey::FirstIndex, Some(encode_index_marker(3).to_vec())),
+                (RawKey::LastIndex, Some(encode_index_marker(9).to_vec())),
+            ])
+            .unwrap();
+        let writes = plan(&storage, &Repair::Markers, false).unwrap();
+        storage.raw_write(&writes).unwrap();
+
+        assert_eq!(storage.get_first_index().unwrap(), 1);
+        assert_eq!(storage.get_last_index().unwrap(), 5);
+        assert!(plan(&storage, &Repair::Markers, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_clamp_commit() {
+        let storage = store_with_entries("repair_clamp_commit", 5, 5);
+        storage
+            .raw_write(&[(RawKey::Entry(4), Some(vec![0xff; 4]))])
+            .unwrap();
+        let writes = plan(&storage, &Repair::ClampCommit, false).unwrap();
+        storage.raw_write(&writes).unwrap();
+
+        let hard_state = storage.get_hard_state().unwrap().unwrap();
+        assert_eq!(hard_state.commit, 3);
+        assert_eq!(hard_state.term, 1);
+        assert!(plan(&storage, &Repair::ClampCommit, false).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_drop_orphans() {
+        let mut storage = store_with_entries("repair_drop_orphans", 5, 5);
+        let mut snapshot = Snapshot::default();
+        snapshot.mut_metadata().index = 5;
+        snapshot.mut_metadata().term = 1;
+        storage.apply_snapshot(snapshot).unwrap();
+        let orphan = Entry {
+            index: 2,
+            term: 1,
+            ..Default::default()
+        };
+        let unknown = RawKey::Unknown(vec![7, 7]);
+        storage
+            .raw_write(&[
+                (RawKey::Entry(2), Some(orphan.write_to_bytes().unwrap())),
+                (unknown.clone(), Some(vec![1])),
+            ])
+            .unwrap();
+
+        let writes = plan(&storage, &Repair::DropOrphans, false).unwrap();
+        assert_eq!(writes, vec![(RawKey::Entry(2), None), (unknown, None)]);
+    }
+
+    #[test]
+    fn test_apply_and_restore() {
+        let storage = store_with_entries("repair_apply_restore", 5, 3);
+        let backup = PathBuf::from("/tmp/raft_log/redo_fix/repair_apply_restore.backup");
+        let _ = std::fs::remove_file(&backup);
+        let before: Vec<_> = storage.raw_scan().map(Result::unwrap).collect();
+
+        let writes = plan(&storage, &Repair::Truncate { after: 3 }, false).unwrap();
+        apply(&storage, &writes, &backup).unwrap();
+        assert_eq!(storage.get_last_index().unwrap(), 3);
+        // The backup is never overwritten.
+        assert!(apply(&storage, &writes, &backup).is_err());
+
+        restore(&storage, &backup).unwrap();
+        let after: Vec<_> = storage.raw_scan().map(Result::unwrap).collect();
+        assert_eq!(after, before);
+        assert_eq!(storage.get_last_index().unwrap(), 5);
+    }
+}
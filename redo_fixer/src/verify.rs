@@ -0,0 +1,254 @@
+//  Copyright 2022 Fabarta Authors.
+//
+//  Licensed under the Apache License, Version 2.0 (the "License");
+//  you may not use this file except in compliance with the License.
+//  You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+//  Unless required by applicable law or agreed to in writing, software
+//  distributed under the License is distributed on an "AS IS" BASIS,
+//  WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+//  See the License for the specific language governing permissions and
+//  limitations under the License.
+
+//! Checks a raft log store for damage, e.g. after disk trouble, before the
+//! partition is restarted on it.
+
+use raft::error::Result;
+use raft::sled_storage::{parse_entry, parse_index_marker, RawKey, SledStorage};
+use raft::storage::LogStore;
+use serde::Serialize;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    /// An index marker can't be decoded.
+    BadMarker,
+    /// An index marker doesn't match the entries that exist.
+    MarkerMismatch,
+    /// Entries are missing between two existing ones.
+    Gap,
+    /// An entry has a lower term than the one before it.
+    TermRegression,
+    /// An entry can't be decoded, or is stored under the wrong index.
+    BadEntry,
+    /// The hard state can't be decoded.
+    BadHardState,
+    /// The commit index points outside of the log.
+    CommitOutOfRange,
+    /// A key `SledStorage` never writes.
+    OrphanKey,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub kind: IssueKind,
+    pub index: Option<u64>,
+    pub detail: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub ok: bool,
+    /// The values of the index markers.
+    pub first_index: Option<u64>,
+    pub last_index: Option<u64>,
+    /// The lowest and highest entry keys that exist.
+    pub lowest_entry: Option<u64>,
+    pub highest_entry: Option<u64>,
+    pub entries: u64,
+    pub snapshot_index: u64,
+    pub commit: Option<u64>,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    fn issue(&mut self, kind: IssueKind, index: Option<u64>, detail: String) {
+        self.issues.push(Issue {
+            kind,
+            index,
+            detail,
+        });
+    }
+}
+
+/// Checks the index markers, the entries and the hard state of `storage`.
+pub fn verify(storage: &SledStorage) -> Result<Report> {
+    let mut report = Report::default();
+    let snapshot = storage.get_snapshot_metadata();
+    report.snapshot_index = snapshot.index;
+
+    // The index and term of the last entry, the term is unknown for broken ones.
+    let mut prev: Option<(u64, Option<u64>)> = None;
+    for kv in storage.raw_scan() {
+        let (key, value) = kv?;
+        match key {
+            RawKey::FirstIndex | RawKey::LastIndex => {
+                let marker = parse_index_marker(&value);
+                if marker.is_none() {
+                    report.issue(
+                        IssueKind::BadMarker,
+                        None,
+                        format!("{:?} has {} bytes", key, value.len()),
+                    );
+                }
+                if key == RawKey::FirstIndex {
+                    report.first_index = marker;
+                } else {
+                    report.last_index = marker;
+                }
+            }
+            RawKey::Entry(index) => {
+                let term = match parse_entry(index, &value) {
+                    Ok(entry) => Some(entry.term),
+                    Err(e) => {
+                        report.issue(IssueKind::BadEntry, Some(index), format!("{:?}", e));
+                        None
+                    }
+                };
+                match prev {
+                    None => {
+                        report.lowest_entry = Some(index);
+                        if index == snapshot.index + 1 && term.is_some_and(|t| t < snapshot.term) {
+                            report.issue(
+                                IssueKind::TermRegression,
+                                Some(index),
+                                format!("term {:?} is lower than the snapshot term {}", term, snapshot.term),
+                            );
+                        }
+                    }
+                    Some((prev_index, prev_term)) => {
+                        if index != prev_index + 1 {
+                            report.issue(
+                                IssueKind::Gap,
+                                Some(prev_index + 1),
+                                format!("entries [{}, {}] are missing", prev_index + 1, index - 1),
+                            );
+                        }
+                        if let (Some(prev_term), Some(term)) = (prev_term, term) {
+                            if term < prev_term {
+                                report.issue(
+                                    IssueKind::TermRegression,
+                                    Some(index),
+                                    format!("term {} follows term {}", term, prev_term),
+                                );
+                            }
+                        }
+                    }
+                }
+                prev = Some((index, term));
+                report.highest_entry = Some(index);
+                report.entries += 1;
+            }
+            RawKey::Unknown(key) => {
+                report.issue(IssueKind::OrphanKey, None, format!("{:02x?}", key));
+            }
+            _ => {}
+        }
+    }
+
+    check_markers(&mut report);
+
+    match storage.get_hard_state() {
+        Ok(Some(hs)) => {
+            report.commit = Some(hs.commit);
+            let last_index = report.highest_entry.unwrap_or(0).max(snapshot.index);
+            if hs.commit < snapshot.index || hs.commit > last_index {
+                report.issue(
+                    IssueKind::CommitOutOfRange,
+                    Some(hs.commit),
+                    format!("commit is out of [{}, {}]", snapshot.index, last_index),
+                );
+            }
+        }
+        Ok(None) => {}
+        Err(e) => report.issue(IssueKind::BadHardState, None, format!("{:?}", e)),
+    }
+
+    report.ok = report.issues.is_empty();
+    Ok(report)
+}
+
+fn check_markers(report: &mut Report) {
+    // A missing marker reads as the snapshot bounds, the same as in
+    // `get_first_index` and `get_last_index`. FIRST_INDEX is only written by
+    // compactions and snapshots, so a log that had neither has none.
+    let first = report.first_index.unwrap_or(report.snapshot_index + 1);
+    let last = report.last_index.unwrap_or(report.snapshot_index);
+    let (lowest, highest) = match (report.lowest_entry, report.highest_entry) {
+        (Some(lowest), Some(highest)) => (lowest, highest),
+        // Without entries the markers must describe an empty log.
+        _ => {
+            if last + 1 != first {
+                report.issue(
+                    IssueKind::MarkerMismatch,
+                    None,
+                    format!("no entries but the markers are [{}, {}]", first, last),
+                );
+            }
+            return;
+        }
+    };
+    for (key, present, marker, expected) in [
+        (RawKey::FirstIndex, report.first_index.is_some(), first, lowest),
+        (RawKey::LastIndex, report.last_index.is_some(), last, highest),
+    ] {
+        if marker != expected {
+            let detail = if present {
+                format!("{:?} is {}, expected {}", key, marker, expected)
+            } else {
+                format!("{:?} is missing, so it reads as {}, expected {}", key, marker, expected)
+            };
+            report.issue(IssueKind::MarkerMismatch, Some(marker), detail);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, IssueKind};
+    use raft::sled_storage::{encode_index_marker, RawKey, SledStorage};
+    use raft::storage::LogStore;
+    use tikv_raft::prelude::Entry;
+
+    fn fresh_store(name: &str) -> SledStorage {
+        let path = format!("/tmp/raft_log/redo_fix/{}", name);
+        let _ = std::fs::remove_dir_all(&path);
+        SledStorage::create_offline(path).unwrap()
+    }
+
+    fn entries(indexes: std::ops::RangeInclusive<u64>) -> Vec<Entry> {
+        indexes
+            .map(|index| Entry {
+                index,
+                term: 1,
+                ..Default::default()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_uncompacted_log_is_ok() {
+        let mut storage = fresh_store("verify_uncompacted");
+        storage.append(&entries(1..=5)).unwrap();
+
+        let report = verify(&storage).unwrap();
+        assert!(report.ok, "{:?}", report.issues);
+        assert_eq!(report.first_index, None);
+        assert_eq!((report.lowest_entry, report.highest_entry), (Some(1), Some(5)));
+    }
+
+    #[test]
+    fn test_wrong_first_index() {
+        let mut storage = fresh_store("verify_wrong_first_index");
+        storage.append(&entries(1..=5)).unwrap();
+        storage
+            .raw_write(&[(RawKey::FirstIndex, Some(encode_index_marker(3).to_vec()))])
+            .unwrap();
+
+        let report = verify(&storage).unwrap();
+        let kinds: Vec<_> = report.issues.iter().map(|issue| issue.kind).collect();
+        assert_eq!(kinds, vec![IssueKind::MarkerMismatch]);
+    }
+}